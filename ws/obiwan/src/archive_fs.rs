@@ -0,0 +1,637 @@
+//! This module implements a [`simple_fs::Filesystem`] that serves
+//! files directly out of an uncompressed tar or newc cpio archive.
+//!
+//! The archive is indexed once when it is opened. Afterwards, reads
+//! are translated into ranged reads of the archive file itself, so
+//! nothing is ever unpacked.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io::{Error, ErrorKind},
+    os::unix::{fs::FileExt, prelude::OsStrExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+
+use crate::{path::normalize, simple_fs};
+
+/// The size of tar headers and the granularity of tar contents.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// The size of the fixed part of a newc cpio header.
+const CPIO_HEADER_SIZE: u64 = 110;
+
+/// The name of the last member of a cpio archive.
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+
+/// The supported archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Cpio,
+}
+
+/// The location of a member's contents in the archive file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Member {
+    offset: u64,
+    size: u64,
+}
+
+/// A file inside an archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveFile {
+    archive: Arc<std::fs::File>,
+    member: Member,
+}
+
+#[async_trait]
+impl simple_fs::File for ArchiveFile {
+    type Error = std::io::Error;
+
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let data = self.read_owned(offset, vec![0; buf.len()]).await?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn read_owned(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        if offset >= self.member.size {
+            buf.clear();
+            return Ok(buf);
+        }
+
+        let len = usize::try_from((self.member.size - offset).min(buf.len() as u64))
+            .map_err(|_| Error::other("Conversion error"))?;
+
+        buf.truncate(len);
+        let buf = simple_fs::read_at(&self.archive, self.member.offset + offset, buf).await?;
+
+        // A short read would look like the end of the member to the
+        // client, so don't hand out truncated files.
+        if buf.len() < len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Archive was truncated after it was opened",
+            ));
+        }
+
+        Ok(buf)
+    }
+
+    async fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.member.size)
+    }
+}
+
+/// A read-only filesystem that is backed by an archive file.
+#[derive(Debug, Clone)]
+pub struct ArchiveFilesystem {
+    archive: Arc<std::fs::File>,
+    members: Arc<BTreeMap<PathBuf, Member>>,
+}
+
+impl ArchiveFilesystem {
+    /// Open an archive and index its contents. The archive format is
+    /// detected automatically.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let archive = std::fs::File::open(path)?;
+        let format = detect_format(&archive)?;
+        let members = match format {
+            ArchiveFormat::Tar => index_tar(&archive)?,
+            ArchiveFormat::Cpio => index_cpio(&archive)?,
+        };
+
+        info!(
+            "Indexed {} files in {format:?} archive {}.",
+            members.len(),
+            path.display()
+        );
+
+        Ok(Self {
+            archive: Arc::new(archive),
+            members: Arc::new(members),
+        })
+    }
+}
+
+#[async_trait]
+impl simple_fs::Filesystem for ArchiveFilesystem {
+    type File = ArchiveFile;
+    type Error = std::io::Error;
+
+    async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+        normalize(path)
+            .and_then(|path| self.members.get(&path))
+            .map(|member| ArchiveFile {
+                archive: self.archive.clone(),
+                member: *member,
+            })
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn detect_format(archive: &std::fs::File) -> std::io::Result<ArchiveFormat> {
+    let mut magic = [0u8; 6];
+
+    // A cpio archive with a single small file can be shorter than a
+    // tar header, so look for the cpio magic first.
+    archive.read_exact_at(&mut magic, 0)?;
+
+    if &magic == b"070701" || &magic == b"070702" {
+        return Ok(ArchiveFormat::Cpio);
+    }
+
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+
+    archive.read_exact_at(&mut header, 0)?;
+
+    if &header[257..262] == b"ustar" || tar_checksum_matches(&header) {
+        Ok(ArchiveFormat::Tar)
+    } else {
+        Err(invalid_data("Unrecognized archive format"))
+    }
+}
+
+/// Remember a member under its normalized name. Members that would
+/// escape the root are dropped.
+fn add_member(members: &mut BTreeMap<PathBuf, Member>, name: &[u8], member: Member) {
+    let name = Path::new(OsStr::from_bytes(name));
+
+    match normalize(name) {
+        Some(normalized) if normalized.as_os_str().is_empty() => {}
+        Some(normalized) => {
+            debug!(
+                "Archive member {} at {:#x} ({} bytes)",
+                normalized.display(),
+                member.offset,
+                member.size
+            );
+            members.insert(normalized, member);
+        }
+        None => warn!(
+            "Ignoring archive member outside of root: {}",
+            name.display()
+        ),
+    }
+}
+
+/// Strip trailing NUL bytes from a fixed-size header field.
+fn trim_nul(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+
+    &field[..len]
+}
+
+fn parse_octal(field: &[u8]) -> std::io::Result<u64> {
+    let digits = trim_nul(field);
+    let digits = std::str::from_utf8(digits).map_err(|_| invalid_data("Invalid tar number"))?;
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+
+    if digits.is_empty() {
+        return Ok(0);
+    }
+
+    u64::from_str_radix(digits, 8).map_err(|_| invalid_data("Invalid tar number"))
+}
+
+fn tar_checksum_matches(header: &[u8]) -> bool {
+    let Ok(expected) = parse_octal(&header[148..156]) else {
+        return false;
+    };
+
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                0x20
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+
+    expected == actual
+}
+
+fn round_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Read a member's contents. This is only used for metadata members,
+/// such as GNU long names or pax headers.
+fn read_metadata(archive: &std::fs::File, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+    // Metadata members are tiny in practice. Don't let a corrupt
+    // archive make us allocate huge amounts of memory.
+    if size > 1 << 20 {
+        return Err(invalid_data("Archive metadata is too large"));
+    }
+
+    let mut data = vec![0; size as usize];
+
+    archive.read_exact_at(&mut data, offset)?;
+    Ok(data)
+}
+
+/// Extract the `path` record from a pax extended header.
+fn pax_path(data: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = data;
+
+    // Each record looks like "<length> <key>=<value>\n".
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+
+        if let Some(path) = record.strip_prefix(b"path=") {
+            return Some(path.to_vec());
+        }
+
+        rest = &rest[len..];
+    }
+
+    None
+}
+
+fn index_tar(archive: &std::fs::File) -> std::io::Result<BTreeMap<PathBuf, Member>> {
+    let archive_size = archive.metadata()?.len();
+    let mut members = BTreeMap::new();
+    let mut offset = 0;
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+
+    while offset + TAR_BLOCK_SIZE <= archive_size {
+        archive.read_exact_at(&mut header, offset)?;
+
+        // An all-zero block marks the end of the archive.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        if !tar_checksum_matches(&header) {
+            return Err(invalid_data("Invalid tar header checksum"));
+        }
+
+        let size = parse_octal(&header[124..136])?;
+        let data_offset = offset + TAR_BLOCK_SIZE;
+
+        if data_offset + size > archive_size {
+            return Err(invalid_data("Truncated tar archive"));
+        }
+
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = trim_nul(&header[0..100]);
+            let prefix = trim_nul(&header[345..500]);
+
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+
+        match header[156] {
+            b'0' | b'\0' | b'7' => add_member(
+                &mut members,
+                &name,
+                Member {
+                    offset: data_offset,
+                    size,
+                },
+            ),
+            b'1' => {
+                let target = normalize(Path::new(OsStr::from_bytes(trim_nul(&header[157..257]))));
+
+                match target.and_then(|t| members.get(&t).copied()) {
+                    Some(member) => add_member(&mut members, &name, member),
+                    None => warn!(
+                        "Ignoring hard link with unknown target: {}",
+                        String::from_utf8_lossy(&name)
+                    ),
+                }
+            }
+            b'L' => {
+                long_name = Some(trim_nul(&read_metadata(archive, data_offset, size)?).to_vec())
+            }
+            b'x' => long_name = pax_path(&read_metadata(archive, data_offset, size)?),
+            b'5' => {}
+            t => debug!(
+                "Ignoring tar member {} of type {:?}",
+                String::from_utf8_lossy(&name),
+                char::from(t)
+            ),
+        }
+
+        offset = data_offset + round_up(size, TAR_BLOCK_SIZE);
+    }
+
+    Ok(members)
+}
+
+fn parse_hex(field: &[u8]) -> std::io::Result<u64> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|s| u64::from_str_radix(s, 16).ok())
+        .ok_or_else(|| invalid_data("Invalid cpio number"))
+}
+
+fn index_cpio(archive: &std::fs::File) -> std::io::Result<BTreeMap<PathBuf, Member>> {
+    let archive_size = archive.metadata()?.len();
+    let mut members = BTreeMap::new();
+    let mut offset = 0;
+    let mut header = [0u8; CPIO_HEADER_SIZE as usize];
+
+    // newc archives only store the contents of hard-linked files
+    // with the last link. Earlier links have a size of zero.
+    let mut pending_links: BTreeMap<u64, Vec<Vec<u8>>> = BTreeMap::new();
+
+    loop {
+        if offset + CPIO_HEADER_SIZE > archive_size {
+            return Err(invalid_data("Truncated cpio archive"));
+        }
+
+        archive.read_exact_at(&mut header, offset)?;
+
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return Err(invalid_data("Invalid cpio header magic"));
+        }
+
+        let field = |i: usize| parse_hex(&header[6 + i * 8..6 + (i + 1) * 8]);
+        let ino = field(0)?;
+        let mode = field(1)?;
+        let nlink = field(4)?;
+        let size = field(6)?;
+        let name_size = field(11)?;
+
+        let name_offset = offset + CPIO_HEADER_SIZE;
+        let name = read_metadata(archive, name_offset, name_size)?;
+        let name = trim_nul(&name);
+        let data_offset = round_up(name_offset + name_size, 4);
+
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        if data_offset + size > archive_size {
+            return Err(invalid_data("Truncated cpio archive"));
+        }
+
+        // Only regular files are interesting for us.
+        if mode & 0o170000 == 0o100000 {
+            if size == 0 && nlink > 1 {
+                pending_links.entry(ino).or_default().push(name.to_vec());
+            } else {
+                let member = Member {
+                    offset: data_offset,
+                    size,
+                };
+
+                for link in pending_links.remove(&ino).unwrap_or_default() {
+                    add_member(&mut members, &link, member);
+                }
+
+                add_member(&mut members, name, member);
+            }
+        }
+
+        offset = round_up(data_offset + size, 4);
+    }
+
+    // Hard links without any data are just empty files.
+    for link in pending_links.into_values().flatten() {
+        add_member(&mut members, &link, Member { offset: 0, size: 0 });
+    }
+
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::simple_fs::{File, Filesystem};
+
+    use super::*;
+
+    fn tar_header(name: &str, size: usize, typeflag: u8) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK_SIZE as usize];
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let checksum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    0x20
+                } else {
+                    u32::from(b)
+                }
+            })
+            .sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        header
+    }
+
+    fn tar_member(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut member = tar_header(name, contents.len(), b'0');
+
+        member.extend_from_slice(contents);
+        member.resize(round_up(member.len() as u64, TAR_BLOCK_SIZE) as usize, 0);
+        member
+    }
+
+    fn cpio_member(ino: u64, mode: u64, nlink: u64, name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "070701{ino:08X}{mode:08X}{:08X}{:08X}{nlink:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+            0,
+            0,
+            0,
+            contents.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        )
+        .into_bytes();
+
+        member.extend_from_slice(name.as_bytes());
+        member.push(0);
+        member.resize(round_up(member.len() as u64, 4) as usize, 0);
+        member.extend_from_slice(contents);
+        member.resize(round_up(member.len() as u64, 4) as usize, 0);
+        member
+    }
+
+    fn write_archive(contents: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    async fn read_all(fs: &ArchiveFilesystem, path: &str) -> Vec<u8> {
+        let file = fs.open(Path::new(path)).await.unwrap();
+        let mut buf = vec![0; usize::try_from(file.size().await.unwrap()).unwrap()];
+
+        assert_eq!(file.read(0, &mut buf).await.unwrap(), buf.len());
+        buf
+    }
+
+    #[tokio::test]
+    async fn can_read_tar() {
+        let archive = write_archive(
+            &[
+                tar_header("./boot/", 0, b'5'),
+                tar_member("./boot/ipxe.efi", b"hello"),
+                tar_member("kernel", &[0xab; 1000]),
+                vec![0; 2 * TAR_BLOCK_SIZE as usize],
+            ]
+            .concat(),
+        );
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        assert_eq!(read_all(&fs, "/boot/ipxe.efi").await, b"hello");
+        assert_eq!(read_all(&fs, "kernel").await, vec![0xab; 1000]);
+        assert!(fs.open(Path::new("/boot")).await.is_err());
+        assert!(fs.open(Path::new("/missing")).await.is_err());
+
+        // Reads past the end of a member must not leak the next member.
+        let file = fs.open(Path::new("/boot/ipxe.efi")).await.unwrap();
+        let mut buf = [0; 64];
+
+        assert_eq!(file.read(3, &mut buf).await.unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(file.read(5, &mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn can_read_tar_long_names() {
+        let long_name = format!("{}/file", "d".repeat(120));
+
+        let archive = write_archive(
+            &[
+                tar_member("././@LongLink", format!("{long_name}\0").as_bytes()),
+                tar_header("ignored", 3, b'0'),
+                b"abc".to_vec(),
+                vec![0; TAR_BLOCK_SIZE as usize - 3],
+            ]
+            .concat(),
+        );
+
+        // Fix up the type of the long name member.
+        let mut contents = std::fs::read(archive.path()).unwrap();
+        contents[..TAR_BLOCK_SIZE as usize].copy_from_slice(&tar_header(
+            "././@LongLink",
+            long_name.len() + 1,
+            b'L',
+        ));
+        let archive = write_archive(&contents);
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        assert_eq!(read_all(&fs, &long_name).await, b"abc");
+        assert!(fs.open(Path::new("ignored")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn can_read_cpio() {
+        let archive = write_archive(
+            &[
+                cpio_member(1, 0o040755, 2, "boot", b""),
+                cpio_member(2, 0o100644, 1, "boot/ipxe.efi", b"hello"),
+                cpio_member(3, 0o100644, 2, "link", b""),
+                cpio_member(3, 0o100644, 2, "kernel", b"abcdefg"),
+                cpio_member(0, 0, 1, "TRAILER!!!", b""),
+            ]
+            .concat(),
+        );
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        assert_eq!(read_all(&fs, "/boot/ipxe.efi").await, b"hello");
+        assert_eq!(read_all(&fs, "/kernel").await, b"abcdefg");
+        assert_eq!(read_all(&fs, "/link").await, b"abcdefg");
+        assert!(fs.open(Path::new("/boot")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn can_read_small_cpio() {
+        let archive = write_archive(
+            &[
+                cpio_member(1, 0o100644, 1, "ipxe.efi", b"hello"),
+                cpio_member(0, 0, 1, "TRAILER!!!", b""),
+            ]
+            .concat(),
+        );
+
+        assert!(std::fs::metadata(archive.path()).unwrap().len() < TAR_BLOCK_SIZE);
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        assert_eq!(read_all(&fs, "/ipxe.efi").await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_members() {
+        let archive = write_archive(
+            &[
+                tar_member("kernel", &[0xab; 1000]),
+                vec![0; 2 * TAR_BLOCK_SIZE as usize],
+            ]
+            .concat(),
+        );
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        archive.as_file().set_len(TAR_BLOCK_SIZE + 100).unwrap();
+
+        let file = fs.open(Path::new("kernel")).await.unwrap();
+        let mut buf = [0; 512];
+
+        assert_eq!(file.read(0, &mut buf[..100]).await.unwrap(), 100);
+        assert!(file.read(0, &mut buf).await.is_err());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let archive = write_archive(&[0x42; 1024]);
+
+        assert!(ArchiveFilesystem::open(archive.path()).is_err());
+    }
+
+    #[test]
+    fn ignores_members_outside_root() {
+        let archive = write_archive(
+            &[
+                tar_member("../passwd", b"evil"),
+                vec![0; 2 * TAR_BLOCK_SIZE as usize],
+            ]
+            .concat(),
+        );
+
+        let fs = ArchiveFilesystem::open(archive.path()).unwrap();
+
+        assert!(fs.members.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+//! Access to the kernel's IPv4 neighbour table.
+//!
+//! TFTP requests don't carry the client's MAC address, but the kernel
+//! usually knows it, because the client is on the same link.
+//!
+//! Several parts of the server want the MAC address of a client.
+//! [`Peer`] carries it alongside the client's address, so the table
+//! is read at most once per request and only if anyone asks.
+
+use std::{
+    fmt,
+    fs::File,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+use log::warn;
+use tokio::sync::OnceCell;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// An open handle to `/proc/net/arp`. Cloning the handle is cheap.
+#[derive(Debug, Clone)]
+pub struct ArpTable {
+    /// Kept open, so we can still read it after `chroot`.
+    file: Arc<File>,
+}
+
+impl ArpTable {
+    pub fn open() -> io::Result<Self> {
+        Self::open_path(Path::new(ARP_TABLE))
+    }
+
+    fn open_path(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: Arc::new(File::open(path)?),
+        })
+    }
+
+    /// Returns the MAC address of `peer` in lower case, such as
+    /// `aa:bb:cc:dd:ee:ff`. The table is read on the blocking thread
+    /// pool.
+    pub async fn mac_address(&self, peer: IpAddr) -> Option<String> {
+        let file = self.file.clone();
+
+        match tokio::task::spawn_blocking(move || read_all(&file))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|contents| contents)
+        {
+            Ok(contents) => find_mac(&contents, peer),
+            Err(e) => {
+                warn!("Failed to read {ARP_TABLE}: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// A client and, if anyone asks, its MAC address.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    addr: SocketAddr,
+    arp_table: Option<ArpTable>,
+    mac: Arc<OnceCell<Option<String>>>,
+}
+
+impl Peer {
+    /// A client at `addr` whose MAC address is looked up in
+    /// `arp_table`.
+    pub fn new(addr: SocketAddr, arp_table: Option<ArpTable>) -> Self {
+        Self {
+            addr,
+            arp_table,
+            mac: Default::default(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    /// Returns the MAC address of the client in lower case. The ARP
+    /// table is only read the first time.
+    pub async fn mac_address(&self) -> Option<&str> {
+        self.mac
+            .get_or_init(|| async {
+                match &self.arp_table {
+                    Some(arp_table) => arp_table.mac_address(self.ip()).await,
+                    None => None,
+                }
+            })
+            .await
+            .as_deref()
+    }
+}
+
+/// A client whose MAC address we don't look up.
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr, None)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+/// Returns the IPv4 address of `peer`, which may be IPv4-mapped.
+pub fn ipv4(peer: IpAddr) -> Option<Ipv4Addr> {
+    match peer {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+    }
+}
+
+/// Read a procfs file from the start. We use `pread`, because the
+/// file is shared between connections.
+fn read_all(file: &File) -> io::Result<String> {
+    let mut contents = vec![];
+    let mut buf = [0u8; 4096];
+
+    loop {
+        match file.read_at(&mut buf, contents.len() as u64)? {
+            0 => break,
+            len => contents.extend_from_slice(&buf[..len]),
+        }
+    }
+
+    String::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Find the MAC address of `peer` in the contents of `/proc/net/arp`.
+fn find_mac(table: &str, peer: IpAddr) -> Option<String> {
+    let peer = ipv4(peer)?;
+
+    table.lines().skip(1).find_map(|line| {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            // Incomplete entries have no flags set.
+            [ip, _, flags, mac, ..] if ip.parse() == Ok(peer) && flags != "0x0" => {
+                Some(mac.to_ascii_lowercase())
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.3      0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.2      0x1         0x2         AA:BB:CC:DD:EE:FF     *        eth0
+";
+
+    #[test]
+    fn finds_mac_addresses() {
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert_eq!(
+            find_mac(TABLE, ip("192.168.1.2")).as_deref(),
+            Some("aa:bb:cc:dd:ee:ff")
+        );
+        assert_eq!(
+            find_mac(TABLE, ip("::ffff:192.168.1.2")).as_deref(),
+            Some("aa:bb:cc:dd:ee:ff")
+        );
+        assert_eq!(find_mac(TABLE, ip("192.168.1.3")), None);
+        assert_eq!(find_mac(TABLE, ip("192.168.1.4")), None);
+    }
+
+    #[tokio::test]
+    async fn reads_the_table_once_per_peer() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut tmp, TABLE.as_bytes()).unwrap();
+
+        let arp_table = ArpTable::open_path(tmp.path()).unwrap();
+        let peer = Peer::new("192.168.1.2:1234".parse().unwrap(), Some(arp_table.clone()));
+
+        assert_eq!(peer.mac_address().await, Some("aa:bb:cc:dd:ee:ff"));
+
+        // The client left the table, but this peer still knows it.
+        tmp.as_file().set_len(0).unwrap();
+        assert_eq!(peer.clone().mac_address().await, Some("aa:bb:cc:dd:ee:ff"));
+
+        let peer = Peer::new("192.168.1.2:1234".parse().unwrap(), Some(arp_table));
+        assert_eq!(peer.mac_address().await, None);
+    }
+}
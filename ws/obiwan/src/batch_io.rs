@@ -0,0 +1,390 @@
+//! This module sends and receives multiple UDP packets with a single
+//! system call.
+//!
+//! Sending uses UDP generic segmentation offload (`UDP_SEGMENT`) when
+//! consecutive packets have the same size and `sendmmsg` otherwise.
+//! Receiving uses `recvmmsg`. When the kernel doesn't support any of
+//! these, we fall back to one system call per packet.
+//!
+//! All functions operate on non-blocking sockets and are meant to be
+//! called from [`tokio::net::UdpSocket::async_io`].
+
+use std::{
+    io::{Error, Result},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use nix::libc;
+
+/// The kernel refuses to segment more than this many packets at once.
+#[cfg(not(feature = "io-uring"))]
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// How many packets we send with one `sendmmsg` call. The message
+/// headers live on the stack, so sending doesn't allocate.
+#[cfg(not(feature = "io-uring"))]
+const MAX_SEND_BATCH: usize = 64;
+
+/// The largest UDP payload we hand to the kernel for segmentation.
+/// This leaves room for IPv4 and IPv6 headers.
+#[cfg(not(feature = "io-uring"))]
+const MAX_GSO_BYTES: usize = 65000;
+
+#[cfg(not(feature = "io-uring"))]
+static GSO_SUPPORTED: AtomicBool = AtomicBool::new(true);
+#[cfg(not(feature = "io-uring"))]
+static SENDMMSG_SUPPORTED: AtomicBool = AtomicBool::new(true);
+static RECVMMSG_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+/// Does this error mean that the kernel lacks support for an operation?
+fn is_unsupported(error: &Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::ENOSYS | libc::EOPNOTSUPP | libc::ENOPROTOOPT)
+    )
+}
+
+/// Return the result of a system call that returns a count or -1.
+fn check(result: isize) -> Result<usize> {
+    usize::try_from(result).map_err(|_| Error::last_os_error())
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn iovec(data: &[u8]) -> libc::iovec {
+    libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    }
+}
+
+/// Return how many of the leading packets can be sent as one GSO
+/// buffer. All segments but the last must have the same size.
+#[cfg(not(feature = "io-uring"))]
+fn gso_segments(packets: &[Vec<u8>]) -> usize {
+    let Some(segment_size) = packets.first().map(Vec::len) else {
+        return 0;
+    };
+
+    let max_segments = MAX_GSO_SEGMENTS.min(MAX_GSO_BYTES / segment_size.max(1));
+    let mut count = 0;
+
+    for packet in packets.iter().take(max_segments) {
+        if packet.is_empty() || packet.len() > segment_size {
+            break;
+        }
+
+        count += 1;
+
+        if packet.len() < segment_size {
+            break;
+        }
+    }
+
+    count
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn send_gso(fd: RawFd, packets: &[Vec<u8>]) -> Result<()> {
+    assert!(packets.len() <= MAX_GSO_SEGMENTS);
+
+    let segment_size =
+        u16::try_from(packets[0].len()).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    let mut iovecs = [iovec(&[]); MAX_GSO_SEGMENTS];
+
+    for (iovec_slot, packet) in iovecs.iter_mut().zip(packets) {
+        *iovec_slot = iovec(packet);
+    }
+
+    // Large enough and aligned for one control message with a u16.
+    let mut control = [0u64; 4];
+
+    // Safety: All pointers in the message header point to buffers
+    // that outlive the system call. The control message fits into
+    // `control`, because we size it with CMSG_SPACE.
+    let sent = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = packets.len() as _;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_size);
+
+        check(libc::sendmsg(fd, &msg, 0))?
+    };
+
+    if sent != packets.iter().map(Vec::len).sum::<usize>() {
+        return Err(Error::other("Short send on UDP socket"));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn send_mmsg(fd: RawFd, packets: &[Vec<u8>]) -> Result<usize> {
+    let count = packets.len().min(MAX_SEND_BATCH);
+    let mut iovecs = [iovec(&[]); MAX_SEND_BATCH];
+    // Safety: An all-zero message header is valid.
+    let mut headers: [libc::mmsghdr; MAX_SEND_BATCH] = unsafe { mem::zeroed() };
+
+    for ((header, iovec_slot), packet) in headers.iter_mut().zip(iovecs.iter_mut()).zip(packets) {
+        *iovec_slot = iovec(packet);
+        header.msg_hdr.msg_iov = iovec_slot;
+        header.msg_hdr.msg_iovlen = 1;
+    }
+
+    // Safety: The headers point to iovecs that point to the packets.
+    // All of them outlive the system call.
+    let sent = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), count as _, 0) };
+
+    check(sent as isize)
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn send_one(fd: RawFd, packet: &[u8]) -> Result<()> {
+    // Safety: The packet outlives the system call.
+    check(unsafe { libc::send(fd, packet.as_ptr().cast(), packet.len(), 0) })?;
+
+    Ok(())
+}
+
+/// Send packets on a connected socket. Returns how many packets were
+/// sent, which is at least one, if there were any.
+#[cfg(not(feature = "io-uring"))]
+pub fn send(fd: RawFd, packets: &[Vec<u8>]) -> Result<usize> {
+    if packets.len() > 1 && GSO_SUPPORTED.load(Ordering::Relaxed) {
+        let count = gso_segments(packets);
+
+        if count > 1 {
+            match send_gso(fd, &packets[..count]) {
+                Ok(()) => return Ok(count),
+                Err(e) if is_unsupported(&e) => {
+                    warn!("UDP segmentation offload is not available, disabling it: {e}");
+                    GSO_SUPPORTED.store(false, Ordering::Relaxed);
+                }
+                // The segments might be too large for the path MTU.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    if packets.len() > 1 && SENDMMSG_SUPPORTED.load(Ordering::Relaxed) {
+        match send_mmsg(fd, packets) {
+            Ok(count) => return Ok(count),
+            Err(e) if is_unsupported(&e) => {
+                warn!("sendmmsg is not available, disabling it: {e}");
+                SENDMMSG_SUPPORTED.store(false, Ordering::Relaxed);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match packets.first() {
+        Some(packet) => send_one(fd, packet).map(|()| 1),
+        None => Ok(0),
+    }
+}
+
+fn to_socket_addr(storage: &libc::sockaddr_storage) -> Result<SocketAddr> {
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            // Safety: The address family tells us the actual type.
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // Safety: The address family tells us the actual type.
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(Error::other(format!("Unknown address family {family}"))),
+    }
+}
+
+/// Buffers for receiving multiple packets at once.
+#[derive(Debug)]
+pub struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Create buffers for up to `count` packets of `size` bytes each.
+    pub fn new(count: usize, size: usize) -> Self {
+        assert!(count > 0);
+
+        Self {
+            buffers: vec![vec![0; size]; count],
+            received: Vec::with_capacity(count),
+        }
+    }
+
+    fn recv_mmsg(&mut self, fd: RawFd) -> Result<()> {
+        let mut iovecs: Vec<libc::iovec> = self
+            .buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        // Safety: All-zero socket addresses are valid.
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            vec![unsafe { mem::zeroed() }; self.buffers.len()];
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                // Safety: An all-zero message header is valid.
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                header
+            })
+            .collect();
+
+        // Safety: The headers point to buffers and addresses that
+        // outlive the system call.
+        let count = check(unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        } as isize)?;
+
+        for (header, addr) in headers.iter().zip(addrs.iter()).take(count) {
+            self.received
+                .push((header.msg_len as usize, to_socket_addr(addr)?));
+        }
+
+        Ok(())
+    }
+
+    fn recv_one(&mut self, fd: RawFd) -> Result<()> {
+        let buf = &mut self.buffers[0];
+        // Safety: An all-zero socket address is valid.
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        // Safety: The buffer and the address outlive the system call.
+        let len = check(unsafe {
+            libc::recvfrom(
+                fd,
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_DONTWAIT,
+                (&mut addr as *mut libc::sockaddr_storage).cast(),
+                &mut addr_len,
+            )
+        })?;
+
+        self.received.push((len, to_socket_addr(&addr)?));
+        Ok(())
+    }
+
+    /// Receive as many packets as are available without blocking.
+    /// Returns the number of packets, which is at least one.
+    pub fn recv(&mut self, fd: RawFd) -> Result<usize> {
+        self.received.clear();
+
+        if RECVMMSG_SUPPORTED.load(Ordering::Relaxed) {
+            match self.recv_mmsg(fd) {
+                Ok(()) => return Ok(self.received.len()),
+                Err(e) if is_unsupported(&e) => {
+                    warn!("recvmmsg is not available, disabling it: {e}");
+                    RECVMMSG_SUPPORTED.store(false, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.recv_one(fd)?;
+        Ok(self.received.len())
+    }
+
+    /// The packets from the last call to [`RecvBatch::recv`].
+    pub fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(self.buffers.iter())
+            .map(|((len, addr), buf)| (&buf[..*len], *addr))
+    }
+}
+
+// The tests send with the functions that io_uring builds don't use.
+#[cfg(all(test, not(feature = "io-uring")))]
+mod tests {
+    use std::{net::UdpSocket, os::fd::AsRawFd};
+
+    use super::*;
+
+    #[test]
+    fn splits_packets_for_segmentation() {
+        let packets = vec![vec![0; 10], vec![0; 10], vec![0; 5], vec![0; 10]];
+
+        assert_eq!(gso_segments(&packets), 3);
+        assert_eq!(gso_segments(&packets[2..]), 1);
+        assert_eq!(gso_segments(&vec![vec![0; 30000]; 4]), 2);
+        assert_eq!(gso_segments(&[]), 0);
+    }
+
+    #[test]
+    fn sends_and_receives_batches() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+
+        let packets: Vec<Vec<u8>> = [3, 3, 3, 1, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| vec![i as u8; len])
+            .collect();
+
+        let mut sent = 0;
+        while sent < packets.len() {
+            sent += send(sender.as_raw_fd(), &packets[sent..]).unwrap();
+        }
+
+        let mut batch = RecvBatch::new(8, 16);
+        let mut received = vec![];
+
+        while received.len() < packets.len() {
+            batch.recv(receiver.as_raw_fd()).unwrap();
+
+            for (data, addr) in batch.packets() {
+                assert_eq!(addr, sender.local_addr().unwrap());
+                received.push(data.to_vec());
+            }
+        }
+
+        assert_eq!(received, packets);
+    }
+}
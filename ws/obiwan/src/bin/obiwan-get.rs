@@ -0,0 +1,117 @@
+//! A small TFTP client for testing servers and health checks.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use log::{debug, LevelFilter};
+
+use obiwan::tftp_client::{self, ClientOptions};
+
+/// Download a file via TFTP
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Verbose mode. Specify multiple times to increase verbosity.
+    #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Request this block size.
+    #[arg(long)]
+    blksize: Option<u16>,
+
+    /// Request this window size.
+    #[arg(long)]
+    windowsize: Option<u16>,
+
+    /// Seconds to wait for the server before resending.
+    #[arg(long, default_value = "1")]
+    timeout: u64,
+
+    /// Write the file here. Without this option, the data is
+    /// discarded, which is useful for health checks.
+    #[arg(short = 'o', long)]
+    output: Option<PathBuf>,
+
+    /// The server to download from.
+    #[arg(value_name = "HOST:PORT")]
+    server: String,
+
+    /// The file to download.
+    file: PathBuf,
+}
+
+fn resolve(server: &str) -> Result<SocketAddr> {
+    server
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {server}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{server} has no addresses"))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    simplelog::SimpleLogger::init(
+        match args.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        },
+        simplelog::Config::default(),
+    )?;
+
+    debug!("Command line parameters: {:?}", args);
+
+    let server = resolve(&args.server)?;
+    let sink: Box<dyn Write + Send> = match &args.output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?))
+        }
+        None => Box::new(std::io::sink()),
+    };
+    let options = ClientOptions {
+        block_size: args.blksize,
+        window_size: args.windowsize,
+        timeout: Duration::from_secs(args.timeout),
+        ..Default::default()
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start I/O engine")?;
+
+    let start = Instant::now();
+    let summary = runtime.block_on(tftp_client::get(server, &args.file, options, sink))?;
+    let elapsed = start.elapsed();
+
+    if let Some(transfer_size) = summary.transfer_size {
+        if transfer_size != summary.bytes {
+            bail!(
+                "Server announced {transfer_size} bytes, but sent {} bytes",
+                summary.bytes
+            );
+        }
+    }
+
+    println!(
+        "Received {} bytes in {:.3}s ({:.2} MiB/s, blksize {}, windowsize {}).",
+        summary.bytes,
+        elapsed.as_secs_f64(),
+        summary.bytes as f64 / elapsed.as_secs_f64() / f64::from(1 << 20),
+        summary.block_size,
+        summary.window_size,
+    );
+
+    Ok(())
+}
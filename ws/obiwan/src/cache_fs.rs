@@ -0,0 +1,390 @@
+//! This module implements a [`Filesystem`] that shares open files
+//! between concurrent transfers.
+//!
+//! Without it, every transfer opens its own file descriptor, even if
+//! hundreds of clients request the same kernel at the same time. With
+//! it, the number of open files scales with the number of distinct
+//! files being served. Repeated requests for a path that is already
+//! open only cost a `stat` to check that the file is still the same.
+
+use std::{
+    collections::HashMap,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use log::debug;
+
+use crate::simple_fs::{File, Filesystem};
+
+/// Identifies a file on disk independent of the path that was used to
+/// reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Inode {
+    dev: u64,
+    ino: u64,
+}
+
+/// Describes a specific version of a file. If any of this changes,
+/// we don't reuse the open file anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Version {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Inode {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+impl Version {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            len: metadata.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot<F> {
+    version: Version,
+    file: Weak<F>,
+}
+
+#[derive(Debug)]
+struct Files<F> {
+    /// The shared handles. This is what decides whether two opens get
+    /// the same file.
+    by_inode: HashMap<Inode, Slot<F>>,
+
+    /// The file that a path led to when we opened it last. This lets
+    /// us find the shared handle without opening the file again.
+    by_path: HashMap<PathBuf, Inode>,
+}
+
+impl<F> Default for Files<F> {
+    fn default() -> Self {
+        Self {
+            by_inode: HashMap::new(),
+            by_path: HashMap::new(),
+        }
+    }
+}
+
+impl<F> Files<F> {
+    /// The shared handle for this version of the file, if anyone
+    /// still uses it.
+    fn get(&self, inode: Inode, version: Version) -> Option<Arc<F>> {
+        self.by_inode
+            .get(&inode)
+            .filter(|slot| slot.version == version)
+            .and_then(|slot| slot.file.upgrade())
+    }
+}
+
+/// A file that is shared between all transfers of the same file.
+///
+/// The underlying file is closed when the last transfer that uses it
+/// is done.
+#[derive(Debug)]
+pub struct CachedFile<F: File>(Arc<F>);
+
+impl<F: File> Clone for CachedFile<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[async_trait]
+impl<F: File> File for CachedFile<F> {
+    type Error = F::Error;
+
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(offset, buf).await
+    }
+
+    async fn read_owned(&self, offset: u64, buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        self.0.read_owned(offset, buf).await
+    }
+
+    async fn size(&self) -> Result<u64, Self::Error> {
+        self.0.size().await
+    }
+
+    fn metadata(&self) -> Option<&std::fs::Metadata> {
+        self.0.metadata()
+    }
+
+    fn is_per_client(&self) -> bool {
+        self.0.is_per_client()
+    }
+}
+
+/// A filesystem that hands out the same open file to all transfers
+/// of a file, as long as the file doesn't change on disk.
+///
+/// Files are identified by the device and inode number of the opened
+/// file, so different paths to the same file share a handle as well.
+/// Files that are not backed by a file on disk are never shared.
+#[derive(Debug, Clone)]
+pub struct CachingFilesystem<FS: Filesystem> {
+    inner: FS,
+    files: Arc<Mutex<Files<FS::File>>>,
+}
+
+impl<FS: Filesystem> CachingFilesystem<FS> {
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner,
+            files: Default::default(),
+        }
+    }
+
+    /// Return the number of files that are currently open.
+    #[cfg(test)]
+    fn open_files(&self) -> usize {
+        self.files
+            .lock()
+            .unwrap()
+            .by_inode
+            .values()
+            .filter(|slot| slot.file.strong_count() > 0)
+            .count()
+    }
+
+    /// Return the shared handle that `path` led to last time, if it
+    /// is still in use and `path` still leads to the same version of
+    /// the same file.
+    async fn lookup(&self, path: &Path) -> Option<Arc<FS::File>> {
+        {
+            let files = self.files.lock().unwrap();
+            let inode = files.by_path.get(path)?;
+
+            // Don't bother to check files that are closed anyway.
+            if files.by_inode.get(inode)?.file.strong_count() == 0 {
+                return None;
+            }
+        }
+
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+
+        self.files
+            .lock()
+            .unwrap()
+            .get(Inode::of(&metadata), Version::of(&metadata))
+    }
+
+    /// Return the shared handle for this version of the file, or make
+    /// `file` the shared handle if there is none yet.
+    fn share(&self, path: &Path, inode: Inode, version: Version, file: FS::File) -> Arc<FS::File> {
+        let mut files = self.files.lock().unwrap();
+
+        files.by_path.insert(path.to_owned(), inode);
+
+        if let Some(existing) = files.get(inode, version) {
+            return existing;
+        }
+
+        let Files { by_inode, by_path } = &mut *files;
+
+        // Forget about files that nobody uses anymore.
+        by_inode.retain(|_, slot| slot.file.strong_count() > 0);
+        by_path.retain(|_, known| *known == inode || by_inode.contains_key(known));
+
+        let file = Arc::new(file);
+        by_inode.insert(
+            inode,
+            Slot {
+                version,
+                file: Arc::downgrade(&file),
+            },
+        );
+
+        file
+    }
+}
+
+#[async_trait]
+impl<FS: Filesystem> Filesystem for CachingFilesystem<FS> {
+    type File = CachedFile<FS::File>;
+    type Error = FS::Error;
+
+    async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+        if let Some(shared) = self.lookup(path).await {
+            debug!("Reusing open file for {}.", path.display());
+            return Ok(CachedFile(shared));
+        }
+
+        // Identify the file by what we actually opened. The path may
+        // lead somewhere else by now.
+        let file = self.inner.open(path).await?;
+
+        let Some(metadata) = file.metadata() else {
+            return Ok(CachedFile(Arc::new(file)));
+        };
+
+        let (inode, version) = (Inode::of(metadata), Version::of(metadata));
+        let shared = self.share(path, inode, version, file);
+
+        if Arc::strong_count(&shared) > 1 {
+            debug!("Reusing open file for {}.", path.display());
+        }
+
+        Ok(CachedFile(shared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::simple_fs::AsyncFilesystem;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_open_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kernel");
+        let link = dir.path().join("link");
+
+        std::fs::write(&path, b"kernel").unwrap();
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+
+        let fs = CachingFilesystem::new(AsyncFilesystem::default());
+
+        let a = fs.open(&path).await.unwrap();
+        let b = fs.open(&path).await.unwrap();
+        let c = fs.open(&link).await.unwrap();
+
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert!(Arc::ptr_eq(&a.0, &c.0));
+        assert_eq!(fs.open_files(), 1);
+
+        drop((a, b, c));
+        assert_eq!(fs.open_files(), 0);
+    }
+
+    /// Counts how often files are opened.
+    #[derive(Debug, Clone, Default)]
+    struct CountingFilesystem(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl Filesystem for CountingFilesystem {
+        type File = <AsyncFilesystem as Filesystem>::File;
+        type Error = std::io::Error;
+
+        async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            AsyncFilesystem::default().open(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_open_paths_without_opening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kernel");
+        let new_path = dir.path().join("kernel.new");
+
+        std::fs::write(&path, b"old").unwrap();
+
+        let counter = CountingFilesystem::default();
+        let opens = || counter.0.load(std::sync::atomic::Ordering::Relaxed);
+        let fs = CachingFilesystem::new(counter.clone());
+
+        let a = fs.open(&path).await.unwrap();
+        let b = fs.open(&path).await.unwrap();
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(opens(), 1);
+
+        // A replaced file is opened again.
+        std::fs::write(&new_path, b"new").unwrap();
+        std::fs::rename(&new_path, &path).unwrap();
+
+        let c = fs.open(&path).await.unwrap();
+        assert!(!Arc::ptr_eq(&a.0, &c.0));
+        assert_eq!(opens(), 2);
+
+        // Closed files are opened again.
+        drop((a, b, c));
+        fs.open(&path).await.unwrap();
+        assert_eq!(opens(), 3);
+    }
+
+    #[tokio::test]
+    async fn invalidates_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kernel");
+
+        std::fs::write(&path, b"old").unwrap();
+
+        let fs = CachingFilesystem::new(AsyncFilesystem::default());
+        let old = fs.open(&path).await.unwrap();
+
+        std::fs::write(&path, b"new contents").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let new = fs.open(&path).await.unwrap();
+
+        assert!(!Arc::ptr_eq(&old.0, &new.0));
+        assert_eq!(new.size().await.unwrap(), 12);
+
+        // The old version stays open for its users, but is not handed
+        // out anymore.
+        assert!(Arc::ptr_eq(&fs.open(&path).await.unwrap().0, &new.0));
+        assert_eq!(old.size().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn distinguishes_replaced_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kernel");
+        let new_path = dir.path().join("kernel.new");
+
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::write(&new_path, b"new").unwrap();
+
+        // Same size and modification time, only the inode differs.
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&new_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let fs = CachingFilesystem::new(AsyncFilesystem::default());
+        let old = fs.open(&path).await.unwrap();
+
+        std::fs::rename(&new_path, &path).unwrap();
+
+        let new = fs.open(&path).await.unwrap();
+        let mut buf = [0; 3];
+
+        assert!(!Arc::ptr_eq(&old.0, &new.0));
+        assert_eq!(new.read(0, &mut buf).await.unwrap(), 3);
+        assert_eq!(&buf, b"new");
+    }
+
+    #[tokio::test]
+    async fn reports_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = CachingFilesystem::new(AsyncFilesystem::default());
+
+        assert!(fs.open(&dir.path().join("missing")).await.is_err());
+    }
+}
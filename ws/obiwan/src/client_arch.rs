@@ -0,0 +1,581 @@
+//! This module finds out which firmware a client runs.
+//!
+//! TFTP requests don't say whether the client is a BIOS or an UEFI
+//! machine, but the DHCP requests before them do. We learn the
+//! architecture of clients from two sources:
+//!
+//! - our own proxyDHCP responder, which records every PXE client it
+//!   answers via [`ArchTable::observe`],
+//! - the lease file of a local ISC DHCP server, if it records the
+//!   vendor class of its clients:
+//!
+//! ```text
+//! lease 10.0.0.50 {
+//!   hardware ethernet aa:bb:cc:dd:ee:ff;
+//!   set vendor-class-identifier = "PXEClient:Arch:00007:UNDI:003016";
+//! }
+//! ```
+//!
+//! Only active leases count. The lease file is reloaded in the
+//! background when it changes. See [`ArchTable::watch`].
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::Ipv4Addr,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::info;
+
+use crate::{
+    arp::{ipv4, Peer},
+    inventory::Host,
+    watched_file::{reload_periodically, WatchedFile},
+};
+
+/// The firmware of a PXE client as announced in option 93. See RFC
+/// 4578 and the IANA registry of processor architecture types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Architecture {
+    Bios,
+    UefiX64,
+    UefiArm64,
+}
+
+impl Architecture {
+    /// Map the value of option 93. Returns `None` for architectures we
+    /// have no boot file for.
+    pub fn from_client_architecture(arch: u16) -> Option<Self> {
+        match arch {
+            0 => Some(Self::Bios),
+            7 | 9 => Some(Self::UefiX64),
+            11 => Some(Self::UefiArm64),
+            _ => None,
+        }
+    }
+
+    /// Parse a vendor class such as `PXEClient:Arch:00007:UNDI:003016`.
+    fn from_vendor_class(vendor_class: &str) -> Option<Self> {
+        let (_, rest) = vendor_class.split_once("PXEClient:Arch:")?;
+
+        Self::from_client_architecture(rest.get(..5)?.parse().ok()?)
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bios => "bios",
+            Self::UefiX64 => "uefi-x64",
+            Self::UefiArm64 => "uefi-arm64",
+        })
+    }
+}
+
+impl FromStr for Architecture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bios" => Ok(Self::Bios),
+            "uefi-x64" => Ok(Self::UefiX64),
+            "uefi-arm64" => Ok(Self::UefiArm64),
+            _ => Err(format!(
+                "Unknown architecture {s}, expected bios, uefi-x64 or uefi-arm64"
+            )),
+        }
+    }
+}
+
+/// Architectures of clients by IP and MAC address.
+#[derive(Debug, Default)]
+struct Clients {
+    by_ip: BTreeMap<Ipv4Addr, Architecture>,
+
+    /// Keys are MAC addresses in lower case, such as
+    /// `aa:bb:cc:dd:ee:ff`.
+    by_mac: BTreeMap<String, Architecture>,
+}
+
+impl Clients {
+    fn insert(&mut self, mac: Option<&str>, ip: Option<Ipv4Addr>, arch: Architecture) {
+        if let Some(mac) = mac {
+            self.by_mac.insert(mac.to_ascii_lowercase(), arch);
+        }
+        if let Some(ip) = ip.filter(|ip| !ip.is_unspecified()) {
+            self.by_ip.insert(ip, arch);
+        }
+    }
+
+    fn lookup(&self, ip: Option<Ipv4Addr>, mac: Option<&str>) -> Option<Architecture> {
+        ip.and_then(|ip| self.by_ip.get(&ip))
+            .or_else(|| mac.and_then(|mac| self.by_mac.get(mac)))
+            .copied()
+    }
+}
+
+/// The most clients of each kind, by IP and by MAC address, that
+/// [`ArchTable::observe`] remembers.
+const MAX_OBSERVED_CLIENTS: usize = 4096;
+
+/// Clients that we have seen ourselves. Anyone on the link can make
+/// us observe new clients, so only the most recent ones are kept.
+#[derive(Debug, Default)]
+struct Observed {
+    /// The architecture of each client and when we saw it.
+    by_ip: BTreeMap<Ipv4Addr, (Architecture, u64)>,
+    by_mac: BTreeMap<String, (Architecture, u64)>,
+
+    /// Counts observations, so we know which ones are the oldest.
+    count: u64,
+}
+
+/// Insert into a table of observations, dropping the oldest one if
+/// the table is full.
+fn insert_capped<K: Ord + Clone>(
+    map: &mut BTreeMap<K, (Architecture, u64)>,
+    key: K,
+    value: (Architecture, u64),
+) {
+    if map.len() >= MAX_OBSERVED_CLIENTS && !map.contains_key(&key) {
+        let oldest = map
+            .iter()
+            .min_by_key(|(_, (_, seen))| *seen)
+            .map(|(key, _)| key.clone());
+
+        if let Some(oldest) = oldest {
+            map.remove(&oldest);
+        }
+    }
+
+    map.insert(key, value);
+}
+
+impl Observed {
+    fn insert(&mut self, mac: &str, ip: Option<Ipv4Addr>, arch: Architecture) {
+        self.count += 1;
+
+        insert_capped(
+            &mut self.by_mac,
+            mac.to_ascii_lowercase(),
+            (arch, self.count),
+        );
+        if let Some(ip) = ip.filter(|ip| !ip.is_unspecified()) {
+            insert_capped(&mut self.by_ip, ip, (arch, self.count));
+        }
+    }
+
+    fn lookup(&self, ip: Option<Ipv4Addr>, mac: Option<&str>) -> Option<Architecture> {
+        ip.and_then(|ip| self.by_ip.get(&ip))
+            .or_else(|| mac.and_then(|mac| self.by_mac.get(mac)))
+            .map(|&(arch, _)| arch)
+    }
+}
+
+/// A lease while we are parsing it.
+#[derive(Debug)]
+struct Lease {
+    ip: Ipv4Addr,
+    mac: Option<String>,
+    arch: Option<Architecture>,
+
+    /// Whether the address is handed out. Free, expired, released
+    /// and abandoned leases aren't.
+    active: bool,
+
+    /// When the lease ends, if it ever does.
+    ends: Option<SystemTime>,
+}
+
+/// Convert a date in the proleptic Gregorian calendar to days since
+/// the Unix epoch.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parse the time of a lease, e.g. `4 2024/01/04 10:00:00` in UTC or
+/// `epoch 1704362400`. Returns `None` for `never` and anything we
+/// don't understand.
+fn parse_lease_time(value: &str) -> Option<SystemTime> {
+    let mut parts = value.split_whitespace();
+    let first = parts.next()?;
+
+    let secs = if first == "epoch" {
+        parts.next()?.parse().ok()?
+    } else {
+        let (date, time) = (parts.next()?, parts.next()?);
+        let date: Vec<i64> = date
+            .split('/')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let time: Vec<i64> = time
+            .split(':')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let (&[year, month, day], &[hours, minutes, seconds]) = (&date[..], &time[..]) else {
+            return None;
+        };
+
+        u64::try_from(
+            days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds,
+        )
+        .ok()?
+    };
+
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Parse an ISC `dhcpd.leases` file. Leases without a PXE vendor
+/// class, inactive leases and leases that ended before `now` are
+/// skipped. Later leases replace earlier ones.
+fn parse_leases(contents: &str, now: SystemTime) -> Clients {
+    let mut clients = Clients::default();
+    let mut lease: Option<Lease> = None;
+
+    for line in contents.lines().map(str::trim) {
+        // Statements end with a semicolon, which may be followed by a
+        // comment.
+        let statement = line
+            .split_once(';')
+            .map_or(line, |(statement, _)| statement);
+
+        if let Some(rest) = line.strip_prefix("lease ") {
+            lease = rest
+                .trim_end_matches('{')
+                .trim()
+                .parse()
+                .ok()
+                .map(|ip| Lease {
+                    ip,
+                    mac: None,
+                    arch: None,
+                    active: true,
+                    ends: None,
+                });
+        } else if line == "}" {
+            match lease.take() {
+                Some(Lease {
+                    ip,
+                    mac,
+                    arch: Some(arch),
+                    active: true,
+                    ends,
+                }) if ends.is_none_or(|ends| ends > now) => {
+                    clients.insert(mac.as_deref(), Some(ip), arch);
+                }
+                Some(lease) => {
+                    // Whoever had this address before is gone.
+                    clients.by_ip.remove(&lease.ip);
+                }
+                None => {}
+            }
+        } else if let Some(lease) = &mut lease {
+            if let Some(hwaddr) = statement.strip_prefix("hardware ethernet ") {
+                lease.mac = Some(hwaddr.trim().to_owned());
+            } else if let Some(state) = statement.strip_prefix("binding state ") {
+                lease.active = state.trim() == "active";
+            } else if let Some(ends) = statement.strip_prefix("ends ") {
+                lease.ends = parse_lease_time(ends);
+            } else if let Some(found) = Architecture::from_vendor_class(line) {
+                lease.arch = Some(found);
+            }
+        }
+    }
+
+    clients
+}
+
+/// Remembers the architecture of clients.
+#[derive(Debug, Default)]
+pub struct ArchTable {
+    observed: RwLock<Observed>,
+
+    leases_file: Option<WatchedFile>,
+    leases: RwLock<Clients>,
+}
+
+impl ArchTable {
+    /// Also learn architectures from the lease file of an ISC DHCP
+    /// server. Use [`ArchTable::watch`] to pick up changes.
+    pub fn load_leases(path: &Path) -> io::Result<Self> {
+        let table = Self {
+            leases_file: Some(WatchedFile::open(path)?),
+            ..Self::default()
+        };
+
+        table.reload()?;
+        Ok(table)
+    }
+
+    /// Read the lease file again, if it has changed.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(file) = &self.leases_file else {
+            return Ok(());
+        };
+
+        if let Some(contents) = file
+            .read_if_changed()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", file.path().display())))?
+        {
+            let leases = parse_leases(&contents, SystemTime::now());
+
+            info!(
+                "Loaded {} PXE leases from {}.",
+                leases.by_ip.len(),
+                file.path().display()
+            );
+            *self.leases.write().unwrap() = leases;
+        }
+
+        Ok(())
+    }
+
+    /// Reload the lease file whenever it changes. This never returns.
+    pub async fn watch(self: Arc<Self>) {
+        reload_periodically(self, Self::reload).await
+    }
+
+    /// Remember that the client with `mac` and, if it has one
+    /// already, `ip` runs `arch`.
+    pub fn observe(&self, mac: &str, ip: Option<Ipv4Addr>, arch: Architecture) {
+        self.observed.write().unwrap().insert(mac, ip, arch);
+    }
+
+    /// Find out the architecture of `peer`. The MAC address comes
+    /// from the inventory or from `peer`, and is only needed if the IP
+    /// address is unknown.
+    pub async fn lookup(&self, peer: &Peer, host: Option<&Host>) -> Option<Architecture> {
+        let ip = ipv4(peer.ip());
+
+        // Our own observations are fresher than the lease file.
+        if let Some(arch) = self.observed.read().unwrap().lookup(ip, None) {
+            return Some(arch);
+        }
+
+        let mac = match host.and_then(|host| host.mac.as_deref()) {
+            Some(mac) => Some(mac),
+            None => peer.mac_address().await,
+        };
+
+        if let Some(arch) = self.observed.read().unwrap().lookup(None, mac) {
+            return Some(arch);
+        }
+
+        self.leases.read().unwrap().lookup(ip, mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client at `ip` without a MAC address.
+    fn peer(ip: &str) -> Peer {
+        Peer::from(std::net::SocketAddr::new(ip.parse().unwrap(), 68))
+    }
+
+    #[test]
+    fn parses_architectures() {
+        assert_eq!("uefi-x64".parse(), Ok(Architecture::UefiX64));
+        assert!("efi".parse::<Architecture>().is_err());
+        assert_eq!(Architecture::UefiArm64.to_string(), "uefi-arm64");
+        assert_eq!(
+            Architecture::from_vendor_class(
+                "set vendor-class-identifier = \"PXEClient:Arch:00000:UNDI:002001\";"
+            ),
+            Some(Architecture::Bios)
+        );
+        assert_eq!(Architecture::from_vendor_class("PXEClient:Arch:00"), None);
+        assert_eq!(Architecture::from_vendor_class("MSFT 5.0"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dhcpd.leases");
+
+        std::fs::write(
+            &path,
+            "# The format of this file is documented in dhcpd.leases(5).
+             lease 10.0.0.50 {
+               starts 4 2024/01/04 10:00:00;
+               hardware ethernet AA:BB:CC:DD:EE:01;
+               set vendor-class-identifier = \"PXEClient:Arch:00000:UNDI:002001\";
+             }
+             lease 10.0.0.51 {
+               hardware ethernet aa:bb:cc:dd:ee:02;
+             }
+             lease 10.0.0.50 {
+               hardware ethernet aa:bb:cc:dd:ee:01;
+               set vendor-class-identifier = \"PXEClient:Arch:00007:UNDI:003016\";
+             }",
+        )
+        .unwrap();
+
+        let table = ArchTable::load_leases(&path).unwrap();
+
+        assert_eq!(
+            table.lookup(&peer("10.0.0.50"), None).await,
+            Some(Architecture::UefiX64)
+        );
+        assert_eq!(
+            table.lookup(&peer("::ffff:10.0.0.50"), None).await,
+            Some(Architecture::UefiX64)
+        );
+        assert_eq!(table.lookup(&peer("10.0.0.51"), None).await, None);
+
+        // Clients that got another address are found by MAC address.
+        let host = Host {
+            ip: None,
+            mac: Some("aa:bb:cc:dd:ee:01".to_owned()),
+            hostname: None,
+            group: None,
+            properties: BTreeMap::new(),
+        };
+        assert_eq!(
+            table.lookup(&peer("10.0.0.60"), Some(&host)).await,
+            Some(Architecture::UefiX64)
+        );
+    }
+
+    #[test]
+    fn skips_inactive_leases() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1704369600);
+
+        let clients = parse_leases(
+            "lease 10.0.0.50 {
+               binding state active;
+               ends 4 2024/01/04 13:00:00;
+               hardware ethernet aa:bb:cc:dd:ee:01;
+               set vendor-class-identifier = \"PXEClient:Arch:00007:UNDI:003016\";
+             }
+             lease 10.0.0.50 {
+               binding state free;
+               hardware ethernet aa:bb:cc:dd:ee:01;
+               set vendor-class-identifier = \"PXEClient:Arch:00007:UNDI:003016\";
+             }
+             lease 10.0.0.51 {
+               ends 4 2024/01/04 11:59:59;
+               hardware ethernet aa:bb:cc:dd:ee:02;
+               set vendor-class-identifier = \"PXEClient:Arch:00000:UNDI:002001\";
+             }
+             lease 10.0.0.52 {
+               binding state active;
+               ends epoch 1704369601; # Thu Jan 04 12:00:01 2024
+               hardware ethernet aa:bb:cc:dd:ee:03;
+               set vendor-class-identifier = \"PXEClient:Arch:00000:UNDI:002001\";
+             }
+             lease 10.0.0.53 {
+               ends never;
+               hardware ethernet aa:bb:cc:dd:ee:04;
+               set vendor-class-identifier = \"PXEClient:Arch:00011:UNDI:003016\";
+             }",
+            now,
+        );
+
+        assert_eq!(
+            clients.lookup(Some(Ipv4Addr::new(10, 0, 0, 50)), None),
+            None
+        );
+        assert_eq!(
+            clients.lookup(Some(Ipv4Addr::new(10, 0, 0, 51)), None),
+            None
+        );
+        assert_eq!(
+            clients.lookup(Some(Ipv4Addr::new(10, 0, 0, 52)), None),
+            Some(Architecture::Bios)
+        );
+        assert_eq!(
+            clients.lookup(Some(Ipv4Addr::new(10, 0, 0, 53)), None),
+            Some(Architecture::UefiArm64)
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_observations() {
+        let table = ArchTable::default();
+        let client = peer("10.0.0.50");
+
+        assert_eq!(table.lookup(&client, None).await, None);
+
+        table.observe("aa:bb:cc:dd:ee:01", None, Architecture::Bios);
+        table.observe(
+            "aa:bb:cc:dd:ee:02",
+            Some(Ipv4Addr::new(10, 0, 0, 50)),
+            Architecture::UefiArm64,
+        );
+        assert_eq!(
+            table.lookup(&client, None).await,
+            Some(Architecture::UefiArm64)
+        );
+
+        // Clients without an address yet are only known by MAC.
+        table.observe(
+            "AA:BB:CC:DD:EE:03",
+            Some(Ipv4Addr::UNSPECIFIED),
+            Architecture::Bios,
+        );
+        assert_eq!(table.lookup(&peer("0.0.0.0"), None).await, None);
+        assert_eq!(
+            table
+                .observed
+                .read()
+                .unwrap()
+                .by_mac
+                .get("aa:bb:cc:dd:ee:03")
+                .map(|&(arch, _)| arch),
+            Some(Architecture::Bios)
+        );
+    }
+
+    #[test]
+    fn forgets_old_observations() {
+        let table = ArchTable::default();
+        let mac = |i: usize| {
+            format!(
+                "aa:bb:cc:{:02x}:{:02x}:{:02x}",
+                i >> 16,
+                (i >> 8) & 0xff,
+                i & 0xff
+            )
+        };
+        let ip = |i: usize| Ipv4Addr::from(0x0a00_0000 + u32::try_from(i).unwrap());
+
+        for i in 0..MAX_OBSERVED_CLIENTS {
+            table.observe(&mac(i), Some(ip(i)), Architecture::Bios);
+        }
+
+        // Seeing a client again makes it recent.
+        table.observe(&mac(0), Some(ip(0)), Architecture::UefiX64);
+        table.observe(
+            &mac(MAX_OBSERVED_CLIENTS),
+            Some(ip(MAX_OBSERVED_CLIENTS)),
+            Architecture::UefiArm64,
+        );
+
+        let observed = table.observed.read().unwrap();
+        assert_eq!(observed.by_mac.len(), MAX_OBSERVED_CLIENTS);
+        assert_eq!(observed.by_ip.len(), MAX_OBSERVED_CLIENTS);
+        assert_eq!(
+            observed.lookup(Some(ip(0)), None),
+            Some(Architecture::UefiX64)
+        );
+        assert_eq!(observed.lookup(Some(ip(1)), Some(&mac(1))), None);
+        assert_eq!(
+            observed.lookup(None, Some(&mac(MAX_OBSERVED_CLIENTS))),
+            Some(Architecture::UefiArm64)
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! This module maps clients to hosts of an inventory file.
+//!
+//! The inventory is a CSV file. Its first line names the columns. The
+//! `ip` and `mac` columns identify hosts. `hostname` and `group` are
+//! optional. All columns are available as properties of the host:
+//!
+//! ```text
+//! ip,mac,hostname,group,kernel_args
+//! 10.0.0.10,,node1,compute,console=ttyS0
+//! ,aa:bb:cc:dd:ee:ff,node2,storage,quiet
+//! ```
+//!
+//! Empty lines and lines starting with `#` are ignored. Fields can't
+//! contain commas.
+//!
+//! An inventory that was loaded from a file is reloaded in the
+//! background when the file's modification time changes. See
+//! [`Inventory::watch`].
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use log::info;
+
+use crate::{
+    arp::{ipv4, Peer},
+    watched_file::{reload_periodically, WatchedFile},
+};
+
+/// A host of the inventory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub ip: Option<IpAddr>,
+
+    /// The MAC address in lower case, such as `aa:bb:cc:dd:ee:ff`.
+    pub mac: Option<String>,
+
+    pub hostname: Option<String>,
+    pub group: Option<String>,
+
+    /// All columns of the host, including the ones above. Empty
+    /// fields are left out.
+    pub properties: BTreeMap<String, String>,
+}
+
+/// A list of hosts that we know by IP or MAC address.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    source: Option<WatchedFile>,
+    hosts: RwLock<Vec<Arc<Host>>>,
+}
+
+fn invalid_data(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {msg}"))
+}
+
+fn parse_hosts(contents: &str) -> io::Result<Vec<Arc<Host>>> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+    let Some((_, header)) = lines.next() else {
+        return Ok(vec![]);
+    };
+    let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+    let mut hosts = vec![];
+
+    for (line_no, line) in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+
+        if fields.len() != columns.len() {
+            return Err(invalid_data(
+                line_no,
+                format!("expected {} fields, got {}", columns.len(), fields.len()),
+            ));
+        }
+
+        let properties: BTreeMap<String, String> = columns
+            .iter()
+            .zip(fields)
+            .filter(|(_, field)| !field.is_empty())
+            .map(|(column, field)| (column.to_string(), field.to_owned()))
+            .collect();
+
+        let ip = properties
+            .get("ip")
+            .map(|ip| ip.parse().map_err(|e| invalid_data(line_no, e)))
+            .transpose()?;
+        let mac = properties
+            .get("mac")
+            .map(|mac| mac.replace('-', ":").to_ascii_lowercase());
+
+        if ip.is_none() && mac.is_none() {
+            return Err(invalid_data(line_no, "host has neither ip nor mac"));
+        }
+
+        hosts.push(Arc::new(Host {
+            ip,
+            mac,
+            hostname: properties.get("hostname").cloned(),
+            group: properties.get("group").cloned(),
+            properties,
+        }));
+    }
+
+    Ok(hosts)
+}
+
+impl Inventory {
+    /// Read the inventory from a file. Use [`Inventory::watch`] to
+    /// pick up changes.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let inventory = Self {
+            source: Some(WatchedFile::open(path)?),
+            ..Self::default()
+        };
+
+        inventory.reload()?;
+        Ok(inventory)
+    }
+
+    /// Parse the contents of an inventory file.
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        Ok(Self {
+            hosts: RwLock::new(parse_hosts(contents)?),
+            ..Self::default()
+        })
+    }
+
+    /// Read the inventory file again, if it has changed. On errors,
+    /// the old list of hosts stays in place.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(source) = &self.source else {
+            return Ok(());
+        };
+
+        let with_path =
+            |e: io::Error| io::Error::new(e.kind(), format!("{}: {e}", source.path().display()));
+
+        if let Some(contents) = source.read_if_changed().map_err(with_path)? {
+            let hosts = parse_hosts(&contents).map_err(with_path)?;
+
+            info!(
+                "Loaded {} hosts from {}.",
+                hosts.len(),
+                source.path().display()
+            );
+            *self.hosts.write().unwrap() = hosts;
+        }
+
+        Ok(())
+    }
+
+    /// Reload the inventory file whenever it changes. This never
+    /// returns.
+    pub async fn watch(self: Arc<Self>) {
+        reload_periodically(self, Self::reload).await
+    }
+
+    /// Find the host that `peer` belongs to. Hosts without a match
+    /// for the IP address are found by the MAC address of `peer`.
+    pub async fn lookup(&self, peer: &Peer) -> Option<Arc<Host>> {
+        let ip = peer.ip();
+        let ip_v4 = ipv4(ip).map(IpAddr::V4);
+
+        if let Some(host) = self
+            .hosts
+            .read()
+            .unwrap()
+            .iter()
+            .find(|host| host.ip.is_some() && (host.ip == Some(ip) || host.ip == ip_v4))
+        {
+            return Some(host.clone());
+        }
+
+        let mac = peer.mac_address().await?;
+
+        self.hosts
+            .read()
+            .unwrap()
+            .iter()
+            .find(|host| host.mac.as_deref() == Some(mac))
+            .cloned()
+    }
+
+    /// Describe `peer` for log messages, e.g. `10.0.0.10:1234 (node1)`.
+    pub async fn describe(&self, peer: &Peer) -> String {
+        match self
+            .lookup(peer)
+            .await
+            .and_then(|host| host.hostname.clone())
+        {
+            Some(hostname) => format!("{peer} ({hostname})"),
+            None => peer.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+
+    /// A client at `addr` without a MAC address.
+    fn peer(addr: &str) -> Peer {
+        Peer::from(addr.parse::<std::net::SocketAddr>().unwrap())
+    }
+
+    #[tokio::test]
+    async fn parses_hosts() {
+        let inventory = Inventory::parse(
+            "# Our lab
+             ip, mac, hostname, group, kernel_args
+
+             10.0.0.10,,node1,compute,console=ttyS0
+             ,AA-BB-CC-DD-EE-FF,node2,,",
+        )
+        .unwrap();
+
+        let node1 = inventory.lookup(&peer("10.0.0.10:1234")).await.unwrap();
+        assert_eq!(node1.hostname.as_deref(), Some("node1"));
+        assert_eq!(node1.group.as_deref(), Some("compute"));
+        assert_eq!(node1.properties["kernel_args"], "console=ttyS0");
+        assert_eq!(
+            inventory.lookup(&peer("[::ffff:10.0.0.10]:1234")).await,
+            Some(node1)
+        );
+        assert_eq!(
+            inventory.describe(&peer("10.0.0.10:1234")).await,
+            "10.0.0.10:1234 (node1)"
+        );
+
+        let node2 = inventory.hosts.read().unwrap()[1].clone();
+        assert_eq!(node2.mac.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(node2.group, None);
+        assert!(!node2.properties.contains_key("kernel_args"));
+
+        // Without the ARP table, hosts can't be found by MAC address.
+        assert_eq!(inventory.lookup(&peer("10.0.0.11:1234")).await, None);
+        assert_eq!(
+            inventory.describe(&peer("10.0.0.11:1234")).await,
+            "10.0.0.11:1234"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for contents in [
+            "ip,hostname\n10.0.0.1",
+            "ip,hostname\n10.0.0.300,node",
+            "ip,hostname\n,node",
+        ] {
+            let err = Inventory::parse(contents).unwrap_err();
+            assert!(err.to_string().starts_with("line 2:"), "{err}");
+        }
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inventory.csv");
+        let hostname = |inventory: &Inventory| inventory.hosts.read().unwrap()[0].hostname.clone();
+
+        std::fs::write(&path, "ip,hostname\n10.0.0.10,old\n").unwrap();
+        let inventory = Inventory::load(&path).unwrap();
+        assert_eq!(hostname(&inventory).as_deref(), Some("old"));
+
+        // Replace the file like an editor would.
+        let new_path = dir.path().join("inventory.csv.new");
+        std::fs::write(&new_path, "ip,hostname\n10.0.0.10,new\n").unwrap();
+        File::options()
+            .write(true)
+            .open(&new_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        std::fs::rename(&new_path, &path).unwrap();
+
+        // Lookups don't touch the file.
+        assert_eq!(hostname(&inventory).as_deref(), Some("old"));
+
+        inventory.reload().unwrap();
+        assert_eq!(hostname(&inventory).as_deref(), Some("new"));
+
+        // Broken files keep the old hosts.
+        std::fs::write(&path, "ip,hostname\n10.0.0.10\n").unwrap();
+        assert!(inventory.reload().is_err());
+        assert_eq!(hostname(&inventory).as_deref(), Some("new"));
+    }
+}
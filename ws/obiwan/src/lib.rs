@@ -0,0 +1,33 @@
+//! Obiwan is a TFTP server for PXE booting.
+//!
+//! The server is configured and started via [`Server`]. Files are
+//! served from anything that implements [`Filesystem`].
+
+pub mod archive_fs;
+pub mod arp;
+mod batch_io;
+pub mod cache_fs;
+pub mod client_arch;
+pub mod inventory;
+pub mod multicast;
+pub mod path;
+pub mod preload_fs;
+pub mod proxy_dhcp;
+pub mod pxe_lookup;
+pub mod quirks;
+mod rtt;
+mod server;
+pub mod simple_fs;
+pub mod simple_proto;
+pub mod template_fs;
+pub mod tftp;
+pub mod tftp_client;
+mod tftp_proto;
+pub mod transport;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+mod watched_file;
+
+pub use server::{Hooks, Server};
+pub use simple_fs::{File, Filesystem};
+pub use tftp_proto::Options;
@@ -1,23 +1,32 @@
-mod path;
-mod simple_fs;
-mod simple_proto;
-mod tftp;
-mod tftp_proto;
-
 use std::{
-    net::SocketAddr,
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::Arc,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
-use log::{debug, error, info, trace, warn, LevelFilter};
-use tokio::{runtime::Handle, time::timeout};
-
-use crate::{
-    simple_proto::{ConnectionStatus, Event, SimpleUdpProtocol},
-    tftp_proto::Connection,
+use log::{debug, info, warn, LevelFilter};
+
+use obiwan::{
+    archive_fs::ArchiveFilesystem,
+    arp::ArpTable,
+    cache_fs::CachingFilesystem,
+    client_arch::{ArchTable, Architecture},
+    inventory::Inventory,
+    multicast::MulticastGroups,
+    path::normalize,
+    preload_fs::PreloadFilesystem,
+    proxy_dhcp::{BootFiles, ProxyDhcp, DHCP_SERVER_PORT, PXE_BOOT_SERVER_PORT},
+    pxe_lookup::PxeLookup,
+    quirks::{Quirk, Quirks},
+    template_fs::TemplateFilesystem,
+    tftp::Rollover,
+    transport::Transport,
+    File, Filesystem, Options, Server,
 };
 
 /// A simple TFTP server for PXE booting
@@ -40,13 +49,195 @@ struct Args {
     #[arg(short = 'l', long, default_value = "127.0.0.1:69")]
     listen_address: String,
 
+    /// Handle transfers on this many threads. By default, everything
+    /// runs on a single thread.
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// Serve the contents of this uncompressed tar or newc cpio
+    /// archive instead of a directory.
+    #[arg(long, conflicts_with = "directory")]
+    archive: Option<PathBuf>,
+
+    /// Keep this file in memory. The path is relative to the served
+    /// directory. Specify multiple times to preload multiple files.
+    #[arg(long, conflicts_with = "archive")]
+    preload: Vec<PathBuf>,
+
+    /// Keep files from the served directory in memory until their
+    /// total size reaches this many bytes.
+    #[arg(long, conflicts_with = "archive")]
+    preload_limit: Option<u64>,
+
+    /// Walk PXELINUX-style lookup chains on the server for requests
+    /// below this prefix, e.g. `pxelinux.cfg/` or `grub.cfg-`, and
+    /// serve the first file that exists. Specify multiple times for
+    /// multiple prefixes.
+    #[arg(long, value_name = "PREFIX")]
+    pxe_lookup: Vec<String>,
+
+    /// Render `NAME.tmpl` when a client requests a file `NAME` that
+    /// doesn't exist.
+    #[arg(long)]
+    templates: bool,
+
+    /// Make a variable available to templates as `{{KEY}}`. Specify
+    /// multiple times for multiple variables.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_variable, requires = "templates")]
+    template_var: Vec<(String, String)>,
+
+    /// A CSV file with the hosts we serve. Host names appear in log
+    /// messages and all properties are available to templates. The
+    /// file is reloaded when it changes.
+    #[arg(long)]
+    inventory: Option<PathBuf>,
+
+    /// Serve hosts of an inventory group from a subdirectory of the
+    /// served directory. Specify multiple times for multiple groups.
+    #[arg(long, value_name = "GROUP=DIR", value_parser = parse_variable, requires = "inventory")]
+    group_root: Vec<(String, String)>,
+
+    /// Tell PXE clients about our boot files via proxyDHCP on ports 67
+    /// and 4011. This works next to an existing DHCP server.
+    #[arg(long)]
+    proxy_dhcp: bool,
+
+    /// The IPv4 address of this TFTP server announced via proxyDHCP.
+    /// Defaults to the listen address.
+    #[arg(long, requires = "proxy_dhcp")]
+    proxy_dhcp_server_ip: Option<Ipv4Addr>,
+
+    /// The boot file for legacy BIOS clients.
+    #[arg(long, default_value = "undionly.kpxe")]
+    boot_file_bios: String,
+
+    /// The boot file for x86-64 UEFI clients.
+    #[arg(long, default_value = "ipxe.efi")]
+    boot_file_uefi_x64: String,
+
+    /// The boot file for ARM64 UEFI clients.
+    #[arg(long, default_value = "ipxe-arm64.efi")]
+    boot_file_uefi_arm64: String,
+
+    /// The boot file for clients that already run iPXE, such as an
+    /// iPXE script.
+    #[arg(long)]
+    boot_file_ipxe: Option<String>,
+
+    /// Serve FILE instead of NAME to clients of architecture ARCH
+    /// (bios, uefi-x64, or uefi-arm64). The architecture is learned
+    /// via proxyDHCP or from --dhcp-leases. Clients of unknown
+    /// architecture get NAME. Specify multiple times for multiple
+    /// rules.
+    #[arg(long, value_name = "NAME:ARCH=FILE", value_parser = parse_arch_rule)]
+    arch_rule: Vec<(String, Architecture, String)>,
+
+    /// The lease file of an ISC DHCP server to learn the architecture
+    /// of clients from. The file is reloaded when it changes.
+    #[arg(long, value_name = "FILE", requires = "arch_rule")]
+    dhcp_leases: Option<PathBuf>,
+
+    /// Offer RFC 2090 multicast sessions on this multicast group, e.g.
+    /// 239.255.0.1:1758. Clients that request the same file share a
+    /// session. Specify multiple times for a pool of groups to serve
+    /// multiple files at once.
+    #[arg(long, value_name = "ADDR:PORT", value_parser = parse_multicast_group)]
+    multicast_group: Vec<SocketAddrV4>,
+
+    /// What follows block 65535 in large transfers: zero, one, or
+    /// refuse to send files with more blocks. Clients can override
+    /// this with the rollover option.
+    #[arg(long, default_value = "zero")]
+    rollover: Rollover,
+
+    /// The largest block size we agree to. Clients that ask for a
+    /// larger one get this one instead.
+    #[arg(long, default_value_t = 65464, value_parser = clap::value_parser!(u16).range(8..=65464))]
+    max_blksize: u16,
+
+    /// Limit the block size to what fits into the path MTU towards
+    /// each client, so data packets aren't fragmented.
+    #[arg(long)]
+    limit_blksize_to_mtu: bool,
+
+    /// Work around a client bug. This is either the name of a built-in
+    /// quirk or a line such as `old-rom options=tsize ip=10.0.0.0/24
+    /// max-blksize=1024`. See the README for the syntax.
+    #[arg(long, value_name = "NAME|QUIRK", value_parser = parse_quirk)]
+    quirk: Vec<Quirk>,
+
+    /// Send files without options to clients that don't accept our
+    /// option acknowledgement.
+    #[arg(long)]
+    restart_without_options: bool,
+
+    /// Refuse requests larger than this many bytes. RFC 2347 allows
+    /// 512 bytes.
+    #[arg(long, default_value_t = 512, value_parser = clap::value_parser!(u16).range(512..))]
+    max_request_size: u16,
+
+    /// Refuse requests for filenames longer than this many bytes.
+    #[arg(long, default_value_t = 255)]
+    max_filename_length: usize,
+
+    /// Refuse requests with more than this many options.
+    #[arg(long, default_value_t = 16)]
+    max_request_options: usize,
+
     /// The directory to serve via TFTP.
-    directory: PathBuf,
+    #[arg(required_unless_present = "archive")]
+    directory: Option<PathBuf>,
+}
+
+fn parse_variable(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("Expected KEY=VALUE, got: {s}"))
+}
+
+fn parse_multicast_group(s: &str) -> Result<SocketAddrV4, String> {
+    let group: SocketAddrV4 = s.parse().map_err(|e| format!("{e}: {s}"))?;
+
+    if !group.ip().is_multicast() {
+        return Err(format!("Not a multicast address: {}", group.ip()));
+    }
+
+    Ok(group)
+}
+
+fn parse_arch_rule(s: &str) -> Result<(String, Architecture, String), String> {
+    let (name, arch, file) = s
+        .split_once('=')
+        .and_then(|(key, file)| Some((key.rsplit_once(':')?, file)))
+        .map(|((name, arch), file)| (name, arch, file))
+        .ok_or_else(|| format!("Expected NAME:ARCH=FILE, got: {s}"))?;
+
+    Ok((name.to_owned(), arch.parse()?, file.to_owned()))
+}
+
+fn parse_quirk(s: &str) -> Result<Quirk, String> {
+    if let Some(quirk) = Quirk::builtin(s) {
+        return Ok(quirk);
+    }
+
+    if !s.contains(char::is_whitespace) {
+        return Err(format!(
+            "Unknown quirk {s}, expected one of {}",
+            Quirk::builtin_names().collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    s.parse()
 }
 
 /// Try to revoke privileges. This may or may not succeed depending on
 /// our privileges.
 ///
+/// This must be called before any additional threads are created.
+/// `NO_NEW_PRIVS` only applies to the calling thread and threads that
+/// are created afterwards. `setuid` is applied to all threads by the C
+/// library, but it's simpler to reason about when there is only one.
+///
 /// The returned path is refers to the passed directory and is
 /// modified depending on whether we managed to actually change to a
 /// new root directory.
@@ -95,99 +286,205 @@ fn drop_privileges(unprivileged_user: &str, directory: &Path) -> Result<PathBuf>
     Ok(new_root)
 }
 
-/// Sets the port of a socket address to zero. This is useful to let
-/// the OS choose the port number for us.
-fn clear_port(mut addr: SocketAddr) -> SocketAddr {
-    addr.set_port(0);
-    addr
-}
+/// Build the protocol options from the command line. This must
+/// happen before we drop privileges.
+fn options(args: &Args) -> Result<Options> {
+    let pxe_lookup = (!args.pxe_lookup.is_empty())
+        .then(|| Arc::new(PxeLookup::new(args.pxe_lookup.iter().cloned())));
+
+    let inventory = match &args.inventory {
+        Some(path) => {
+            Some(Arc::new(Inventory::load(path).with_context(|| {
+                format!("Failed to load inventory {}", path.display())
+            })?))
+        }
+        None => None,
+    };
 
-async fn send_packet(socket: &tokio::net::UdpSocket, packet: tftp::Packet) -> Result<()> {
-    trace!("{packet:?}");
-    socket.send(&packet.to_vec()).await?;
+    let group_roots = args
+        .group_root
+        .iter()
+        .map(|(group, dir)| {
+            normalize(Path::new(dir))
+                .map(|dir| (group.clone(), dir))
+                .ok_or_else(|| anyhow!("Invalid root directory for group {group}: {dir}"))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut arch_rules: BTreeMap<PathBuf, BTreeMap<Architecture, PathBuf>> = BTreeMap::new();
+
+    for (name, arch, file) in &args.arch_rule {
+        let (Some(name), Some(file)) = (normalize(Path::new(name)), normalize(Path::new(file)))
+        else {
+            bail!("Invalid path in architecture rule: {name}:{arch}={file}");
+        };
+
+        arch_rules.entry(name).or_default().insert(*arch, file);
+    }
 
-    Ok(())
-}
+    let arch_table = if arch_rules.is_empty() {
+        None
+    } else {
+        let arch_table = match &args.dhcp_leases {
+            Some(path) => ArchTable::load_leases(path)
+                .with_context(|| format!("Failed to load DHCP leases {}", path.display()))?,
+            None => ArchTable::default(),
+        };
 
-async fn recv_packet(
-    socket: &tokio::net::UdpSocket,
-    recv_timeout: Duration,
-) -> Result<Option<tftp::Packet>> {
-    let mut buf = vec![0u8; 1 << 16];
+        Some(Arc::new(arch_table))
+    };
 
-    match timeout(recv_timeout, socket.recv(&mut buf)).await {
-        Ok(res) => Some(res?),
-        Err(_) => None,
-    }
-    .map(|len| tftp::Packet::try_from(&buf[0..len]).context("Failed to parse incoming packet"))
-    .transpose()
+    // Without the ARP table, we still find everything by IP address,
+    // but not by MAC address.
+    let arp_table = if pxe_lookup.is_none() && inventory.is_none() && arch_table.is_none() {
+        None
+    } else {
+        match ArpTable::open() {
+            Ok(arp_table) => Some(arp_table),
+            Err(e) => {
+                warn!("Failed to open ARP table, won't look up MAC addresses: {e}");
+                None
+            }
+        }
+    };
+
+    Ok(Options {
+        pxe_lookup,
+        arp_table,
+        inventory,
+        group_roots,
+        arch_table,
+        arch_rules,
+        rollover: args.rollover,
+        max_block_size: args.max_blksize,
+        limit_block_size_to_mtu: args.limit_blksize_to_mtu,
+        max_request_size: args.max_request_size,
+        max_filename_length: args.max_filename_length,
+        max_request_options: args.max_request_options,
+        restart_without_options: args.restart_without_options,
+        quirks: (!args.quirk.is_empty()).then(|| Arc::new(Quirks::new(args.quirk.iter().cloned()))),
+        multicast: (!args.multicast_group.is_empty())
+            .then(|| Arc::new(MulticastGroups::new(args.multicast_group.iter().copied()))),
+        ..Options::default()
+    })
 }
 
-async fn handle_connection(
-    local_addr: SocketAddr,
-    remote_addr: SocketAddr,
-    root: &Path,
-    initial_request: tftp::Packet,
-) -> Result<()> {
-    debug!("{remote_addr}: Establishing new connection.");
-    trace!("{remote_addr}: {initial_request:?}");
+/// The sockets we serve on. They are bound before we drop privileges.
+struct Sockets {
+    tftp: UdpSocket,
+    proxy_dhcp: Option<(Arc<ProxyDhcp>, Vec<UdpSocket>)>,
+}
 
-    let socket = tokio::net::UdpSocket::bind(clear_port(local_addr)).await?;
-    debug!("{remote_addr}: Local address: {}", socket.local_addr()?);
+impl Sockets {
+    fn bind(args: &Args, options: &Options) -> Result<Self> {
+        let tftp = UdpSocket::bind(&args.listen_address).context("Failed to bind server port")?;
 
-    socket.connect(remote_addr).await?;
+        debug!("Opened server socket: {:?}", tftp);
 
-    let mut con = Connection::new(root);
-    let mut packet = Some(initial_request);
+        if !args.proxy_dhcp {
+            return Ok(Self {
+                tftp,
+                proxy_dhcp: None,
+            });
+        }
 
-    loop {
-        let response = con
-            .handle_event(match packet {
-                Some(p) => Event::PacketReceived(p),
-                None => Event::Timeout,
+        let server_ip = match (args.proxy_dhcp_server_ip, tftp.local_addr()?.ip()) {
+            (Some(ip), _) => ip,
+            (None, IpAddr::V4(ip)) if !ip.is_unspecified() => ip,
+            _ => bail!(
+                "Can't announce {}, use --proxy-dhcp-server-ip",
+                args.listen_address
+            ),
+        };
+        let mut responder = ProxyDhcp::new(
+            server_ip,
+            BootFiles {
+                bios: args.boot_file_bios.clone(),
+                uefi_x64: args.boot_file_uefi_x64.clone(),
+                uefi_arm64: args.boot_file_uefi_arm64.clone(),
+                ipxe: args.boot_file_ipxe.clone(),
+            },
+        )?;
+
+        if let Some(arch_table) = &options.arch_table {
+            responder = responder.observations(arch_table.clone());
+        }
+
+        // DHCP clients without an address only reach us via broadcasts,
+        // so we can't bind to a specific address.
+        let sockets = [DHCP_SERVER_PORT, PXE_BOOT_SERVER_PORT]
+            .into_iter()
+            .map(|port| {
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+                    .with_context(|| format!("Failed to bind proxyDHCP port {port}"))
             })
-            .await?;
+            .collect::<Result<_>>()?;
 
-        if let Some(p) = response.packet {
-            send_packet(&socket, p).await?;
-        }
+        info!("Announcing {server_ip} via proxyDHCP.");
 
-        match response.next_status {
-            ConnectionStatus::Terminated => break,
-            ConnectionStatus::WaitingForPacket(timeout) => {
-                packet = recv_packet(&socket, timeout).await?;
-            }
-        }
+        Ok(Self {
+            tftp,
+            proxy_dhcp: Some((Arc::new(responder), sockets)),
+        })
     }
+}
 
-    debug!("{remote_addr}: Connection terminated.");
-    Ok(())
+/// Serve `filesystem` on `sockets` with the settings from the command
+/// line until an error occurs.
+fn serve<FS>(
+    args: &Args,
+    options: Options,
+    transport: Transport,
+    sockets: Sockets,
+    filesystem: FS,
+    root: &Path,
+) -> Result<()>
+where
+    FS: Filesystem<Error = io::Error> + 'static,
+    FS::File: File<Error = io::Error>,
+{
+    if !args.templates {
+        return start(args, options, transport, sockets, filesystem, root);
+    }
+
+    let mut filesystem = TemplateFilesystem::new(filesystem);
+
+    for (name, value) in &args.template_var {
+        filesystem = filesystem.variable(name, value);
+    }
+
+    if let Some(inventory) = &options.inventory {
+        filesystem = filesystem.inventory(inventory.clone());
+    }
+
+    start(args, options, transport, sockets, filesystem, root)
 }
 
-async fn server_main(runtime: &Handle, socket: tokio::net::UdpSocket, root: &Path) -> Result<()> {
-    let local_addr = socket.local_addr()?;
-    let mut buf = vec![0u8; 1 << 16];
-
-    loop {
-        let (len, remote_addr) = socket
-            .recv_from(&mut buf)
-            .await
-            .context("Failed to read from UDP socket")?;
-
-        match tftp::Packet::try_from(&buf[0..len]) {
-            Ok(packet) => {
-                let root = root.to_owned();
-
-                runtime.spawn(async move {
-                    if let Err(e) = handle_connection(local_addr, remote_addr, &root, packet).await
-                    {
-                        error!("Connection to {remote_addr} died due to an error: {e}");
-                    }
-                });
-            }
-            Err(e) => warn!("Ignoring packet: {e}"),
+fn start<FS: Filesystem + 'static>(
+    args: &Args,
+    options: Options,
+    transport: Transport,
+    sockets: Sockets,
+    filesystem: FS,
+    root: &Path,
+) -> Result<()> {
+    let mut server = Server::new(filesystem)
+        .root(root)
+        .socket(sockets.tftp)
+        .options(options)
+        .transport(transport);
+
+    if let Some((responder, proxy_sockets)) = sockets.proxy_dhcp {
+        for socket in proxy_sockets {
+            server = server.proxy_dhcp(socket, responder.clone());
         }
     }
+
+    if let Some(threads) = args.threads {
+        server = server.threads(threads);
+    }
+
+    server.run()
 }
 
 fn main() -> Result<()> {
@@ -206,31 +503,74 @@ fn main() -> Result<()> {
     info!("Hello!");
     debug!("Command line parameters: {:?}", args);
 
-    let socket =
-        std::net::UdpSocket::bind(&args.listen_address).context("Failed to bind server port")?;
+    let options = options(&args)?;
+
+    let sockets = Sockets::bind(&args, &options)?;
+
+    if let Some(archive) = &args.archive {
+        // The archive stays open across the chroot, so we jail
+        // ourselves into the directory that contains it.
+        let filesystem = ArchiveFilesystem::open(archive).context("Failed to open archive")?;
+        let jail = archive
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        drop_privileges(&args.unprivileged_user, jail)?;
 
-    // Because we create the socket without Tokio, we need to make
-    // sure it is non-blocking. Otherwise, Tokio will hang when
-    // reading from it and not schedule other tasks.
-    socket.set_nonblocking(true)?;
+        let transport = Transport::new().context("Failed to set up I/O")?;
 
-    debug!("Opened server socket: {:?}", socket);
+        serve(
+            &args,
+            options,
+            transport,
+            sockets,
+            filesystem,
+            Path::new("/"),
+        )?;
+    } else {
+        let directory = args
+            .directory
+            .as_deref()
+            .ok_or_else(|| anyhow!("No directory to serve"))?;
+        let root_directory = drop_privileges(&args.unprivileged_user, directory)?;
+        let transport = Transport::new().context("Failed to set up I/O")?;
+
+        #[cfg(not(feature = "io-uring"))]
+        let base_filesystem = obiwan::simple_fs::AsyncFilesystem::default();
+        #[cfg(feature = "io-uring")]
+        let base_filesystem = obiwan::uring::UringFilesystem::new(transport.ring());
+
+        let filesystem = PreloadFilesystem::new(CachingFilesystem::new(base_filesystem));
+
+        for path in &args.preload {
+            let local_path = root_directory.join(
+                normalize(path)
+                    .ok_or_else(|| anyhow!("Invalid path to preload: {}", path.display()))?,
+            );
+
+            filesystem
+                .preload(&local_path)
+                .with_context(|| format!("Failed to preload {}", path.display()))?;
+        }
 
-    let root_directory = drop_privileges(&args.unprivileged_user, &args.directory)?;
+        if let Some(limit) = args.preload_limit {
+            let total = filesystem
+                .preload_all(&root_directory, limit)
+                .context("Failed to preload directory")?;
 
-    let tokio_runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .context("Failed to start I/O engine")?;
+            info!("Preloaded {total} bytes.");
+        }
 
-    tokio_runtime.block_on(async {
-        server_main(
-            tokio_runtime.handle(),
-            tokio::net::UdpSocket::from_std(socket)?,
+        serve(
+            &args,
+            options,
+            transport,
+            sockets,
+            filesystem,
             &root_directory,
-        )
-        .await
-    })?;
+        )?;
+    }
 
     info!("Graceful exit. Bye!");
     Ok(())
@@ -0,0 +1,265 @@
+//! This module groups clients into multicast sessions.
+//!
+//! With the `multicast` option of [RFC
+//! 2090](https://datatracker.ietf.org/doc/html/rfc2090), clients that
+//! request the same file share a single stream of data packets that
+//! is sent to a multicast group. One client of each session is the
+//! master client. Only its ACKs drive the transfer. When the master
+//! client is done, the next client of the session becomes master and
+//! asks for the blocks it has missed, so clients can join a running
+//! session at any time.
+//!
+//! Each session needs a group address of its own. They come from a
+//! pool and are returned when the last client of a session leaves.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, info};
+
+/// Identifies the data of a session. Clients share a session only if
+/// they would receive exactly the same packets.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionKey {
+    pub path: PathBuf,
+    pub size: u64,
+    pub block_size: u16,
+}
+
+#[derive(Debug)]
+struct Session {
+    group: SocketAddrV4,
+
+    /// The client whose ACKs we follow.
+    master: SocketAddr,
+
+    /// The other clients, in the order they will become master.
+    waiting: VecDeque<SocketAddr>,
+
+    /// How many memberships each client holds. A client that repeats
+    /// its request joins again, but keeps its place.
+    members: BTreeMap<SocketAddr, usize>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    free_groups: VecDeque<SocketAddrV4>,
+    sessions: BTreeMap<SessionKey, Session>,
+}
+
+/// The pool of multicast groups and the sessions that use them.
+#[derive(Debug, Default)]
+pub struct MulticastGroups {
+    state: Mutex<State>,
+}
+
+impl MulticastGroups {
+    /// Use these multicast addresses and ports for sessions.
+    pub fn new(groups: impl IntoIterator<Item = SocketAddrV4>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                free_groups: groups.into_iter().collect(),
+                sessions: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Add `peer` to the session for `key`. The first client of a
+    /// session becomes its master. Returns `None`, if we are out of
+    /// multicast groups.
+    pub fn join(self: &Arc<Self>, key: SessionKey, peer: SocketAddr) -> Option<Membership> {
+        let mut state = self.state.lock().unwrap();
+
+        let group = match state.sessions.get_mut(&key) {
+            Some(session) => {
+                let count = session.members.entry(peer).or_default();
+
+                *count += 1;
+                if *count == 1 {
+                    session.waiting.push_back(peer);
+                }
+
+                session.group
+            }
+            None => {
+                let Some(group) = state.free_groups.pop_front() else {
+                    debug!("{peer}: No multicast group left for {}", key.path.display());
+                    return None;
+                };
+
+                info!(
+                    "{peer}: Starting multicast session for {} on {group}",
+                    key.path.display()
+                );
+                state.sessions.insert(
+                    key.clone(),
+                    Session {
+                        group,
+                        master: peer,
+                        waiting: VecDeque::new(),
+                        members: BTreeMap::from([(peer, 1)]),
+                    },
+                );
+                group
+            }
+        };
+
+        Some(Membership {
+            groups: self.clone(),
+            key,
+            peer,
+            group,
+        })
+    }
+
+    fn is_master(&self, key: &SessionKey, peer: SocketAddr) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .sessions
+            .get(key)
+            .is_some_and(|session| session.master == peer)
+    }
+
+    fn leave(&self, key: &SessionKey, peer: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let Some(session) = state.sessions.get_mut(key) else {
+            return;
+        };
+
+        match session.members.get_mut(&peer) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            _ => {
+                session.members.remove(&peer);
+            }
+        }
+
+        if session.master != peer {
+            session.waiting.retain(|waiting| *waiting != peer);
+            return;
+        }
+
+        match session.waiting.pop_front() {
+            Some(next) => {
+                debug!("{next}: Taking over as master of {}", session.group);
+                session.master = next;
+            }
+            None => {
+                info!("Multicast session on {} is over.", session.group);
+
+                let group = session.group;
+                state.sessions.remove(key);
+                state.free_groups.push_back(group);
+            }
+        }
+    }
+}
+
+/// A client's place in a session. The client leaves the session when
+/// this is dropped.
+#[derive(Debug)]
+pub struct Membership {
+    groups: Arc<MulticastGroups>,
+    key: SessionKey,
+    peer: SocketAddr,
+    group: SocketAddrV4,
+}
+
+impl Membership {
+    /// The multicast address and port that data is sent to.
+    pub fn group(&self) -> SocketAddrV4 {
+        self.group
+    }
+
+    /// Whether the client currently drives the transfer.
+    pub fn is_master(&self) -> bool {
+        self.groups.is_master(&self.key, self.peer)
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.groups.leave(&self.key, self.peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str) -> SessionKey {
+        SessionKey {
+            path: PathBuf::from(path),
+            size: 1234,
+            block_size: 512,
+        }
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn hands_over_master() {
+        let groups = Arc::new(MulticastGroups::new(["239.255.0.1:1758".parse().unwrap()]));
+
+        let first = groups.join(key("/a"), peer(1)).unwrap();
+        let second = groups.join(key("/a"), peer(2)).unwrap();
+        let third = groups.join(key("/a"), peer(3)).unwrap();
+
+        assert_eq!(second.group(), first.group());
+        assert!(first.is_master());
+        assert!(!second.is_master());
+
+        // Clients that give up before their turn are skipped.
+        drop(second);
+        drop(first);
+        assert!(third.is_master());
+    }
+
+    #[test]
+    fn keeps_repeated_requests_in_place() {
+        let groups = Arc::new(MulticastGroups::new(["239.255.0.1:1758".parse().unwrap()]));
+
+        let first = groups.join(key("/a"), peer(1)).unwrap();
+        let second = groups.join(key("/a"), peer(2)).unwrap();
+        let third = groups.join(key("/a"), peer(3)).unwrap();
+        let second_again = groups.join(key("/a"), peer(2)).unwrap();
+
+        {
+            let state = groups.state.lock().unwrap();
+            let session = &state.sessions[&key("/a")];
+            assert_eq!(session.waiting, [peer(2), peer(3)]);
+        }
+
+        // The client stays in the session until all its requests are
+        // done.
+        drop(second);
+        drop(first);
+        assert!(second_again.is_master());
+        assert!(!third.is_master());
+
+        drop(second_again);
+        assert!(third.is_master());
+    }
+
+    #[test]
+    fn returns_groups_to_the_pool() {
+        let groups = Arc::new(MulticastGroups::new(["239.255.0.1:1758".parse().unwrap()]));
+
+        let a = groups.join(key("/a"), peer(1)).unwrap();
+        assert!(groups.join(key("/b"), peer(2)).is_none());
+
+        drop(a);
+        let b = groups.join(key("/b"), peer(2)).unwrap();
+        assert!(b.is_master());
+        assert_eq!(b.group(), "239.255.0.1:1758".parse().unwrap());
+    }
+}
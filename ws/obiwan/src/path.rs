@@ -5,7 +5,7 @@ use std::path::{Path, PathBuf};
 /// If all '..' cannot be collapsed, this function returns `None`.
 pub fn normalize(path: &Path) -> Option<PathBuf> {
     let collapsed = path.iter().fold(PathBuf::new(), |mut acc, c| {
-        if c == "/" {
+        if c == "/" || c == "." {
             // Skip to avoid making the path absolute.
         } else if c == ".." && acc.parent().is_some() {
             acc.pop();
@@ -47,6 +47,11 @@ mod tests {
 
         assert_eq!(normalize(Path::new("../a")), None);
 
+        assert_eq!(
+            normalize(Path::new("./foo/./bar")),
+            Some(Path::new("foo/bar").to_owned())
+        );
+
         assert_eq!(
             normalize(Path::new("/foo/../bar/../")),
             Some(Path::new("").to_owned())
@@ -0,0 +1,327 @@
+//! This module implements a [`Filesystem`] that keeps
+//! frequently requested files in memory.
+//!
+//! When many clients boot at the same time, they all request the
+//! same handful of files. Serving these from shared immutable buffers
+//! avoids hitting the disk for every block. Files that are not
+//! preloaded are served by the wrapped filesystem.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+
+use crate::simple_fs::{File, Filesystem};
+
+/// A file that is kept in memory.
+#[derive(Debug, Clone)]
+struct Entry {
+    data: Arc<[u8]>,
+
+    /// The modification time of the file when we loaded it.
+    modified: SystemTime,
+}
+
+impl Entry {
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let modified = std::fs::metadata(path)?.modified()?;
+
+        Ok(Self {
+            data: std::fs::read(path)?.into(),
+            modified,
+        })
+    }
+
+    async fn load_async(path: &Path) -> std::io::Result<Self> {
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+
+        Ok(Self {
+            data: tokio::fs::read(path).await?.into(),
+            modified,
+        })
+    }
+}
+
+/// A file that is either served from memory or by the wrapped filesystem.
+#[derive(Debug, Clone)]
+pub enum PreloadedFile<F: File> {
+    Memory(Arc<[u8]>),
+    Passthrough(F),
+}
+
+#[async_trait]
+impl<F: File<Error = std::io::Error>> File for PreloadedFile<F> {
+    type Error = std::io::Error;
+
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::Memory(data) => data.read(offset, buf).await,
+            Self::Passthrough(file) => file.read(offset, buf).await,
+        }
+    }
+
+    async fn read_owned(&self, offset: u64, buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        match self {
+            Self::Memory(data) => data.read_owned(offset, buf).await,
+            Self::Passthrough(file) => file.read_owned(offset, buf).await,
+        }
+    }
+
+    async fn size(&self) -> Result<u64, Self::Error> {
+        match self {
+            Self::Memory(data) => data.size().await,
+            Self::Passthrough(file) => file.size().await,
+        }
+    }
+}
+
+/// A filesystem that serves a set of preloaded files from memory.
+///
+/// Preloaded files are reloaded when their modification time
+/// changes. All other files are passed through to the wrapped
+/// filesystem.
+#[derive(Debug, Clone)]
+pub struct PreloadFilesystem<FS: Filesystem> {
+    inner: FS,
+    entries: Arc<RwLock<BTreeMap<PathBuf, Entry>>>,
+}
+
+impl<FS: Filesystem> PreloadFilesystem<FS> {
+    /// Wrap a filesystem without preloading anything.
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner,
+            entries: Default::default(),
+        }
+    }
+
+    /// Load a single file into memory. The path must be the same
+    /// that is later passed to [`Filesystem::open`].
+    pub fn preload(&self, path: &Path) -> std::io::Result<()> {
+        let entry = Entry::load(path)?;
+
+        info!("Preloaded {} ({} bytes).", path.display(), entry.data.len());
+        self.entries.write().unwrap().insert(path.to_owned(), entry);
+
+        Ok(())
+    }
+
+    /// Load all files below `root` into memory until their total
+    /// size would exceed `limit` bytes. Smaller files are preferred,
+    /// so we cover as many files as possible. Files that are already
+    /// preloaded don't count against the limit. Returns the number of
+    /// bytes that were loaded.
+    pub fn preload_all(&self, root: &Path, limit: u64) -> std::io::Result<u64> {
+        let mut files = vec![];
+
+        collect_files(root, &mut files)?;
+        files.retain(|(path, _)| !self.entries.read().unwrap().contains_key(path));
+        files.sort_by_key(|(path, size)| (*size, path.clone()));
+
+        let mut total = 0;
+
+        for (path, size) in files {
+            if total + size > limit {
+                debug!(
+                    "Not preloading {} ({size} bytes), because it exceeds the limit.",
+                    path.display()
+                );
+                continue;
+            }
+
+            match self.preload(&path) {
+                Ok(()) => total += size,
+                Err(e) => warn!("Failed to preload {}: {e}", path.display()),
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Return the in-memory contents of a preloaded file, reloading
+    /// them if the file has changed on disk.
+    async fn lookup(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let entry = self.entries.read().unwrap().get(path).cloned()?;
+
+        match tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+            Ok(modified) if modified == entry.modified => Some(entry.data),
+            Ok(_) => match Entry::load_async(path).await {
+                Ok(entry) => {
+                    info!("Reloaded changed file {}.", path.display());
+
+                    let data = entry.data.clone();
+                    self.entries.write().unwrap().insert(path.to_owned(), entry);
+                    Some(data)
+                }
+                Err(e) => {
+                    warn!("Failed to reload {}: {e}", path.display());
+                    self.entries.write().unwrap().remove(path);
+                    None
+                }
+            },
+            Err(e) => {
+                // The file is gone or inaccessible. Let the wrapped
+                // filesystem report the error.
+                debug!("Dropping preloaded file {}: {e}", path.display());
+                self.entries.write().unwrap().remove(path);
+                None
+            }
+        }
+    }
+}
+
+/// Recursively collect all regular files below `dir` with their sizes.
+/// Symlinks to regular files are followed. Symlinks to directories are
+/// not, because they might form loops.
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push((entry.path(), entry.metadata()?.len()));
+        } else if file_type.is_symlink() {
+            match std::fs::metadata(entry.path()) {
+                Ok(metadata) if metadata.is_file() => files.push((entry.path(), metadata.len())),
+                Ok(_) => debug!("Not preloading symlink {}.", entry.path().display()),
+                Err(e) => warn!("Not preloading symlink {}: {e}", entry.path().display()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl<FS: Filesystem<Error = std::io::Error>> Filesystem for PreloadFilesystem<FS>
+where
+    FS::File: File<Error = std::io::Error>,
+{
+    type File = PreloadedFile<FS::File>;
+    type Error = std::io::Error;
+
+    async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+        match self.lookup(path).await {
+            Some(data) => Ok(PreloadedFile::Memory(data)),
+            None => self.inner.open(path).await.map(PreloadedFile::Passthrough),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::simple_fs::AsyncFilesystem;
+
+    use super::*;
+
+    async fn read_all<F: File>(file: &F) -> Vec<u8> {
+        let mut buf = vec![0; usize::try_from(file.size().await.unwrap()).unwrap()];
+
+        assert_eq!(file.read(0, &mut buf).await.unwrap(), buf.len());
+        buf
+    }
+
+    #[tokio::test]
+    async fn serves_and_refreshes_preloaded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipxe.efi");
+
+        std::fs::write(&path, b"old").unwrap();
+
+        let fs = PreloadFilesystem::new(AsyncFilesystem::default());
+        fs.preload(&path).unwrap();
+
+        let file = fs.open(&path).await.unwrap();
+        assert!(matches!(file, PreloadedFile::Memory(_)));
+        assert_eq!(read_all(&file).await, b"old");
+
+        // Make sure the modification time changes, even on
+        // filesystems with coarse timestamps.
+        std::fs::write(&path, b"new contents").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let new_file = fs.open(&path).await.unwrap();
+        assert!(matches!(new_file, PreloadedFile::Memory(_)));
+        assert_eq!(read_all(&new_file).await, b"new contents");
+
+        // Already opened files keep their contents.
+        assert_eq!(read_all(&file).await, b"old");
+    }
+
+    #[tokio::test]
+    async fn passes_through_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kernel");
+
+        std::fs::write(&path, b"kernel").unwrap();
+
+        let fs = PreloadFilesystem::new(AsyncFilesystem::default());
+        let file = fs.open(&path).await.unwrap();
+
+        assert!(matches!(file, PreloadedFile::Passthrough(_)));
+        assert_eq!(read_all(&file).await, b"kernel");
+        assert!(fs.open(&dir.path().join("missing")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn preload_all_respects_limit() {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a"), [0; 10]).unwrap();
+        std::fs::write(dir.path().join("sub/b"), [0; 20]).unwrap();
+        std::fs::write(dir.path().join("c"), [0; 100]).unwrap();
+
+        let fs = PreloadFilesystem::new(AsyncFilesystem::default());
+
+        assert_eq!(fs.preload_all(dir.path(), 50).unwrap(), 30);
+        assert!(matches!(
+            fs.open(&dir.path().join("sub/b")).await.unwrap(),
+            PreloadedFile::Memory(_)
+        ));
+        assert!(matches!(
+            fs.open(&dir.path().join("c")).await.unwrap(),
+            PreloadedFile::Passthrough(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn preload_all_skips_preloaded_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a"), [0; 10]).unwrap();
+        std::fs::write(dir.path().join("b"), [0; 20]).unwrap();
+        std::os::unix::fs::symlink("b", dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("sub", dir.path().join("dir-link")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.path().join("dangling")).unwrap();
+
+        let fs = PreloadFilesystem::new(AsyncFilesystem::default());
+
+        fs.preload(&dir.path().join("b")).unwrap();
+
+        assert_eq!(fs.preload_all(dir.path(), 30).unwrap(), 30);
+        assert!(matches!(
+            fs.open(&dir.path().join("a")).await.unwrap(),
+            PreloadedFile::Memory(_)
+        ));
+        assert!(matches!(
+            fs.open(&dir.path().join("link")).await.unwrap(),
+            PreloadedFile::Memory(_)
+        ));
+    }
+}
@@ -0,0 +1,588 @@
+//! This module implements a proxyDHCP responder.
+//!
+//! A proxyDHCP server doesn't hand out addresses. It only tells PXE
+//! clients where to find their boot file, so it can run next to a
+//! DHCP server that we don't control. See the PXE specification 2.1,
+//! section 2.2.
+//!
+//! Clients broadcast their DHCPDISCOVER to port 67 and we answer with
+//! an offer that contains our TFTP server and a boot file. Some
+//! clients then send a DHCPREQUEST to port 4011, which we acknowledge
+//! with the same information.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, trace};
+use tokio::net::UdpSocket;
+
+use crate::client_arch::ArchTable;
+pub use crate::client_arch::Architecture;
+
+/// The well-known port of DHCP servers.
+pub const DHCP_SERVER_PORT: u16 = 67;
+
+/// The port PXE clients send their boot server requests to.
+pub const PXE_BOOT_SERVER_PORT: u16 = 4011;
+
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// The offset of the options in a DHCP packet.
+const OPTIONS_OFFSET: usize = 240;
+
+/// The size of the `file` field of the DHCP header, including the
+/// terminating NUL.
+const FILE_FIELD_SIZE: usize = 128;
+
+/// Flags of the DHCP header.
+const FLAG_BROADCAST: u16 = 0x8000;
+
+mod option {
+    pub const PAD: u8 = 0;
+    pub const VENDOR_SPECIFIC: u8 = 43;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_IDENTIFIER: u8 = 54;
+    pub const VENDOR_CLASS: u8 = 60;
+    pub const TFTP_SERVER_NAME: u8 = 66;
+    pub const BOOTFILE_NAME: u8 = 67;
+    pub const USER_CLASS: u8 = 77;
+    pub const CLIENT_ARCHITECTURE: u8 = 93;
+    pub const CLIENT_UUID: u8 = 97;
+    pub const END: u8 = 255;
+}
+
+mod message_type {
+    pub const DISCOVER: u8 = 1;
+    pub const OFFER: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const ACK: u8 = 5;
+}
+
+/// The PXE_DISCOVERY_CONTROL sub-option of option 43.
+const PXE_DISCOVERY_CONTROL: u8 = 6;
+
+/// Tells the client to download the boot file without showing a boot
+/// menu or looking for boot servers.
+const DISCOVERY_USE_BOOTFILE: u8 = 0x08;
+
+/// The boot files we announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootFiles {
+    pub bios: String,
+    pub uefi_x64: String,
+    pub uefi_arm64: String,
+
+    /// Served to clients that are already running iPXE, so they don't
+    /// load iPXE again in an endless loop.
+    pub ipxe: Option<String>,
+}
+
+impl Default for BootFiles {
+    fn default() -> Self {
+        Self {
+            bios: "undionly.kpxe".to_owned(),
+            uefi_x64: "ipxe.efi".to_owned(),
+            uefi_arm64: "ipxe-arm64.efi".to_owned(),
+            ipxe: None,
+        }
+    }
+}
+
+impl BootFiles {
+    fn for_architecture(&self, arch: Architecture) -> &str {
+        match arch {
+            Architecture::Bios => &self.bios,
+            Architecture::UefiX64 => &self.uefi_x64,
+            Architecture::UefiArm64 => &self.uefi_arm64,
+        }
+    }
+}
+
+/// The parts of a DHCP request we care about.
+#[derive(Debug)]
+struct Request<'a> {
+    header: &'a [u8],
+    message_type: u8,
+    vendor_class: &'a [u8],
+    user_class: Option<&'a [u8]>,
+    architecture: Option<u16>,
+    uuid: Option<&'a [u8]>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < OPTIONS_OFFSET
+            || packet[0] != BOOTREQUEST
+            || packet[236..OPTIONS_OFFSET] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut request = Request {
+            header: &packet[..OPTIONS_OFFSET],
+            message_type: 0,
+            vendor_class: &[],
+            user_class: None,
+            architecture: None,
+            uuid: None,
+        };
+        let mut options = &packet[OPTIONS_OFFSET..];
+
+        loop {
+            match options {
+                [] | [option::END, ..] => break,
+                [option::PAD, rest @ ..] => options = rest,
+                [code, len, rest @ ..] if rest.len() >= usize::from(*len) => {
+                    let (value, rest) = rest.split_at(usize::from(*len));
+
+                    match (*code, value) {
+                        (option::MESSAGE_TYPE, [message_type]) => {
+                            request.message_type = *message_type
+                        }
+                        (option::VENDOR_CLASS, _) => request.vendor_class = value,
+                        (option::USER_CLASS, _) => request.user_class = Some(value),
+                        (option::CLIENT_ARCHITECTURE, [hi, lo, ..]) => {
+                            request.architecture = Some(u16::from_be_bytes([*hi, *lo]))
+                        }
+                        (option::CLIENT_UUID, _) => request.uuid = Some(value),
+                        _ => (),
+                    }
+
+                    options = rest;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(request)
+    }
+
+    fn xid(&self) -> &[u8] {
+        &self.header[4..8]
+    }
+
+    fn flags(&self) -> u16 {
+        u16::from_be_bytes([self.header[10], self.header[11]])
+    }
+
+    fn ciaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::new(
+            self.header[12],
+            self.header[13],
+            self.header[14],
+            self.header[15],
+        )
+    }
+
+    fn giaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::new(
+            self.header[24],
+            self.header[25],
+            self.header[26],
+            self.header[27],
+        )
+    }
+
+    /// The hardware address of the client, including padding.
+    fn chaddr(&self) -> &[u8] {
+        &self.header[28..44]
+    }
+
+    /// The MAC address of the client, such as `aa:bb:cc:dd:ee:ff`.
+    fn mac(&self) -> String {
+        let len = usize::from(self.header[2]).min(16);
+
+        self.chaddr()[..len]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+/// Answers PXE requests with our TFTP server and a boot file.
+#[derive(Debug, Clone)]
+pub struct ProxyDhcp {
+    server_ip: Ipv4Addr,
+    boot_files: BootFiles,
+    observations: Option<Arc<ArchTable>>,
+}
+
+fn push_option(packet: &mut Vec<u8>, code: u8, value: &[u8]) {
+    packet.push(code);
+    packet.push(u8::try_from(value.len()).expect("DHCP options are at most 255 bytes"));
+    packet.extend_from_slice(value);
+}
+
+impl ProxyDhcp {
+    /// Announce the TFTP server at `server_ip`.
+    pub fn new(server_ip: Ipv4Addr, boot_files: BootFiles) -> Result<Self> {
+        let BootFiles {
+            bios,
+            uefi_x64,
+            uefi_arm64,
+            ipxe,
+        } = &boot_files;
+
+        for file in [bios, uefi_x64, uefi_arm64].into_iter().chain(ipxe) {
+            if file.len() >= FILE_FIELD_SIZE {
+                bail!("Boot file name is too long for DHCP: {file}");
+            }
+        }
+
+        Ok(Self {
+            server_ip,
+            boot_files,
+            observations: None,
+        })
+    }
+
+    /// Remember the architecture of every client we answer, so TFTP
+    /// requests can be rewritten for it later.
+    pub fn observations(mut self, table: Arc<ArchTable>) -> Self {
+        self.observations = Some(table);
+        self
+    }
+
+    /// Build the reply to a DHCP packet. Returns `None` for packets
+    /// that are not PXE requests.
+    fn respond(&self, packet: &[u8]) -> Option<(Vec<u8>, Architecture, String)> {
+        let request = Request::parse(packet)?;
+
+        let reply_type = match request.message_type {
+            message_type::DISCOVER => message_type::OFFER,
+            message_type::REQUEST => message_type::ACK,
+            _ => return None,
+        };
+
+        if !request.vendor_class.starts_with(b"PXEClient") {
+            return None;
+        }
+
+        let arch = match request.architecture {
+            // Clients that don't send option 93 are legacy BIOS.
+            None => Architecture::Bios,
+            Some(arch) => match Architecture::from_client_architecture(arch) {
+                Some(arch) => arch,
+                None => {
+                    debug!("Ignoring PXE client with unknown architecture {arch}.");
+                    return None;
+                }
+            },
+        };
+
+        if let Some(table) = &self.observations {
+            table.observe(&request.mac(), Some(request.ciaddr()), arch);
+        }
+
+        let boot_file = match (&self.boot_files.ipxe, request.user_class) {
+            (Some(ipxe), Some(b"iPXE")) => ipxe,
+            _ => self.boot_files.for_architecture(arch),
+        };
+
+        let mut reply = vec![0u8; OPTIONS_OFFSET];
+
+        reply[0] = BOOTREPLY;
+        reply[1..3].copy_from_slice(&request.header[1..3]);
+        reply[4..8].copy_from_slice(request.xid());
+        reply[10..12].copy_from_slice(&request.flags().to_be_bytes());
+        reply[12..16].copy_from_slice(&request.ciaddr().octets());
+        reply[20..24].copy_from_slice(&self.server_ip.octets());
+        reply[24..28].copy_from_slice(&request.giaddr().octets());
+        reply[28..44].copy_from_slice(request.chaddr());
+
+        // Old clients only look at the fixed fields.
+        let file = boot_file.as_bytes();
+        reply[108..108 + file.len()].copy_from_slice(file);
+
+        reply[236..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut reply, option::MESSAGE_TYPE, &[reply_type]);
+        push_option(
+            &mut reply,
+            option::SERVER_IDENTIFIER,
+            &self.server_ip.octets(),
+        );
+        push_option(&mut reply, option::VENDOR_CLASS, b"PXEClient");
+        if let Some(uuid) = request.uuid {
+            push_option(&mut reply, option::CLIENT_UUID, uuid);
+        }
+        push_option(
+            &mut reply,
+            option::VENDOR_SPECIFIC,
+            &[
+                PXE_DISCOVERY_CONTROL,
+                1,
+                DISCOVERY_USE_BOOTFILE,
+                option::END,
+            ],
+        );
+        push_option(
+            &mut reply,
+            option::TFTP_SERVER_NAME,
+            self.server_ip.to_string().as_bytes(),
+        );
+        push_option(&mut reply, option::BOOTFILE_NAME, file);
+        reply.push(option::END);
+
+        Some((reply, arch, request.mac()))
+    }
+
+    /// Where to send the reply to a request from `peer`.
+    fn destination(packet: &[u8], peer: SocketAddr) -> SocketAddr {
+        let request = Request::parse(packet).expect("We only answer valid requests");
+
+        if !request.giaddr().is_unspecified() {
+            SocketAddrV4::new(request.giaddr(), DHCP_SERVER_PORT).into()
+        } else if request.flags() & FLAG_BROADCAST != 0
+            || peer.ip().is_unspecified()
+            || peer.port() == DHCP_CLIENT_PORT
+        {
+            // The client has no address yet.
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT).into()
+        } else {
+            peer
+        }
+    }
+
+    /// Answer requests on `socket` until an error occurs. The socket
+    /// must allow broadcasts.
+    pub async fn serve(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let mut buf = vec![0u8; 1500];
+
+        loop {
+            let (len, peer) = socket
+                .recv_from(&mut buf)
+                .await
+                .context("Failed to read from proxyDHCP socket")?;
+            let packet = &buf[..len];
+
+            trace!("{peer}: DHCP packet: {packet:x?}");
+
+            let Some((reply, arch, mac)) = self.respond(packet) else {
+                continue;
+            };
+            let destination = Self::destination(packet, peer);
+
+            info!("Sending boot file to {mac} ({arch:?}) at {destination} via proxyDHCP.");
+
+            if let Err(e) = socket.send_to(&reply, destination).await {
+                // A single unreachable client shouldn't stop us.
+                debug!("Failed to send proxyDHCP reply to {destination}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+
+    /// Craft a DHCP request with the given options.
+    fn request(message_type: u8, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut packet = vec![0u8; OPTIONS_OFFSET];
+
+        packet[0] = BOOTREQUEST;
+        packet[1] = 1;
+        packet[2] = 6;
+        packet[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        packet[28..34].copy_from_slice(&MAC);
+        packet[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut packet, option::MESSAGE_TYPE, &[message_type]);
+        for (code, value) in options {
+            push_option(&mut packet, *code, value);
+        }
+        packet.push(option::END);
+
+        packet
+    }
+
+    fn pxe_request(message_type: u8, arch: u16) -> Vec<u8> {
+        request(
+            message_type,
+            &[
+                (
+                    option::VENDOR_CLASS,
+                    b"PXEClient:Arch:00007:UNDI:003016".as_slice(),
+                ),
+                (option::CLIENT_ARCHITECTURE, &arch.to_be_bytes()),
+            ],
+        )
+    }
+
+    fn proxy() -> ProxyDhcp {
+        ProxyDhcp::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            BootFiles {
+                ipxe: Some("boot.ipxe".to_owned()),
+                ..BootFiles::default()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Returns the value of `code` in a reply.
+    fn find_option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &reply[OPTIONS_OFFSET..];
+
+        while let [c, len, rest @ ..] = options {
+            let (value, rest) = rest.split_at(usize::from(*len));
+
+            if *c == code {
+                return Some(value);
+            }
+            options = rest;
+        }
+
+        None
+    }
+
+    #[test]
+    fn offers_boot_file_by_architecture() {
+        for (arch, file) in [
+            (0, "undionly.kpxe"),
+            (7, "ipxe.efi"),
+            (9, "ipxe.efi"),
+            (11, "ipxe-arm64.efi"),
+        ] {
+            let (reply, _, mac) = proxy()
+                .respond(&pxe_request(message_type::DISCOVER, arch))
+                .unwrap();
+
+            assert_eq!(mac, "aa:bb:cc:dd:ee:ff");
+            assert_eq!(reply[0], BOOTREPLY);
+            assert_eq!(reply[4..8], [1, 2, 3, 4]);
+            assert_eq!(reply[16..20], [0, 0, 0, 0], "We don't hand out addresses");
+            assert_eq!(reply[20..24], [10, 0, 0, 1]);
+            assert_eq!(reply[28..34], MAC);
+            assert_eq!(&reply[108..108 + file.len()], file.as_bytes());
+            assert_eq!(
+                find_option(&reply, option::MESSAGE_TYPE),
+                Some([message_type::OFFER].as_slice())
+            );
+            assert_eq!(
+                find_option(&reply, option::BOOTFILE_NAME),
+                Some(file.as_bytes())
+            );
+            assert_eq!(
+                find_option(&reply, option::TFTP_SERVER_NAME),
+                Some(b"10.0.0.1".as_slice())
+            );
+        }
+    }
+
+    #[test]
+    fn acknowledges_requests() {
+        let mut packet = pxe_request(message_type::REQUEST, 9);
+        packet[12..16].copy_from_slice(&[10, 0, 0, 50]);
+
+        let (reply, arch, _) = proxy().respond(&packet).unwrap();
+
+        assert_eq!(arch, Architecture::UefiX64);
+        assert_eq!(reply[12..16], [10, 0, 0, 50]);
+        assert_eq!(
+            find_option(&reply, option::MESSAGE_TYPE),
+            Some([message_type::ACK].as_slice())
+        );
+        assert_eq!(
+            ProxyDhcp::destination(&packet, "10.0.0.50:1234".parse().unwrap()),
+            "10.0.0.50:1234".parse().unwrap()
+        );
+        assert_eq!(
+            ProxyDhcp::destination(&packet, "0.0.0.0:68".parse().unwrap()),
+            "255.255.255.255:68".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn breaks_ipxe_loops() {
+        let packet = request(
+            message_type::DISCOVER,
+            &[
+                (option::VENDOR_CLASS, b"PXEClient".as_slice()),
+                (option::USER_CLASS, b"iPXE"),
+            ],
+        );
+
+        let (reply, _, _) = proxy().respond(&packet).unwrap();
+        assert_eq!(
+            find_option(&reply, option::BOOTFILE_NAME),
+            Some(b"boot.ipxe".as_slice())
+        );
+    }
+
+    #[test]
+    fn rejects_long_boot_files() {
+        let boot_files = BootFiles {
+            bios: "a".repeat(FILE_FIELD_SIZE),
+            ..BootFiles::default()
+        };
+
+        assert!(ProxyDhcp::new(Ipv4Addr::LOCALHOST, boot_files).is_err());
+    }
+
+    #[test]
+    fn ignores_other_packets() {
+        let proxy = proxy();
+
+        // Not a PXE client.
+        assert!(proxy
+            .respond(&request(message_type::DISCOVER, &[]))
+            .is_none());
+        // Unknown architecture.
+        assert!(proxy
+            .respond(&pxe_request(message_type::DISCOVER, 6))
+            .is_none());
+        // DHCPRELEASE.
+        assert!(proxy.respond(&pxe_request(7, 0)).is_none());
+        // Truncated packets and options.
+        let packet = pxe_request(message_type::DISCOVER, 0);
+        assert!(proxy.respond(&packet[..200]).is_none());
+        assert!(proxy.respond(&packet[..packet.len() - 2]).is_none());
+    }
+
+    #[tokio::test]
+    async fn records_architectures() {
+        let table = Arc::new(ArchTable::default());
+        let proxy = proxy().observations(table.clone());
+        let mut packet = pxe_request(message_type::REQUEST, 11);
+        packet[12..16].copy_from_slice(&[10, 0, 0, 50]);
+
+        proxy.respond(&packet).unwrap();
+        assert_eq!(
+            table
+                .lookup(&SocketAddr::from(([10, 0, 0, 50], 1234)).into(), None)
+                .await,
+            Some(Architecture::UefiArm64)
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_on_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        tokio::spawn(Arc::new(proxy()).serve(socket));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packet = pxe_request(message_type::REQUEST, 0);
+        packet[12..16].copy_from_slice(&[127, 0, 0, 1]);
+        client.send_to(&packet, server_addr).await.unwrap();
+
+        let mut buf = vec![0; 1500];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            find_option(&buf[..len], option::BOOTFILE_NAME),
+            Some(b"undionly.kpxe".as_slice())
+        );
+    }
+}
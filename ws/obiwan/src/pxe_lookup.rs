@@ -0,0 +1,260 @@
+//! Server-side resolution of PXELINUX-style configuration lookups.
+//!
+//! PXELINUX asks for `pxelinux.cfg/<UUID>`, then
+//! `pxelinux.cfg/01-<MAC>`, then the client's IPv4 address in hex
+//! with one digit less each time, and finally `pxelinux.cfg/default`.
+//! GRUB does the same with `grub.cfg-<...>` and `grub.cfg`. Every miss
+//! costs the client a round trip, so [`PxeLookup`] lets the server walk
+//! the chain on the first request instead.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    arp::{ipv4, Peer},
+    inventory::Host,
+};
+
+/// Computes the lookup chain for requests below a set of prefixes
+/// (lookup families).
+#[derive(Debug)]
+pub struct PxeLookup {
+    families: Vec<String>,
+}
+
+impl PxeLookup {
+    /// Resolve lookups below these prefixes, e.g. `pxelinux.cfg/` or
+    /// `grub.cfg-`. Prefixes that end in `/` are directories with a
+    /// `default` file in them. For other prefixes, the default file is
+    /// the prefix without a trailing `-`.
+    pub fn new<S: Into<String>>(families: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            families: families
+                .into_iter()
+                .map(|f| f.into().trim_start_matches('/').to_owned())
+                .collect(),
+        }
+    }
+
+    /// Returns the files to try for a request of `path` by `peer`, best
+    /// match first. `path` must be normalized. The MAC address of
+    /// `host` is preferred over the one of `peer`, which allows to try
+    /// `01-<MAC>` before the client asks for it. Returns `None`, if
+    /// `path` is not the start of a lookup chain.
+    pub async fn candidates(
+        &self,
+        path: &Path,
+        peer: &Peer,
+        host: Option<&Host>,
+    ) -> Option<Vec<PathBuf>> {
+        let ip = peer.ip();
+        let path = path.to_str()?;
+        let (family, name) = self
+            .families
+            .iter()
+            .find_map(|family| Some((family, path.strip_prefix(family.as_str())?)))?;
+
+        let mut names = vec![name.to_owned()];
+
+        let hex_ip = if is_hex_ip_of(name, ip) {
+            name.to_owned()
+        } else if is_uuid(name) || is_mac(name) {
+            if is_uuid(name) {
+                let mac = match host.and_then(|host| host.mac.as_deref()) {
+                    Some(mac) => Some(mac),
+                    None => peer.mac_address().await,
+                };
+
+                names.extend(mac.map(|mac| format!("01-{}", mac.replace(':', "-"))));
+            }
+
+            hex_ip(ip).unwrap_or_default()
+        } else {
+            return None;
+        };
+
+        names.extend((1..=hex_ip.len()).rev().map(|len| hex_ip[..len].to_owned()));
+        names.dedup();
+
+        let mut candidates: Vec<PathBuf> = names
+            .into_iter()
+            .map(|name| format!("{family}{name}").into())
+            .collect();
+        candidates.push(default_file(family).into());
+
+        Some(candidates)
+    }
+}
+
+/// The file that is requested when nothing more specific exists.
+fn default_file(family: &str) -> String {
+    if family.ends_with('/') {
+        format!("{family}default")
+    } else {
+        family.trim_end_matches('-').to_owned()
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Checks for `01234567-89ab-cdef-0123-456789abcdef`.
+fn is_uuid(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('-').collect();
+
+    parts.len() == 5
+        && parts
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(part, len)| is_hex(part, len))
+}
+
+/// Checks for `01-aa-bb-cc-dd-ee-ff`, i.e. an Ethernet address.
+fn is_mac(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('-').collect();
+
+    parts.len() == 7 && parts[0] == "01" && parts.iter().all(|part| is_hex(part, 2))
+}
+
+/// Checks for a prefix of the peer's IPv4 address in upper case hex,
+/// such as `C0A8`. Other names that happen to look like hex, such as
+/// `01` or `123`, are ordinary files.
+fn is_hex_ip_of(name: &str, peer: IpAddr) -> bool {
+    !name.is_empty() && hex_ip(peer).is_some_and(|hex_ip| hex_ip.starts_with(name))
+}
+
+fn hex_ip(peer: IpAddr) -> Option<String> {
+    ipv4(peer).map(|ip| format!("{:08X}", u32::from(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inventory::Inventory;
+
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Option<Vec<PathBuf>> {
+        Some(paths.iter().map(PathBuf::from).collect())
+    }
+
+    /// A client at `ip` without a MAC address.
+    fn peer(ip: &str) -> Peer {
+        Peer::from(std::net::SocketAddr::new(ip.parse().unwrap(), 1234))
+    }
+
+    #[tokio::test]
+    async fn pxelinux_chain() {
+        let lookup = PxeLookup::new(["/pxelinux.cfg/"]);
+        let peer = &peer("192.168.1.2");
+
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"), peer, None)
+                .await,
+            paths(&[
+                "pxelinux.cfg/01-aa-bb-cc-dd-ee-ff",
+                "pxelinux.cfg/C0A80102",
+                "pxelinux.cfg/C0A8010",
+                "pxelinux.cfg/C0A801",
+                "pxelinux.cfg/C0A80",
+                "pxelinux.cfg/C0A8",
+                "pxelinux.cfg/C0A",
+                "pxelinux.cfg/C0",
+                "pxelinux.cfg/C",
+                "pxelinux.cfg/default",
+            ])
+        );
+
+        // A client that already walked part of the chain continues
+        // where it is.
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/C0A"), peer, None)
+                .await,
+            paths(&[
+                "pxelinux.cfg/C0A",
+                "pxelinux.cfg/C0",
+                "pxelinux.cfg/C",
+                "pxelinux.cfg/default",
+            ])
+        );
+
+        // Other files in the directory are served as usual, even if
+        // they look like hex.
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/menu.c32"), peer, None)
+                .await,
+            None
+        );
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/01"), peer, None)
+                .await,
+            None
+        );
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/123"), peer, None)
+                .await,
+            None
+        );
+        assert_eq!(
+            lookup
+                .candidates(Path::new("pxelinux.cfg/0A000001"), peer, None)
+                .await,
+            None
+        );
+        assert_eq!(
+            lookup.candidates(Path::new("pxelinux.0"), peer, None).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn grub_chain_with_uuid() {
+        let lookup = PxeLookup::new(["grub.cfg-"]);
+        let peer = &peer("10.0.0.1");
+
+        let uuid = Path::new("grub.cfg-01234567-89ab-cdef-0123-456789abcdef");
+
+        assert_eq!(
+            lookup.candidates(uuid, peer, None).await.unwrap()[..3],
+            paths(&[
+                "grub.cfg-01234567-89ab-cdef-0123-456789abcdef",
+                "grub.cfg-0A000001",
+                "grub.cfg-0A00000",
+            ])
+            .unwrap()
+        );
+
+        // The inventory tells us the MAC address.
+        let inventory = Inventory::parse("ip,mac\n10.0.0.1,AA:BB:CC:DD:EE:FF").unwrap();
+        let host = inventory.lookup(peer).await;
+
+        assert_eq!(
+            lookup
+                .candidates(uuid, peer, host.as_deref())
+                .await
+                .unwrap()[..3],
+            paths(&[
+                "grub.cfg-01234567-89ab-cdef-0123-456789abcdef",
+                "grub.cfg-01-aa-bb-cc-dd-ee-ff",
+                "grub.cfg-0A000001",
+            ])
+            .unwrap()
+        );
+        assert_eq!(
+            lookup
+                .candidates(Path::new("grub.cfg-0"), peer, None)
+                .await
+                .unwrap()
+                .last()
+                .unwrap(),
+            Path::new("grub.cfg")
+        );
+    }
+}
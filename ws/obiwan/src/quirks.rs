@@ -0,0 +1,378 @@
+//! This module works around bugs of particular clients.
+//!
+//! A quirk matches clients by the options they send, which tends to
+//! identify the firmware, and by their address. Each quirk is a line
+//! of a name, matchers and fixes:
+//!
+//! ```text
+//! old-rom options=tsize,blksize ip=10.0.0.0/24 ignore=tsize max-blksize=1024
+//! ```
+//!
+//! - `options=NAME,...` matches requests with exactly these options in
+//!   this order. `options=` matches requests without options.
+//! - `ip=ADDR[/LEN]` matches clients in this network.
+//! - `ignore=NAME,...` pretends the client didn't send these options.
+//! - `no-oack` ignores all options, so the client gets no OACK.
+//! - `max-blksize=N` and `max-windowsize=N` cap what we agree to.
+//!
+//! A quirk without matchers applies to all clients. The fixes of all
+//! matching quirks are combined.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+};
+
+use log::info;
+
+use crate::{arp::ipv4, tftp::RequestOption, tftp_proto::Options};
+
+/// Quirks of firmware we know about. They are only used when enabled
+/// by name, because their fingerprints can match healthy clients too.
+const BUILTIN: &[&str] = &[
+    // Old Intel PXE 2.1 ROMs ask for the file size alone and then
+    // don't understand the OACK.
+    "intel-pxe-2.1 options=tsize no-oack",
+    // Some Broadcom UEFI builds fail with blocks that don't fit into
+    // an Ethernet frame.
+    "broadcom-uefi options=blksize,tsize max-blksize=1468",
+    // For firmware that gets confused by the transfer size.
+    "no-tsize ignore=tsize",
+];
+
+/// An IPv4 or IPv6 network, such as `10.0.0.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    fn contains(&self, peer: IpAddr) -> bool {
+        let (addr, peer, bits) = match (self.addr, ipv4(peer)) {
+            (IpAddr::V4(addr), Some(peer)) => {
+                (u128::from(addr.to_bits()), u128::from(peer.to_bits()), 32)
+            }
+            (IpAddr::V6(addr), None) => (addr.to_bits(), to_ipv6(peer).to_bits(), 128),
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix_len);
+
+        addr.checked_shr(host_bits).unwrap_or(0) == peer.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid address {addr}: {e}"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length {prefix_len}"))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// How we treat a client differently.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixes {
+    /// Options we pretend the client didn't send, in lower case.
+    pub ignore: BTreeSet<String>,
+
+    /// Ignore all options.
+    pub no_oack: bool,
+
+    pub max_block_size: Option<u16>,
+    pub max_window_size: Option<u16>,
+}
+
+impl Fixes {
+    fn merge(&mut self, other: &Fixes) {
+        self.ignore.extend(other.ignore.iter().cloned());
+        self.no_oack |= other.no_oack;
+        self.max_block_size = min_limit(self.max_block_size, other.max_block_size);
+        self.max_window_size = min_limit(self.max_window_size, other.max_window_size);
+    }
+
+    /// Lower the limits in `options` and return the options of the
+    /// request that are left.
+    pub fn apply<'a>(
+        &self,
+        options: &mut Options,
+        request: &[RequestOption<'a>],
+    ) -> Vec<RequestOption<'a>> {
+        if let Some(max_block_size) = self.max_block_size {
+            options.max_block_size = options.max_block_size.min(max_block_size);
+        }
+        if let Some(max_window_size) = self.max_window_size {
+            options.max_window_size = options.max_window_size.min(max_window_size);
+        }
+
+        if self.no_oack {
+            return vec![];
+        }
+
+        request
+            .iter()
+            .filter(|option| !self.ignore.contains(&option.name.to_ascii_lowercase()))
+            .cloned()
+            .collect()
+    }
+}
+
+fn min_limit(a: Option<u16>, b: Option<u16>) -> Option<u16> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A workaround for clients that match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quirk {
+    pub name: String,
+
+    /// The names of the options the client sends, in lower case.
+    fingerprint: Option<Vec<String>>,
+    network: Option<Network>,
+    fixes: Fixes,
+}
+
+impl Quirk {
+    /// Look up a quirk of the built-in table.
+    pub fn builtin(name: &str) -> Option<Self> {
+        BUILTIN
+            .iter()
+            .map(|line| line.parse::<Self>().expect("Built-in quirks are valid"))
+            .find(|quirk| quirk.name == name)
+    }
+
+    /// The names of all built-in quirks.
+    pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+        BUILTIN
+            .iter()
+            .filter_map(|line| line.split_whitespace().next())
+    }
+
+    fn matches(&self, peer: IpAddr, options: &[RequestOption]) -> bool {
+        let fingerprint_matches = self.fingerprint.as_ref().is_none_or(|fingerprint| {
+            fingerprint.len() == options.len()
+                && fingerprint
+                    .iter()
+                    .zip(options)
+                    .all(|(name, option)| option.name.eq_ignore_ascii_case(name))
+        });
+
+        fingerprint_matches && self.network.is_none_or(|network| network.contains(peer))
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+fn parse_limit(key: &str, value: &str, min: u16, max: u16) -> Result<u16, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|limit| (min..=max).contains(limit))
+        .ok_or_else(|| format!("Invalid {key} {value}, expected {min} to {max}"))
+}
+
+impl FromStr for Quirk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words
+            .next()
+            .filter(|name| !name.contains('='))
+            .ok_or_else(|| format!("Quirk {s:?} needs a name"))?;
+        let mut quirk = Self {
+            name: name.to_owned(),
+            fingerprint: None,
+            network: None,
+            fixes: Fixes::default(),
+        };
+
+        for word in words {
+            match word.split_once('=') {
+                Some(("options", value)) => quirk.fingerprint = Some(parse_list(value)),
+                Some(("ip", value)) => quirk.network = Some(value.parse()?),
+                Some(("ignore", value)) => quirk.fixes.ignore.extend(parse_list(value)),
+                Some(("max-blksize", value)) => {
+                    quirk.fixes.max_block_size = Some(parse_limit("max-blksize", value, 8, 65464)?)
+                }
+                Some(("max-windowsize", value)) => {
+                    quirk.fixes.max_window_size =
+                        Some(parse_limit("max-windowsize", value, 1, u16::MAX)?)
+                }
+                None if word == "no-oack" => quirk.fixes.no_oack = true,
+                _ => return Err(format!("Unknown matcher or fix {word} in quirk {name}")),
+            }
+        }
+
+        if quirk.fixes == Fixes::default() {
+            return Err(format!("Quirk {name} doesn't fix anything"));
+        }
+
+        Ok(quirk)
+    }
+}
+
+impl fmt::Display for Quirk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// The quirks we work around.
+#[derive(Debug, Default)]
+pub struct Quirks {
+    quirks: Vec<Quirk>,
+}
+
+impl Quirks {
+    pub fn new(quirks: impl IntoIterator<Item = Quirk>) -> Self {
+        Self {
+            quirks: quirks.into_iter().collect(),
+        }
+    }
+
+    /// Combine the fixes of all quirks that match a request from
+    /// `peer` with `options`. Returns `None`, if no quirk matches.
+    pub fn fixes(&self, peer: IpAddr, options: &[RequestOption]) -> Option<Fixes> {
+        let mut fixes: Option<Fixes> = None;
+
+        for quirk in self.quirks.iter().filter(|q| q.matches(peer, options)) {
+            info!("{peer}: Applying quirk {quirk}");
+            fixes.get_or_insert_with(Fixes::default).merge(&quirk.fixes);
+        }
+
+        fixes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(names: &[&str]) -> Vec<RequestOption<'static>> {
+        names
+            .iter()
+            .map(|name| RequestOption {
+                name: name.to_string().into(),
+                value: "0".into(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_quirks() {
+        let quirk: Quirk = "old options=TSIZE,blksize ip=10.0.0.0/8 ignore=tsize max-blksize=1024"
+            .parse()
+            .unwrap();
+
+        assert_eq!(quirk.name, "old");
+        assert_eq!(
+            quirk.fingerprint,
+            Some(vec!["tsize".to_owned(), "blksize".to_owned()])
+        );
+        assert_eq!(quirk.fixes.ignore, BTreeSet::from(["tsize".to_owned()]));
+        assert_eq!(quirk.fixes.max_block_size, Some(1024));
+
+        assert!("".parse::<Quirk>().is_err());
+        assert!("ip=10.0.0.1 no-oack".parse::<Quirk>().is_err());
+        assert!("nothing options=tsize".parse::<Quirk>().is_err());
+        assert!("bad max-blksize=7".parse::<Quirk>().is_err());
+        assert!("bad ip=10.0.0.0/33 no-oack".parse::<Quirk>().is_err());
+        assert!("bad typo=1 no-oack".parse::<Quirk>().is_err());
+
+        for name in Quirk::builtin_names() {
+            assert_eq!(Quirk::builtin(name).unwrap().name, name);
+        }
+        assert_eq!(Quirk::builtin("unknown"), None);
+    }
+
+    #[test]
+    fn matches_clients() {
+        let quirks = Quirks::new([
+            "small ip=10.0.0.0/24 max-blksize=1024 max-windowsize=4"
+                .parse()
+                .unwrap(),
+            "smaller ip=10.0.0.5 max-blksize=512".parse().unwrap(),
+            Quirk::builtin("intel-pxe-2.1").unwrap(),
+        ]);
+        let fixes =
+            |peer: &str, names: &[&str]| quirks.fixes(peer.parse().unwrap(), &options(names));
+
+        assert_eq!(fixes("10.0.1.5", &["blksize"]), None);
+
+        let small = fixes("::ffff:10.0.0.6", &["blksize"]).unwrap();
+        assert_eq!(small.max_block_size, Some(1024));
+        assert_eq!(small.max_window_size, Some(4));
+
+        let combined = fixes("10.0.0.5", &["TSize"]).unwrap();
+        assert_eq!(combined.max_block_size, Some(512));
+        assert!(combined.no_oack);
+
+        // The fingerprint must match exactly.
+        assert_eq!(fixes("192.168.0.1", &["tsize", "blksize"]), None);
+        assert!(fixes("192.168.0.1", &["tsize"]).unwrap().no_oack);
+
+        let network: Network = "fe80::/10".parse().unwrap();
+        assert!(network.contains("fe80::1".parse().unwrap()));
+        assert!(!network.contains("10.0.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Network>()
+            .unwrap()
+            .contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn applies_fixes() {
+        let fixes = Fixes {
+            ignore: BTreeSet::from(["tsize".to_owned()]),
+            max_block_size: Some(1468),
+            ..Fixes::default()
+        };
+        let mut server_options = Options::default();
+
+        let left = fixes.apply(&mut server_options, &options(&["blksize", "TSIZE"]));
+        assert_eq!(left, options(&["blksize"]));
+        assert_eq!(server_options.max_block_size, 1468);
+
+        let no_oack = Fixes {
+            no_oack: true,
+            ..Fixes::default()
+        };
+        assert!(no_oack.apply(&mut server_options, &left).is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! This module estimates how long to wait for a response.
+//!
+//! The retransmission timeout follows [RFC
+//! 6298](https://datatracker.ietf.org/doc/html/rfc6298): we keep a
+//! smoothed round-trip time and its variance, and double the timeout
+//! each time it expires. Round trips of retransmitted packets are not
+//! measured, because we can't tell which copy was answered (Karn's
+//! algorithm).
+
+use std::time::Duration;
+
+/// The clock granularity in RFC 6298. This keeps the timeout above
+/// the round-trip time when the variance is zero.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    /// The smoothed round-trip time, once we have a measurement.
+    srtt: Option<Duration>,
+
+    /// The round-trip time variation.
+    rttvar: Duration,
+
+    /// The current retransmission timeout.
+    rto: Duration,
+
+    min_rto: Duration,
+    max_rto: Duration,
+}
+
+impl RttEstimator {
+    /// Start with `initial` as timeout until the first round trip is
+    /// measured. Timeouts always stay between `min` and `max`.
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: initial.clamp(min, max),
+            min_rto: min,
+            max_rto: max,
+        }
+    }
+
+    /// How long to wait for a response.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Take a measured round-trip time into account.
+    pub fn sample(&mut self, rtt: Duration) {
+        let (srtt, rttvar) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some(srtt) => (
+                (srtt * 7 + rtt) / 8,
+                (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4,
+            ),
+        };
+
+        self.srtt = Some(srtt);
+        self.rttvar = rttvar;
+        self.rto = (srtt + (rttvar * 4).max(CLOCK_GRANULARITY)).clamp(self.min_rto, self.max_rto);
+    }
+
+    /// The timeout expired, so wait longer next time.
+    pub fn back_off(&mut self) {
+        self.rto = (self.rto * 2).min(self.max_rto);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn follows_round_trips() {
+        let mut rtt = RttEstimator::new(ms(1000), ms(10), ms(4000));
+        assert_eq!(rtt.rto(), ms(1000));
+
+        // SRTT = 100, RTTVAR = 50
+        rtt.sample(ms(100));
+        assert_eq!(rtt.rto(), ms(300));
+
+        // SRTT = 100, RTTVAR = 37.5
+        rtt.sample(ms(100));
+        assert_eq!(rtt.rto(), ms(250));
+
+        // SRTT = 112.5, RTTVAR = 53.125
+        rtt.sample(ms(200));
+        assert_eq!(rtt.rto(), Duration::from_micros(325_000));
+    }
+
+    #[test]
+    fn stays_within_limits() {
+        let mut rtt = RttEstimator::new(ms(1000), ms(50), ms(3000));
+
+        rtt.sample(Duration::from_micros(100));
+        assert_eq!(rtt.rto(), ms(50));
+
+        rtt.back_off();
+        assert_eq!(rtt.rto(), ms(100));
+        for _ in 0..10 {
+            rtt.back_off();
+        }
+        assert_eq!(rtt.rto(), ms(3000));
+
+        assert_eq!(RttEstimator::new(ms(1), ms(50), ms(3000)).rto(), ms(50));
+    }
+}
@@ -0,0 +1,973 @@
+//! This module ties the protocol implementation to sockets.
+//!
+//! [`Server`] is the entry point for embedding the TFTP server into
+//! other programs.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    num::NonZeroUsize,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, trace, warn};
+use tokio::{io::Interest, sync::oneshot, task::JoinSet};
+
+use crate::{
+    arp::{ipv4, Peer},
+    batch_io::RecvBatch,
+    proxy_dhcp::ProxyDhcp,
+    simple_fs::Filesystem,
+    simple_proto::{ConnectionStatus, Event, SimpleUdpProtocol},
+    tftp,
+    tftp_proto::{check_request_options, Connection, Options},
+    transport::{TransferSocket, Transport},
+};
+
+/// How many packets a server socket receives at once.
+const RECV_BATCH_SIZE: usize = 16;
+
+/// How many errors a server socket sends per second at most. Senders
+/// of bogus packets may be spoofed, so we don't answer all of them.
+const MAX_ERRORS_PER_SECOND: u32 = 100;
+
+/// Callbacks that let the embedding program observe the server.
+///
+/// All methods do nothing by default.
+pub trait Hooks: Send + Sync {
+    /// A client asked for `path`. This is called before the file is
+    /// opened.
+    fn request_received(&self, _peer: SocketAddr, _path: &Path) {}
+
+    /// The transfer with `peer` is over. `error` is set, if the
+    /// transfer failed due to a local problem.
+    fn transfer_finished(&self, _peer: SocketAddr, _error: Option<&anyhow::Error>) {}
+}
+
+impl Hooks for () {}
+
+/// A TFTP server that serves files from a [`Filesystem`].
+///
+/// ```no_run
+/// use obiwan::{simple_fs::AsyncFilesystem, Server};
+///
+/// Server::new(AsyncFilesystem::default())
+///     .root("/srv/tftp")
+///     .listen("0.0.0.0:69")?
+///     .run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Server<FS: Filesystem> {
+    filesystem: FS,
+    root: PathBuf,
+    sockets: Vec<UdpSocket>,
+    options: Options,
+    threads: Option<NonZeroUsize>,
+    transport: Option<Transport>,
+    hooks: Arc<dyn Hooks>,
+    proxy_dhcp: Vec<(UdpSocket, Arc<ProxyDhcp>)>,
+}
+
+impl<FS: Filesystem + 'static> Server<FS> {
+    /// Create a server that serves `filesystem` from its root
+    /// directory `/`.
+    pub fn new(filesystem: FS) -> Self {
+        Self {
+            filesystem,
+            root: PathBuf::from("/"),
+            sockets: vec![],
+            options: Options::default(),
+            threads: None,
+            transport: None,
+            hooks: Arc::new(()),
+            proxy_dhcp: vec![],
+        }
+    }
+
+    /// Serve files below this directory of the filesystem.
+    pub fn root(mut self, root: impl AsRef<Path>) -> Self {
+        self.root = root.as_ref().to_owned();
+        self
+    }
+
+    /// Bind a socket to `addr` and serve requests on it.
+    pub fn listen(self, addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        Ok(self.socket(UdpSocket::bind(addr)?))
+    }
+
+    /// Serve requests on an already bound socket. This is useful to
+    /// bind privileged ports before dropping privileges.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.sockets.push(socket);
+        self
+    }
+
+    /// Set the protocol options.
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Handle transfers on this many threads in [`Server::run`]. By
+    /// default, everything runs on a single thread.
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Use this transport for transfers instead of creating a new one.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Install callbacks that are invoked for every transfer.
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Answer PXE clients on an already bound socket with `responder`.
+    /// This is usually called twice, for ports 67 and 4011.
+    pub fn proxy_dhcp(mut self, socket: UdpSocket, responder: Arc<ProxyDhcp>) -> Self {
+        self.proxy_dhcp.push((socket, responder));
+        self
+    }
+
+    /// Run the server on its own runtime until it fails.
+    ///
+    /// This creates the runtime and with it all worker threads. If the
+    /// caller intends to drop privileges, it must do so before.
+    pub fn run(self) -> Result<()> {
+        let tokio_runtime = match self.threads {
+            None => tokio::runtime::Builder::new_current_thread(),
+            Some(threads) => {
+                info!("Using {threads} worker threads.");
+
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                builder.worker_threads(threads.get());
+                builder
+            }
+        }
+        .enable_all()
+        .build()
+        .context("Failed to start I/O engine")?;
+
+        tokio_runtime.block_on(self.serve())
+    }
+
+    /// Serve requests on the current Tokio runtime until an error
+    /// occurs. The thread setting is ignored.
+    pub async fn serve(self) -> Result<()> {
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Transport::new().context("Failed to set up I/O")?,
+        };
+        let shared = Arc::new(Shared {
+            transport,
+            filesystem: self.filesystem,
+            root: self.root,
+            options: self.options,
+            hooks: self.hooks,
+            pending: Default::default(),
+        });
+
+        let mut listeners = JoinSet::new();
+
+        if let Some(inventory) = &shared.options.inventory {
+            let inventory = inventory.clone();
+
+            listeners.spawn(async move {
+                inventory.watch().await;
+                Ok(())
+            });
+        }
+
+        if let Some(arch_table) = &shared.options.arch_table {
+            let arch_table = arch_table.clone();
+
+            listeners.spawn(async move {
+                arch_table.watch().await;
+                Ok(())
+            });
+        }
+
+        for socket in self.sockets {
+            // Because we create the socket without Tokio, we need to
+            // make sure it is non-blocking. Otherwise, Tokio will hang
+            // when reading from it and not schedule other tasks.
+            socket.set_nonblocking(true)?;
+            debug!("Serving on socket: {:?}", socket);
+
+            listeners.spawn(listen(
+                shared.clone(),
+                tokio::net::UdpSocket::from_std(socket)?,
+            ));
+        }
+
+        for (socket, responder) in self.proxy_dhcp {
+            socket.set_nonblocking(true)?;
+            socket.set_broadcast(true)?;
+            debug!("Serving proxyDHCP on socket: {:?}", socket);
+
+            listeners.spawn(responder.serve(tokio::net::UdpSocket::from_std(socket)?));
+        }
+
+        while let Some(result) = listeners.join_next().await {
+            result??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Everything that connections share.
+struct Shared<FS: Filesystem> {
+    transport: Transport,
+    filesystem: FS,
+    root: PathBuf,
+    options: Options,
+    hooks: Arc<dyn Hooks>,
+    pending: Mutex<PendingTransfers>,
+}
+
+/// A transfer that the listener has started and that is still
+/// running.
+#[derive(Debug)]
+struct Pending {
+    /// The datagram that started the transfer.
+    request: Vec<u8>,
+
+    /// Tells this transfer apart from later ones with the same peer.
+    id: u64,
+
+    /// Dropping this ends the transfer.
+    _cancel: oneshot::Sender<()>,
+}
+
+/// The running transfers by peer.
+///
+/// Clients repeat their request when the first answer is lost, and
+/// clients that don't understand our OACK ask again without options.
+/// Neither should cost us a second transfer to the same peer.
+#[derive(Debug, Default)]
+struct PendingTransfers {
+    by_peer: HashMap<SocketAddr, Pending>,
+    next_id: u64,
+}
+
+impl PendingTransfers {
+    /// Register a transfer for `request` from `peer`. This ends any
+    /// other transfer with `peer`. Returns `None`, if the transfer is
+    /// already running, because this is a repeated request.
+    fn start(&mut self, peer: SocketAddr, request: &[u8]) -> Option<(u64, oneshot::Receiver<()>)> {
+        if self
+            .by_peer
+            .get(&peer)
+            .is_some_and(|pending| pending.request == request)
+        {
+            return None;
+        }
+
+        let (cancel, cancelled) = oneshot::channel();
+        let id = self.next_id;
+
+        self.next_id += 1;
+        self.by_peer.insert(
+            peer,
+            Pending {
+                request: request.to_vec(),
+                id,
+                _cancel: cancel,
+            },
+        );
+
+        Some((id, cancelled))
+    }
+
+    /// Forget the transfer `id` with `peer`, unless it was already
+    /// replaced.
+    fn finish(&mut self, peer: SocketAddr, id: u64) {
+        if self
+            .by_peer
+            .get(&peer)
+            .is_some_and(|pending| pending.id == id)
+        {
+            self.by_peer.remove(&peer);
+        }
+    }
+}
+
+/// Sets the port of a socket address to zero. This is useful to let
+/// the OS choose the port number for us.
+fn clear_port(mut addr: SocketAddr) -> SocketAddr {
+    addr.set_port(0);
+    addr
+}
+
+/// Encode `packets` into `bufs` and send them to the peer or to
+/// `destination`. The buffers are kept across calls, so sending
+/// doesn't allocate once they have grown large enough.
+async fn send_packets(
+    socket: &TransferSocket,
+    destination: Option<SocketAddr>,
+    packets: &[tftp::Packet<'_>],
+    bufs: &mut Vec<Vec<u8>>,
+) -> Result<()> {
+    if bufs.len() < packets.len() {
+        bufs.resize_with(packets.len(), Vec::new);
+    }
+
+    for (packet, buf) in packets.iter().zip(bufs.iter_mut()) {
+        trace!("{packet:?}");
+        packet.encode_into(buf);
+    }
+
+    if let Some(destination) = destination {
+        for buf in &mut bufs[..packets.len()] {
+            *buf = socket.send_to(std::mem::take(buf), destination).await?;
+        }
+
+        return Ok(());
+    }
+
+    match &mut bufs[..packets.len()] {
+        [] => (),
+        [buf] => *buf = socket.send(std::mem::take(buf)).await?,
+        bufs => socket.send_batch(bufs).await?,
+    }
+
+    Ok(())
+}
+
+async fn recv_packet(
+    socket: &TransferSocket,
+    buf: &mut Vec<u8>,
+    recv_timeout: Duration,
+) -> Result<Option<tftp::Packet<'static>>> {
+    let (received, returned) = socket.recv(std::mem::take(buf), recv_timeout).await?;
+
+    *buf = returned;
+    received
+        .map(|len| {
+            tftp::Packet::try_from(&buf[0..len])
+                .map(tftp::Packet::into_owned)
+                .context("Failed to parse incoming packet")
+        })
+        .transpose()
+}
+
+/// The largest block size whose data packets fit into `mtu` bytes
+/// on the way to `remote_addr`.
+fn block_size_for_mtu(mtu: u32, remote_addr: SocketAddr) -> u16 {
+    const UDP_HEADER: u32 = 8;
+    const TFTP_DATA_HEADER: u32 = 4;
+
+    let ip_header = match ipv4(remote_addr.ip()) {
+        Some(_) => 20,
+        None => 40,
+    };
+
+    u16::try_from(mtu.saturating_sub(ip_header + UDP_HEADER + TFTP_DATA_HEADER)).unwrap_or(u16::MAX)
+}
+
+/// Describe a client for log messages. With an inventory, this
+/// includes the host name.
+async fn describe_client<FS: Filesystem>(shared: &Shared<FS>, peer: &Peer) -> String {
+    match &shared.options.inventory {
+        Some(inventory) => inventory.describe(peer).await,
+        None => peer.to_string(),
+    }
+}
+
+async fn handle_connection<FS: Filesystem>(
+    shared: &Shared<FS>,
+    local_addr: SocketAddr,
+    peer: &Peer,
+    client: &str,
+    initial_request: tftp::Packet<'static>,
+) -> Result<()> {
+    let remote_addr = peer.addr();
+
+    debug!("{client}: Establishing new connection.");
+    trace!("{client}: {initial_request:?}");
+
+    let socket = shared
+        .transport
+        .connect(clear_port(local_addr), remote_addr)
+        .await?;
+    debug!("{client}: Local address: {}", socket.local_addr()?);
+
+    let mut options = shared.options.clone();
+
+    if options.limit_block_size_to_mtu {
+        match socket.path_mtu() {
+            Ok(mtu) => {
+                let block_size = block_size_for_mtu(mtu, remote_addr);
+
+                debug!("{client}: Path MTU is {mtu}, limiting block size to {block_size}.");
+                options.max_block_size = options.max_block_size.min(block_size);
+            }
+            Err(e) => warn!("{client}: Failed to query path MTU: {e}"),
+        }
+    }
+
+    let mut con = Connection::new(
+        shared.filesystem.clone(),
+        &shared.root,
+        peer.clone(),
+        options,
+    );
+    let mut packet = Some(initial_request);
+
+    // These buffers are reused for the whole transfer.
+    let mut recv_buf = vec![0u8; 1 << 16];
+    let mut send_bufs = vec![];
+
+    loop {
+        let response = con
+            .handle_event(match packet {
+                Some(p) => Event::PacketReceived(p),
+                None => Event::Timeout,
+            })
+            .await?;
+
+        send_packets(
+            &socket,
+            con.destination(),
+            &response.packets,
+            &mut send_bufs,
+        )
+        .await?;
+
+        let next_status = response.next_status;
+        con.recycle(response);
+
+        match next_status {
+            ConnectionStatus::Terminated => break,
+            ConnectionStatus::WaitingForPacket(timeout) => {
+                packet = recv_packet(&socket, &mut recv_buf, timeout).await?;
+            }
+        }
+    }
+
+    debug!("{client}: Connection terminated.");
+    Ok(())
+}
+
+/// Accept requests on `socket` and spawn a task for each transfer.
+async fn listen<FS: Filesystem + 'static>(
+    shared: Arc<Shared<FS>>,
+    socket: tokio::net::UdpSocket,
+) -> Result<()> {
+    let local_addr = socket.local_addr()?;
+    let mut batch = RecvBatch::new(RECV_BATCH_SIZE, 1 << 16);
+    let mut errors = RateLimit::new(MAX_ERRORS_PER_SECOND, Instant::now());
+
+    loop {
+        socket
+            .async_io(Interest::READABLE, || batch.recv(socket.as_raw_fd()))
+            .await
+            .context("Failed to read from UDP socket")?;
+
+        for (data, remote_addr) in batch.packets() {
+            let packet = match tftp::Packet::try_from(data) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    match check_parse_error(e) {
+                        Verdict::Reject(error_code, error_msg) => {
+                            debug!("{remote_addr}: Rejecting request: {e}");
+                            reject(&socket, &mut errors, remote_addr, error_code, error_msg).await;
+                        }
+                        _ => warn!("Ignoring packet: {e}"),
+                    }
+                    continue;
+                }
+            };
+
+            match check_packet(&packet, data.len(), &shared.options) {
+                Verdict::Accept => {
+                    let Some((id, cancelled)) =
+                        shared.pending.lock().unwrap().start(remote_addr, data)
+                    else {
+                        debug!("{remote_addr}: Ignoring repeated request {packet:?}");
+                        continue;
+                    };
+
+                    if let tftp::Packet::Rrq { filename, .. } = &packet {
+                        shared.hooks.request_received(remote_addr, filename);
+                    }
+
+                    let packet = packet.into_owned();
+                    let shared = shared.clone();
+
+                    tokio::spawn(async move {
+                        let peer = Peer::new(remote_addr, shared.options.arp_table.clone());
+                        let client = describe_client(&shared, &peer).await;
+                        let transfer =
+                            handle_connection(&shared, local_addr, &peer, &client, packet);
+                        let result = tokio::select! {
+                            result = transfer => result,
+                            _ = cancelled => {
+                                info!("{client}: Client sent a new request, ending this transfer.");
+                                Ok(())
+                            }
+                        };
+
+                        shared.pending.lock().unwrap().finish(remote_addr, id);
+
+                        if let Err(e) = &result {
+                            error!("Connection to {client} died due to an error: {e}");
+                        }
+
+                        shared
+                            .hooks
+                            .transfer_finished(remote_addr, result.as_ref().err());
+                    });
+                }
+                Verdict::Ignore => debug!("{remote_addr}: Ignoring {packet:?}"),
+                Verdict::Reject(error_code, error_msg) => {
+                    debug!("{remote_addr}: Rejecting {packet:?}: {error_msg}");
+                    reject(&socket, &mut errors, remote_addr, error_code, error_msg).await;
+                }
+            }
+        }
+    }
+}
+
+/// What the listener does with a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    /// Start a transfer.
+    Accept,
+
+    /// Drop the packet without an answer.
+    Ignore,
+
+    /// Answer with this error code and message.
+    Reject(u16, &'static str),
+}
+
+/// Decide whether `packet`, which arrived in a datagram of `len`
+/// bytes, starts a transfer. Only valid read requests do. Everything
+/// else is handled on the listening socket, so bogus packets don't
+/// cost us a task and a socket.
+fn check_packet(packet: &tftp::Packet, len: usize, options: &Options) -> Verdict {
+    match packet {
+        tftp::Packet::Rrq {
+            filename,
+            options: request_options,
+            ..
+        } => {
+            if filename.as_os_str().len() > options.max_filename_length {
+                Verdict::Reject(tftp::error::ILLEGAL_OPERATION, "Filename is too long")
+            } else if let Err(error_msg) = check_options(request_options, len, options) {
+                Verdict::Reject(tftp::error::INVALID_OPTION, error_msg)
+            } else {
+                Verdict::Accept
+            }
+        }
+        tftp::Packet::Wrq { .. } => Verdict::Reject(
+            tftp::error::ACCESS_VIOLATION,
+            "This server only supports reading files",
+        ),
+        tftp::Packet::Data { .. } | tftp::Packet::Ack { .. } => {
+            Verdict::Reject(tftp::error::UNKNOWN_TRANSFER_ID, "Unknown transfer ID")
+        }
+        tftp::Packet::OAck { .. } => Verdict::Reject(
+            tftp::error::ILLEGAL_OPERATION,
+            "Expected a read or write request",
+        ),
+        // Errors are never answered.
+        tftp::Packet::Error { .. } => Verdict::Ignore,
+    }
+}
+
+/// Decide what to do with a packet that we failed to parse. Requests
+/// whose filename and mode make sense get an error.
+fn check_parse_error(error: tftp::ParseError) -> Verdict {
+    match error {
+        tftp::ParseError::InvalidMode => {
+            Verdict::Reject(tftp::error::ILLEGAL_OPERATION, "Unsupported transfer mode")
+        }
+        tftp::ParseError::InvalidOptions => {
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Malformed options")
+        }
+        tftp::ParseError::UnrecognizedPacket | tftp::ParseError::InvalidString => Verdict::Ignore,
+    }
+}
+
+/// Check the options of a request of `len` bytes as RFC 2347 asks.
+/// Returns the reason to refuse the request.
+fn check_options(
+    request_options: &[tftp::RequestOption],
+    len: usize,
+    options: &Options,
+) -> std::result::Result<(), &'static str> {
+    if len > usize::from(options.max_request_size) {
+        return Err("Request is too large");
+    }
+
+    if request_options.len() > options.max_request_options {
+        return Err("Too many options");
+    }
+
+    check_request_options(request_options)
+}
+
+/// Limits how often something happens per second.
+#[derive(Debug)]
+struct RateLimit {
+    limit: u32,
+    second_start: Instant,
+    count: u32,
+}
+
+impl RateLimit {
+    fn new(limit: u32, now: Instant) -> Self {
+        Self {
+            limit,
+            second_start: now,
+            count: 0,
+        }
+    }
+
+    /// Whether it may happen once more at `now`.
+    fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.second_start) >= Duration::from_secs(1) {
+            self.second_start = now;
+            self.count = 0;
+        }
+
+        if self.count >= self.limit {
+            return false;
+        }
+
+        self.count += 1;
+        true
+    }
+}
+
+/// Answer a packet with an error from the listening socket.
+async fn reject(
+    socket: &tokio::net::UdpSocket,
+    errors: &mut RateLimit,
+    remote_addr: SocketAddr,
+    error_code: u16,
+    error_msg: &str,
+) {
+    if !errors.allow(Instant::now()) {
+        debug!("{remote_addr}: Not sending error, too many errors recently.");
+        return;
+    }
+
+    let error = tftp::Packet::Error {
+        error_code,
+        error_msg: error_msg.into(),
+    };
+
+    if let Err(e) = socket.send_to(&error.to_vec(), remote_addr).await {
+        warn!("{remote_addr}: Failed to send error: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::simple_fs::MapFilesystem;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingHooks {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Hooks for Arc<RecordingHooks> {
+        fn request_received(&self, _peer: SocketAddr, path: &Path) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("request {}", path.display()));
+        }
+
+        fn transfer_finished(&self, _peer: SocketAddr, error: Option<&anyhow::Error>) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("finished {}", error.is_none()));
+        }
+    }
+
+    #[test]
+    fn replaces_pending_transfers() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 1234));
+        let other_peer = SocketAddr::from(([10, 0, 0, 2], 1234));
+        let with_options = b"\x00\x01kernel\0octet\0blksize\x001468\0";
+        let without_options = b"\x00\x01kernel\0octet\0";
+        let mut pending = PendingTransfers::default();
+
+        let (first, mut first_cancelled) = pending.start(peer, with_options).unwrap();
+        assert!(pending.start(other_peer, with_options).is_some());
+
+        // A repeated request doesn't start another transfer.
+        assert!(pending.start(peer, with_options).is_none());
+        assert_eq!(
+            first_cancelled.try_recv(),
+            Err(oneshot::error::TryRecvError::Empty)
+        );
+
+        // A different request replaces the running transfer.
+        let (second, _second_cancelled) = pending.start(peer, without_options).unwrap();
+        assert_eq!(
+            first_cancelled.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        );
+
+        // The replaced transfer doesn't forget its successor.
+        pending.finish(peer, first);
+        assert!(pending.start(peer, without_options).is_none());
+
+        pending.finish(peer, second);
+        assert!(pending.start(peer, without_options).is_some());
+    }
+
+    #[test]
+    fn limits_rate() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(2, start);
+
+        assert!(limit.allow(start));
+        assert!(limit.allow(start + Duration::from_millis(500)));
+        assert!(!limit.allow(start + Duration::from_millis(999)));
+
+        assert!(limit.allow(start + Duration::from_secs(1)));
+        assert!(limit.allow(start + Duration::from_millis(1500)));
+        assert!(!limit.allow(start + Duration::from_millis(1999)));
+    }
+
+    #[test]
+    fn checks_requests() {
+        let options = Options::default();
+        let check = |data: &[u8]| match tftp::Packet::try_from(data) {
+            Ok(packet) => check_packet(&packet, data.len(), &options),
+            Err(e) => check_parse_error(e),
+        };
+
+        assert_eq!(
+            check(b"\x00\x01kernel\0octet\0blksize\x001468\0"),
+            Verdict::Accept
+        );
+        assert_eq!(
+            check(b"\x00\x02kernel\0octet\0"),
+            Verdict::Reject(
+                tftp::error::ACCESS_VIOLATION,
+                "This server only supports reading files"
+            )
+        );
+        assert_eq!(
+            check(b"\x00\x04\x00\x01"),
+            Verdict::Reject(tftp::error::UNKNOWN_TRANSFER_ID, "Unknown transfer ID")
+        );
+        assert_eq!(check(b"\x00\x05\x00\x00Oops\0"), Verdict::Ignore);
+
+        let mut long_name = b"\x00\x01".to_vec();
+        long_name.extend([b'a'; 256]);
+        long_name.extend(b"\0octet\0");
+        assert_eq!(
+            check(&long_name),
+            Verdict::Reject(tftp::error::ILLEGAL_OPERATION, "Filename is too long")
+        );
+
+        let mut many_options = b"\x00\x01kernel\0octet\0".to_vec();
+        for i in 0..=options.max_request_options {
+            many_options.extend(format!("x-{i}\x001\x00").as_bytes());
+        }
+        assert_eq!(
+            check(&many_options),
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Too many options")
+        );
+
+        assert_eq!(
+            check(b"\x00\x01kernel\0octet\0blksize\x001468\0BLKSIZE\x00512\0"),
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Duplicate option")
+        );
+        assert_eq!(check(b"\x00\x01kernel\0octet\0\0\0"), Verdict::Accept);
+        assert_eq!(
+            check(b"\x00\x01kernel\0octet\0\0\0\0"),
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Malformed options")
+        );
+        assert_eq!(
+            check(b"\x00\x01kernel\0mail\0"),
+            Verdict::Reject(tftp::error::ILLEGAL_OPERATION, "Unsupported transfer mode")
+        );
+        assert_eq!(check(b"\x00\x01kernel"), Verdict::Ignore);
+        assert_eq!(
+            check(b"\x00\x01kernel\0octet\0\0\0blksize\x001468\0"),
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Empty option name")
+        );
+
+        let mut large = b"\x00\x01kernel\0octet\0".to_vec();
+        large.extend(b"x-padding\0");
+        large.extend([b'a'; 500]);
+        large.push(0);
+        assert_eq!(
+            check(&large),
+            Verdict::Reject(tftp::error::INVALID_OPTION, "Request is too large")
+        );
+
+        // All limits can be raised.
+        let options = Options {
+            max_request_size: 1024,
+            max_filename_length: 1024,
+            max_request_options: 32,
+            ..Options::default()
+        };
+        for request in [&large, &long_name, &many_options] {
+            assert_eq!(
+                check_packet(
+                    &tftp::Packet::try_from(&request[..]).unwrap(),
+                    request.len(),
+                    &options
+                ),
+                Verdict::Accept
+            );
+        }
+    }
+
+    #[test]
+    fn fits_blocks_into_mtu() {
+        assert_eq!(
+            block_size_for_mtu(1500, "10.0.0.1:1234".parse().unwrap()),
+            1468
+        );
+        assert_eq!(
+            block_size_for_mtu(1500, "[::ffff:10.0.0.1]:1234".parse().unwrap()),
+            1468
+        );
+        assert_eq!(
+            block_size_for_mtu(1500, "[fe80::1]:1234".parse().unwrap()),
+            1448
+        );
+        assert_eq!(
+            block_size_for_mtu(65536, "127.0.0.1:1234".parse().unwrap()),
+            65504
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_files() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let hooks = Arc::new(RecordingHooks::default());
+
+        let server = Server::new(MapFilesystem::from([(
+            PathBuf::from("/boot/kernel"),
+            b"kernel".to_vec(),
+        )]))
+        .root("/boot")
+        .socket(socket)
+        .hooks(hooks.clone());
+        tokio::spawn(server.serve());
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0; 1024];
+
+        client
+            .send_to(b"\x00\x01kernel\0octet\0", server_addr)
+            .await
+            .unwrap();
+
+        let (len, transfer_addr) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01kernel");
+
+        client
+            .send_to(b"\x00\x04\x00\x01", transfer_addr)
+            .await
+            .unwrap();
+
+        // Give the server a moment to finish the transfer.
+        for _ in 0..100 {
+            if hooks.events.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(
+            *hooks.events.lock().unwrap(),
+            ["request kernel", "finished true"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_strays() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+
+        let server = Server::new(MapFilesystem::from([(
+            PathBuf::from("/kernel"),
+            b"kernel".to_vec(),
+        )]))
+        .socket(socket);
+        tokio::spawn(server.serve());
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stray = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0; 1024];
+
+        // Packets that don't start a transfer are answered by the
+        // listening socket.
+        stray
+            .send_to(b"\x00\x04\x00\x01", server_addr)
+            .await
+            .unwrap();
+        let (len, from) = stray.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(&buf[..len], b"\x00\x05\x00\x05Unknown transfer ID\0");
+
+        stray.send_to(b"\x00\x06", server_addr).await.unwrap();
+        let (len, _) = stray.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x00\x05\x00\x04Expected a read or write request\0"
+        );
+
+        stray
+            .send_to(b"\x00\x01kernel\0mail\0", server_addr)
+            .await
+            .unwrap();
+        let (len, from) = stray.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(&buf[..len], b"\x00\x05\x00\x04Unsupported transfer mode\0");
+
+        client
+            .send_to(b"\x00\x01kernel\0octet\0", server_addr)
+            .await
+            .unwrap();
+        let (len, transfer_addr) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01kernel");
+
+        // Packets from other ports don't disturb the transfer. The
+        // kernel answers them with ICMP, which we don't see here.
+        stray
+            .send_to(b"\x00\x04\x00\x01", transfer_addr)
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stray.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        // The client still gets its data packet again after the timeout.
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, transfer_addr);
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01kernel");
+    }
+}
@@ -2,13 +2,11 @@
 //! degree that the TFTP protocol will need. It's main purpose is to
 //! facilitate unit testing.
 
-use std::{fmt::Debug, io::SeekFrom, path::Path, sync::Arc};
+use std::{fmt::Debug, os::unix::fs::FileExt, path::Path, sync::Arc};
 
 use async_trait::async_trait;
-use tokio::{
-    io::{AsyncReadExt, AsyncSeekExt},
-    sync::Mutex,
-};
+
+use crate::arp::Peer;
 
 #[async_trait]
 pub trait File: Debug + Send + Sync + Sized + Clone {
@@ -19,8 +17,32 @@ pub trait File: Debug + Send + Sync + Sized + Clone {
     /// file has ended.
     async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error>;
 
+    /// Like [`File::read`], but takes `buf` and hands it back
+    /// truncated to the bytes that were read. Files that read on
+    /// another thread implement this to fill the buffer there instead
+    /// of copying.
+    async fn read_owned(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        let len = self.read(offset, &mut buf).await?;
+
+        buf.truncate(len);
+        Ok(buf)
+    }
+
     /// Return the size of the file in bytes.
     async fn size(&self) -> Result<u64, Self::Error>;
+
+    /// Return the metadata of the file on disk from when it was
+    /// opened. Files that are not backed by a single file on disk
+    /// return `None`.
+    fn metadata(&self) -> Option<&std::fs::Metadata> {
+        None
+    }
+
+    /// Whether the contents were made for the client that opened the
+    /// file. Such files must not be shared with other clients.
+    fn is_per_client(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -30,18 +52,78 @@ pub trait Filesystem: Debug + Send + Sync + Clone {
 
     /// Open a file for reading.
     async fn open(&self, path: &Path) -> Result<Self::File, Self::Error>;
+
+    /// Open a file for reading on behalf of `peer`. Filesystems that
+    /// serve different contents to different clients implement this.
+    /// Such filesystems must be the outermost layer, because other
+    /// layers only see [`Filesystem::open`].
+    async fn open_for(&self, path: &Path, _peer: &Peer) -> Result<Self::File, Self::Error> {
+        self.open(path).await
+    }
+}
+
+/// Read from `file` at `offset` until `buf` is full or the file
+/// ends. Returns `buf` truncated to the bytes that were read.
+///
+/// This uses positional reads on the blocking thread pool, so
+/// concurrent reads from the same file don't need to coordinate a
+/// shared file position. The buffer moves to the blocking thread and
+/// back, so the data is not copied.
+pub async fn read_at(
+    file: &Arc<std::fs::File>,
+    offset: u64,
+    mut buf: Vec<u8>,
+) -> Result<Vec<u8>, std::io::Error> {
+    let file = file.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            match file.read_at(&mut buf[pos..], offset + pos as u64) {
+                Ok(0) => break,
+                Ok(bytes_read) => pos += bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        buf.truncate(pos);
+        Ok(buf)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Like [`read_at`], but into a borrowed buffer. This costs a copy.
+pub async fn read_into(
+    file: &Arc<std::fs::File>,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize, std::io::Error> {
+    let data = read_at(file, offset, vec![0; buf.len()]).await?;
+
+    buf[..data.len()].copy_from_slice(&data);
+    Ok(data.len())
 }
 
 #[derive(Debug, Clone)]
 pub struct AsyncFile {
-    file: Arc<Mutex<tokio::fs::File>>,
+    file: Arc<std::fs::File>,
+
+    /// The metadata of the file when it was opened.
+    metadata: std::fs::Metadata,
 }
 
-impl From<tokio::fs::File> for AsyncFile {
-    fn from(file: tokio::fs::File) -> Self {
-        Self {
-            file: Arc::new(Mutex::new(file)),
-        }
+impl AsyncFile {
+    pub async fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = tokio::fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+
+        Ok(Self {
+            file: Arc::new(file.into_std().await),
+            metadata,
+        })
     }
 }
 
@@ -50,28 +132,19 @@ impl File for AsyncFile {
     type Error = std::io::Error;
 
     async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let mut file = self.file.lock().await;
-
-        file.seek(SeekFrom::Start(offset)).await?;
-
-        let mut offset = 0;
-
-        loop {
-            let bytes_read = file.read(&mut buf[offset..]).await?;
-            offset += bytes_read;
-
-            if bytes_read == 0 || offset == buf.len() {
-                break;
-            }
-        }
+        read_into(&self.file, offset, buf).await
+    }
 
-        Ok(offset)
+    async fn read_owned(&self, offset: u64, buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        read_at(&self.file, offset, buf).await
     }
 
     async fn size(&self) -> Result<u64, Self::Error> {
-        let file = self.file.lock().await;
+        Ok(self.metadata.len())
+    }
 
-        Ok(file.metadata().await?.len())
+    fn metadata(&self) -> Option<&std::fs::Metadata> {
+        Some(&self.metadata)
     }
 }
 
@@ -84,32 +157,47 @@ impl Filesystem for AsyncFilesystem {
     type Error = std::io::Error;
 
     async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
-        tokio::fs::File::open(path).await.map(AsyncFile::from)
+        AsyncFile::open(path).await
     }
 }
 
-#[cfg(test)]
+/// Implements [`File::read`] for files that are completely in memory.
+fn read_slice(data: &[u8], offset: u64, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    if offset
+        >= u64::try_from(data.len())
+            .map_err(|_| ())
+            .map_err(|_| std::io::Error::other("Conversion error"))?
+    {
+        return Ok(0);
+    }
+
+    let offset = usize::try_from(offset).map_err(|_| std::io::Error::other("Conversion error"))?;
+    let len = buf.len().min(data.len() - offset);
+
+    buf[..len].copy_from_slice(&data[offset..(offset + len)]);
+    Ok(len)
+}
+
 #[async_trait]
-impl File for Vec<u8> {
+impl File for Arc<[u8]> {
     type Error = std::io::Error;
 
     async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        use std::io::ErrorKind;
-
-        if offset
-            >= u64::try_from(self.len())
-                .map_err(|_| ())
-                .map_err(|_| Self::Error::new(ErrorKind::Other, "Conversion error"))?
-        {
-            return Ok(0);
-        }
+        read_slice(self, offset, buf)
+    }
 
-        let offset = usize::try_from(offset)
-            .map_err(|_| Self::Error::new(ErrorKind::Other, "Conversion error"))?;
-        let len = buf.len().min(self.len() - offset);
+    async fn size(&self) -> Result<u64, Self::Error> {
+        Ok(u64::try_from(self.len()).unwrap())
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl File for Vec<u8> {
+    type Error = std::io::Error;
 
-        buf[..len].copy_from_slice(&self[offset..(offset + len)]);
-        Ok(len)
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        read_slice(self, offset, buf)
     }
 
     async fn size(&self) -> Result<u64, Self::Error> {
@@ -161,4 +249,65 @@ mod tests {
 
         assert_eq!(file.size().await.unwrap(), 4);
     }
+
+    #[tokio::test]
+    async fn can_read_async_file_concurrently() {
+        let contents: Vec<u8> = (0..=255).cycle().take(10000).collect();
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+
+        std::io::Write::write_all(&mut tmp, &contents).unwrap();
+
+        let file = AsyncFilesystem::default()
+            .open(tmp.path())
+            .await
+            .expect("Failed to open file");
+
+        assert_eq!(file.size().await.unwrap(), 10000);
+
+        let reads: Vec<_> = (0..10)
+            .map(|i| {
+                let file = file.clone();
+
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let len = file.read(i * 1024, &mut buf).await.unwrap();
+
+                    (i, buf[..len].to_vec())
+                })
+            })
+            .collect();
+
+        for read in reads {
+            let (i, data) = read.await.unwrap();
+            let start = usize::try_from(i * 1024).unwrap();
+            let end = (start + 1024).min(contents.len());
+
+            assert_eq!(data, contents[start..end]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_into_owned_buffers_in_place() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+
+        std::io::Write::write_all(&mut tmp, b"kernel").unwrap();
+
+        let file = AsyncFilesystem::default()
+            .open(tmp.path())
+            .await
+            .expect("Failed to open file");
+
+        let buf = Vec::with_capacity(1024);
+        let ptr = buf.as_ptr();
+
+        let mut buf = file.read_owned(0, buf).await.unwrap();
+        assert!(buf.is_empty());
+
+        buf.resize(1024, 0);
+        let buf = file.read_owned(2, buf).await.unwrap();
+
+        assert_eq!(buf, b"rnel");
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.capacity(), 1024);
+    }
 }
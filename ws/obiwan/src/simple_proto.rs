@@ -4,7 +4,7 @@
 //! The abstraction aims to make unit testing for simple UDP protocols
 //! easy.
 
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
 
 use async_trait::async_trait;
 
@@ -29,7 +29,9 @@ pub enum Event<T: Debug + Clone + PartialEq + Eq> {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Response<T: Debug + Clone + PartialEq + Eq> {
-    pub packet: Option<T>,
+    // The packets to send, in order. Multiple packets are sent as one
+    // batch, if possible.
+    pub packets: Vec<T>,
     pub next_status: ConnectionStatus,
 }
 
@@ -42,4 +44,14 @@ pub trait SimpleUdpProtocol {
         &mut self,
         event: Event<Self::Packet>,
     ) -> Result<Response<Self::Packet>, Self::Error>;
+
+    /// Hand back a response after its packets were sent. This allows
+    /// the protocol to reuse buffers for the next response.
+    fn recycle(&mut self, _response: Response<Self::Packet>) {}
+
+    /// Where the packets of the last response go, if not to the peer,
+    /// e.g. a multicast group.
+    fn destination(&self) -> Option<SocketAddr> {
+        None
+    }
 }
@@ -0,0 +1,329 @@
+//! This module implements a [`Filesystem`] that renders templates.
+//!
+//! When a client requests `boot.ipxe` and only `boot.ipxe.tmpl`
+//! exists, the template is rendered for this client and served from
+//! memory. Templates refer to variables as `{{name}}`, which doesn't
+//! clash with iPXE's own `${name}` syntax. The variables are, from
+//! lowest to highest precedence:
+//!
+//! - the server settings given via [`TemplateFilesystem::variable`],
+//! - the properties of the client in the [`Inventory`],
+//! - `client_ip`, the IP address of the client.
+//!
+//! Unknown variables fail the request instead of producing a broken
+//! boot script.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use log::{debug, info};
+
+use crate::{
+    arp::{ipv4, Peer},
+    inventory::Inventory,
+    simple_fs::{File, Filesystem},
+};
+
+/// Appended to the requested path to find its template.
+pub const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+/// The largest template in bytes that we render. Templates are read
+/// into memory and rendered for every request, and boot scripts are
+/// much smaller than this.
+pub const MAX_TEMPLATE_SIZE: u64 = 1 << 20;
+
+/// A file that is either rendered from a template or served by the
+/// wrapped filesystem.
+#[derive(Debug, Clone)]
+pub enum TemplateFile<F: File> {
+    Rendered(Arc<[u8]>),
+    Passthrough(F),
+}
+
+#[async_trait]
+impl<F: File<Error = io::Error>> File for TemplateFile<F> {
+    type Error = io::Error;
+
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::Rendered(data) => data.read(offset, buf).await,
+            Self::Passthrough(file) => file.read(offset, buf).await,
+        }
+    }
+
+    async fn read_owned(&self, offset: u64, buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        match self {
+            Self::Rendered(data) => data.read_owned(offset, buf).await,
+            Self::Passthrough(file) => file.read_owned(offset, buf).await,
+        }
+    }
+
+    async fn size(&self) -> Result<u64, Self::Error> {
+        match self {
+            Self::Rendered(data) => data.size().await,
+            Self::Passthrough(file) => file.size().await,
+        }
+    }
+
+    fn is_per_client(&self) -> bool {
+        match self {
+            Self::Rendered(_) => true,
+            Self::Passthrough(file) => file.is_per_client(),
+        }
+    }
+}
+
+/// A filesystem that renders templates for files that don't exist.
+#[derive(Debug, Clone)]
+pub struct TemplateFilesystem<FS: Filesystem> {
+    inner: FS,
+    variables: BTreeMap<String, String>,
+    inventory: Option<Arc<Inventory>>,
+}
+
+impl<FS: Filesystem> TemplateFilesystem<FS> {
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner,
+            variables: BTreeMap::new(),
+            inventory: None,
+        }
+    }
+
+    /// Make `value` available to all templates as `{{name}}`.
+    pub fn variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.variables.insert(name.into(), value.into());
+        self
+    }
+
+    /// Make the properties of the requesting host available to
+    /// templates.
+    pub fn inventory(mut self, inventory: Arc<Inventory>) -> Self {
+        self.inventory = Some(inventory);
+        self
+    }
+
+    /// Collect the variables for a request from `peer`.
+    async fn variables_for(&self, peer: &Peer) -> BTreeMap<String, String> {
+        let mut variables = self.variables.clone();
+
+        if let Some(inventory) = &self.inventory {
+            if let Some(host) = inventory.lookup(peer).await {
+                variables.extend(host.properties.clone());
+            }
+        }
+
+        let ip = ipv4(peer.ip()).map_or(peer.ip(), Into::into);
+        variables.insert("client_ip".to_owned(), ip.to_string());
+
+        variables
+    }
+}
+
+fn template_path(path: &Path) -> PathBuf {
+    let mut template = OsString::from(path);
+
+    template.push(TEMPLATE_SUFFIX);
+    template.into()
+}
+
+/// Read the whole file into memory.
+async fn read_to_end<F: File<Error = io::Error>>(file: &F) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; usize::try_from(file.size().await?).map_err(io::Error::other)?];
+    let len = file.read(0, &mut data).await?;
+
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Replace all `{{name}}` in `template` with the value of the variable.
+fn render(template: &str, variables: &BTreeMap<String, String>) -> io::Result<String> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| invalid("Unterminated {{ in template".to_owned()))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = variables
+            .get(name)
+            .ok_or_else(|| invalid(format!("Unknown template variable: {name}")))?;
+
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[async_trait]
+impl<FS: Filesystem<Error = io::Error>> Filesystem for TemplateFilesystem<FS>
+where
+    FS::File: File<Error = io::Error>,
+{
+    type File = TemplateFile<FS::File>;
+    type Error = io::Error;
+
+    /// Without knowing the client, we can't render templates.
+    async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+        Ok(TemplateFile::Passthrough(self.inner.open(path).await?))
+    }
+
+    async fn open_for(&self, path: &Path, peer: &Peer) -> Result<Self::File, Self::Error> {
+        let open_error = match self.inner.open(path).await {
+            Ok(file) => return Ok(TemplateFile::Passthrough(file)),
+            Err(e) => e,
+        };
+
+        let template_path = template_path(path);
+        let Ok(template) = self.inner.open(&template_path).await else {
+            return Err(open_error);
+        };
+
+        debug!("{peer}: Rendering {}", template_path.display());
+
+        if template.size().await? > MAX_TEMPLATE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: Template is larger than {MAX_TEMPLATE_SIZE} bytes",
+                    template_path.display()
+                ),
+            ));
+        }
+
+        let template = String::from_utf8(read_to_end(&template).await?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let rendered = render(&template, &self.variables_for(peer).await)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", template_path.display())))?;
+
+        info!(
+            "{peer}: Rendered {} ({} bytes).",
+            template_path.display(),
+            rendered.len()
+        );
+
+        Ok(TemplateFile::Rendered(rendered.into_bytes().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_fs::MapFilesystem;
+
+    use super::*;
+
+    /// [`MapFilesystem`] has the wrong file type, so we serve
+    /// everything from memory.
+    #[derive(Debug, Clone)]
+    struct MemoryFilesystem(MapFilesystem);
+
+    #[async_trait]
+    impl Filesystem for MemoryFilesystem {
+        type File = Arc<[u8]>;
+        type Error = io::Error;
+
+        async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+            Ok(self.0.open(path).await?.into())
+        }
+    }
+
+    /// A client at `addr` without a MAC address.
+    fn peer(addr: &str) -> Peer {
+        Peer::from(addr.parse::<std::net::SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn renders_variables() {
+        let variables = BTreeMap::from([
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+        ]);
+
+        assert_eq!(render("{{a}}-{{ b }}}", &variables).unwrap(), "1-2}");
+        assert_eq!(render("${a} {a}", &variables).unwrap(), "${a} {a}");
+        assert!(render("{{c}}", &variables).is_err());
+        assert!(render("{{a", &variables).is_err());
+    }
+
+    #[tokio::test]
+    async fn renders_templates_per_client() {
+        let fs = TemplateFilesystem::new(MemoryFilesystem(MapFilesystem::from([
+            (
+                PathBuf::from("/boot.ipxe.tmpl"),
+                b"#!ipxe\nkernel {{server}}/vmlinuz hostname={{hostname}} ip={{client_ip}}\n"
+                    .to_vec(),
+            ),
+            (PathBuf::from("/plain"), b"{{hostname}}".to_vec()),
+        ])))
+        .variable("server", "http://boot")
+        .variable("hostname", "unknown")
+        .inventory(Arc::new(
+            Inventory::parse("ip,hostname\n10.0.0.10,node1").unwrap(),
+        ));
+
+        let read = |file: TemplateFile<Arc<[u8]>>| async move {
+            String::from_utf8(read_to_end(&file).await.unwrap()).unwrap()
+        };
+
+        let file = fs
+            .open_for(Path::new("/boot.ipxe"), &peer("10.0.0.10:1234"))
+            .await
+            .unwrap();
+        assert!(file.is_per_client());
+        assert_eq!(
+            file.size().await.unwrap(),
+            u64::try_from(read(file.clone()).await.len()).unwrap()
+        );
+        assert_eq!(
+            read(file).await,
+            "#!ipxe\nkernel http://boot/vmlinuz hostname=node1 ip=10.0.0.10\n"
+        );
+
+        let file = fs
+            .open_for(Path::new("/boot.ipxe"), &peer("[::ffff:10.0.0.11]:1234"))
+            .await
+            .unwrap();
+        assert_eq!(
+            read(file).await,
+            "#!ipxe\nkernel http://boot/vmlinuz hostname=unknown ip=10.0.0.11\n"
+        );
+
+        // Existing files are not rendered and templates need a client.
+        let peer = &peer("10.0.0.10:1234");
+        let file = fs.open_for(Path::new("/plain"), peer).await.unwrap();
+        assert!(!file.is_per_client());
+        assert_eq!(read(file).await, "{{hostname}}");
+        assert!(fs.open(Path::new("/boot.ipxe")).await.is_err());
+        assert!(fs.open_for(Path::new("/missing"), peer).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_large_templates() {
+        let size = usize::try_from(MAX_TEMPLATE_SIZE).unwrap();
+        let fs = TemplateFilesystem::new(MemoryFilesystem(MapFilesystem::from([
+            (PathBuf::from("/small.ipxe.tmpl"), vec![b'x'; size]),
+            (PathBuf::from("/large.ipxe.tmpl"), vec![b'x'; size + 1]),
+        ])));
+        let peer = &peer("10.0.0.10:1234");
+
+        assert!(fs.open_for(Path::new("/small.ipxe"), peer).await.is_ok());
+
+        let error = fs
+            .open_for(Path::new("/large.ipxe"), peer)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("larger than"));
+    }
+}
@@ -4,18 +4,16 @@
 //! the basic protocol. [RFC
 //! 1782](https://datatracker.ietf.org/doc/html/rfc1782) covers the
 //! option extension to the protocol.
+//!
+//! Parsed packets borrow from the buffer they were parsed from and
+//! packets are encoded into caller-provided buffers, so handling a
+//! packet doesn't need to allocate.
 
 use std::{
-    error::Error,
-    ffi::{OsStr, OsString},
-    fmt::Display,
-    io::Cursor,
-    os::unix::prelude::{OsStrExt, OsStringExt},
-    path::PathBuf,
+    borrow::Cow, error::Error, ffi::OsStr, fmt::Display, os::unix::prelude::OsStrExt, path::Path,
+    str::FromStr, time::Duration,
 };
 
-use binrw::{binrw, helpers::until_eof, BinReaderExt, BinWriterExt, NullString};
-
 /// TFTP error constants as defined by the RFC.
 #[allow(dead_code)]
 pub mod error {
@@ -30,88 +28,142 @@ pub mod error {
     pub const INVALID_OPTION: u16 = 8;
 }
 
+/// TFTP opcodes as defined by the RFC.
+pub(crate) mod opcode {
+    pub const RRQ: u16 = 1;
+    pub const WRQ: u16 = 2;
+    pub const DATA: u16 = 3;
+    pub const ACK: u16 = 4;
+    pub const ERROR: u16 = 5;
+    pub const OACK: u16 = 6;
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestMode {
     Octet,
     Netascii,
 }
 
+impl RequestMode {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            RequestMode::Octet => b"octet",
+            RequestMode::Netascii => b"netascii",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct RequestOption {
-    pub name: String,
-    pub value: String,
+pub struct RequestOption<'a> {
+    pub name: Cow<'a, str>,
+    pub value: Cow<'a, str>,
+}
+
+impl RequestOption<'_> {
+    pub fn into_owned(self) -> RequestOption<'static> {
+        RequestOption {
+            name: Cow::Owned(self.name.into_owned()),
+            value: Cow::Owned(self.value.into_owned()),
+        }
+    }
+}
+
+/// The largest block number that fits into a packet.
+pub const MAX_BLOCK_NUMBER: u64 = 0xffff;
+
+/// The block size without the `blksize` option.
+pub const DEFAULT_TFTP_BLKSIZE: u16 = 512;
+
+/// The window size without the `windowsize` option.
+pub const DEFAULT_TFTP_WINDOWSIZE: u16 = 1;
+
+/// How long both sides wait for a response before they resend, unless
+/// configured otherwise.
+pub const DEFAULT_TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many times both sides resend packets, if they don't get a
+/// response, unless configured otherwise.
+pub const MAX_RETRANSMISSIONS: u32 = 5;
+
+/// What follows block 65535. RFC 1350 doesn't say and clients
+/// disagree. Clients can choose with the `rollover` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rollover {
+    /// Continue with block 0, like most servers do.
+    #[default]
+    ToZero,
+
+    /// Continue with block 1.
+    ToOne,
+
+    /// Refuse to send files with more than 65535 blocks.
+    Refuse,
+}
+
+impl Rollover {
+    /// The number of the `block`th block of a transfer in packets.
+    /// Returns `None`, if the block can't be sent.
+    pub fn block_number(self, block: u64) -> Option<u16> {
+        let block = match self {
+            Rollover::ToZero => block & MAX_BLOCK_NUMBER,
+            Rollover::ToOne if block > MAX_BLOCK_NUMBER => (block - 1) % MAX_BLOCK_NUMBER + 1,
+            Rollover::ToOne | Rollover::Refuse => block,
+        };
+
+        u16::try_from(block).ok()
+    }
+
+    /// Parse the value of the `rollover` option.
+    pub fn from_option(value: &str) -> Option<Self> {
+        match value {
+            "0" => Some(Rollover::ToZero),
+            "1" => Some(Rollover::ToOne),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Rollover {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Rollover::ToZero),
+            "one" => Ok(Rollover::ToOne),
+            "refuse" => Ok(Rollover::Refuse),
+            _ => Err(format!(
+                "Unknown rollover policy {s}, expected zero, one or refuse"
+            )),
+        }
+    }
 }
 
 /// A TFTP protocol packet
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Packet {
+pub enum Packet<'a> {
     Rrq {
-        filename: PathBuf,
+        filename: Cow<'a, Path>,
         mode: RequestMode,
-        options: Vec<RequestOption>,
+        options: Vec<RequestOption<'a>>,
     },
     Wrq {
-        filename: PathBuf,
+        filename: Cow<'a, Path>,
         mode: RequestMode,
-        options: Vec<RequestOption>,
+        options: Vec<RequestOption<'a>>,
     },
     Data {
         block: u16,
-        data: Vec<u8>,
+        data: Cow<'a, [u8]>,
     },
     Ack {
         block: u16,
     },
     Error {
         error_code: u16,
-        error_msg: String,
-    },
-    OAck {
-        options: Vec<RequestOption>,
-    },
-}
-
-#[binrw]
-#[brw(big)]
-struct ProtoOption {
-    name: NullString,
-    value: NullString,
-}
-
-#[binrw]
-#[brw(big)]
-enum ProtoPacket {
-    #[brw(magic(1u16))]
-    Rrq {
-        filename: NullString,
-        mode: NullString,
-        #[br(parse_with = until_eof)]
-        options: Vec<ProtoOption>,
-    },
-    #[brw(magic(2u16))]
-    Wrq {
-        filename: NullString,
-        mode: NullString,
-        #[br(parse_with = until_eof)]
-        options: Vec<ProtoOption>,
-    },
-    #[brw(magic(3u16))]
-    Data {
-        block: u16,
-        #[br(parse_with = until_eof)]
-        data: Vec<u8>,
-    },
-    #[brw(magic(4u16))]
-    Ack { block: u16 },
-    #[brw(magic(5u16))]
-    Error {
-        error_code: u16,
-        error_msg: NullString,
+        error_msg: Cow<'a, str>,
     },
-    #[brw(magic(6u16))]
     OAck {
-        #[br(parse_with = until_eof)]
-        options: Vec<ProtoOption>,
+        options: Vec<RequestOption<'a>>,
     },
 }
 
@@ -120,6 +172,10 @@ pub enum ParseError {
     UnrecognizedPacket,
     InvalidString,
     InvalidMode,
+
+    /// The filename and mode of a request were fine, but its options
+    /// were not.
+    InvalidOptions,
 }
 
 impl Display for ParseError {
@@ -137,6 +193,9 @@ impl Display for ParseError {
                     "Failed to parse the packet because of an invalid mode string"
                 )
             }
+            ParseError::InvalidOptions => {
+                write!(f, "Failed to parse the options of the request")
+            }
         }
     }
 }
@@ -144,143 +203,237 @@ impl Display for ParseError {
 impl Error for ParseError {}
 
 fn mode_from_u8(input: &[u8]) -> Result<RequestMode, ParseError> {
-    let mode_str = OsStr::from_bytes(input);
-
-    if mode_str.eq_ignore_ascii_case("netascii") {
+    if input.eq_ignore_ascii_case(b"netascii") {
         Ok(RequestMode::Netascii)
-    } else if mode_str.eq_ignore_ascii_case("octet") {
+    } else if input.eq_ignore_ascii_case(b"octet") {
         Ok(RequestMode::Octet)
     } else {
         Err(ParseError::InvalidMode)
     }
 }
 
-fn mode_to_u8(mode: RequestMode) -> Vec<u8> {
-    match mode {
-        RequestMode::Octet => b"octet".to_vec(),
-        RequestMode::Netascii => b"netascii".to_vec(),
+fn string_from_u8(input: &[u8]) -> Result<&str, ParseError> {
+    std::str::from_utf8(input).map_err(|_| ParseError::InvalidString)
+}
+
+/// Split a big-endian `u16` off the front of `input`.
+fn take_u16(input: &[u8]) -> Result<(u16, &[u8]), ParseError> {
+    match input {
+        [hi, lo, rest @ ..] => Ok((u16::from_be_bytes([*hi, *lo]), rest)),
+        _ => Err(ParseError::UnrecognizedPacket),
     }
 }
 
-fn string_from_u8(input: &[u8]) -> Result<String, ParseError> {
-    std::str::from_utf8(input)
-        .map_err(|_| ParseError::InvalidString)
-        .map(|s| s.to_owned())
+/// Split a NUL-terminated string off the front of `input`. The
+/// returned string doesn't include the terminator.
+fn take_string(input: &[u8]) -> Result<(&[u8], &[u8]), ParseError> {
+    let end = input
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(ParseError::UnrecognizedPacket)?;
+
+    Ok((&input[..end], &input[end + 1..]))
 }
 
-fn option_from_proto(proto_option: &ProtoOption) -> Result<RequestOption, ParseError> {
-    Ok(RequestOption {
-        name: string_from_u8(&proto_option.name)?,
-        value: string_from_u8(&proto_option.value)?,
-    })
+fn parse_options(mut input: &[u8]) -> Result<Vec<RequestOption<'_>>, ParseError> {
+    let mut options = vec![];
+
+    while !input.is_empty() {
+        let (name, rest) = take_string(input)?;
+        let (value, rest) = take_string(rest)?;
+
+        options.push(RequestOption {
+            name: Cow::Borrowed(string_from_u8(name)?),
+            value: Cow::Borrowed(string_from_u8(value)?),
+        });
+        input = rest;
+    }
+
+    Ok(options)
 }
 
-fn options_from_proto(proto_options: &[ProtoOption]) -> Result<Vec<RequestOption>, ParseError> {
-    proto_options.iter().map(option_from_proto).collect()
+/// Parse the common part of RRQ and WRQ packets.
+fn parse_request(
+    input: &[u8],
+) -> Result<(Cow<'_, Path>, RequestMode, Vec<RequestOption<'_>>), ParseError> {
+    let (filename, rest) = take_string(input)?;
+    let (mode, rest) = take_string(rest)?;
+
+    // We avoid going through String to accept filenames with invalid
+    // UTF-8. While the TFTP spec only allows plain ASCII filenames,
+    // this is not the reality on a modern Linux system.
+    Ok((
+        Cow::Borrowed(Path::new(OsStr::from_bytes(filename))),
+        mode_from_u8(mode)?,
+        parse_options(rest).map_err(|_| ParseError::InvalidOptions)?,
+    ))
 }
 
-fn option_to_proto(option: &RequestOption) -> ProtoOption {
-    ProtoOption {
-        name: NullString(option.name.clone().into_bytes()),
-        value: NullString(option.value.clone().into_bytes()),
-    }
+fn put_string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(s);
+    buf.push(0);
 }
 
-fn options_to_proto(options: &[RequestOption]) -> Vec<ProtoOption> {
-    options.iter().map(option_to_proto).collect()
+fn put_options(buf: &mut Vec<u8>, options: &[RequestOption]) {
+    for option in options {
+        put_string(buf, option.name.as_bytes());
+        put_string(buf, option.value.as_bytes());
+    }
 }
 
-impl Packet {
-    pub fn to_vec(&self) -> Vec<u8> {
-        let proto_packet = match self {
+impl Packet<'_> {
+    /// Encode the packet into `buf`, replacing its previous contents.
+    /// This only allocates, if `buf` is too small.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+
+        match self {
             Packet::Rrq {
                 filename,
                 mode,
                 options,
-            } => ProtoPacket::Rrq {
-                filename: NullString(filename.clone().into_os_string().into_vec()),
-                mode: NullString(mode_to_u8(*mode)),
-                options: options_to_proto(options),
-            },
-            Packet::Wrq {
+            }
+            | Packet::Wrq {
                 filename,
                 mode,
                 options,
-            } => ProtoPacket::Wrq {
-                filename: NullString(filename.clone().into_os_string().into_vec()),
-                mode: NullString(mode_to_u8(*mode)),
-                options: options_to_proto(options),
-            },
-            Packet::Data { block, data } => ProtoPacket::Data {
-                block: *block,
-                data: data.clone(),
-            },
-            Packet::Ack { block } => ProtoPacket::Ack { block: *block },
+            } => {
+                let opcode = match self {
+                    Packet::Rrq { .. } => opcode::RRQ,
+                    _ => opcode::WRQ,
+                };
+
+                buf.extend_from_slice(&opcode.to_be_bytes());
+                put_string(buf, filename.as_os_str().as_bytes());
+                put_string(buf, mode.as_bytes());
+                put_options(buf, options);
+            }
+            Packet::Data { block, data } => {
+                buf.extend_from_slice(&opcode::DATA.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Packet::Ack { block } => {
+                buf.extend_from_slice(&opcode::ACK.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+            }
             Packet::Error {
                 error_code,
                 error_msg,
-            } => ProtoPacket::Error {
-                error_code: *error_code,
-                error_msg: error_msg.clone().into(),
-            },
-            Packet::OAck { options } => ProtoPacket::OAck {
-                options: options_to_proto(options),
-            },
-        };
-
-        let mut cur = Cursor::new(Vec::new());
-        cur.write_be(&proto_packet).unwrap();
-        cur.into_inner()
+            } => {
+                buf.extend_from_slice(&opcode::ERROR.to_be_bytes());
+                buf.extend_from_slice(&error_code.to_be_bytes());
+                put_string(buf, error_msg.as_bytes());
+            }
+            Packet::OAck { options } => {
+                buf.extend_from_slice(&opcode::OACK.to_be_bytes());
+                put_options(buf, options);
+            }
+        }
     }
-}
 
-impl TryFrom<&[u8]> for Packet {
-    type Error = ParseError;
+    /// Encode the packet into a new buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![];
 
-    fn try_from(input: &[u8]) -> Result<Self, ParseError> {
-        let proto_packet: ProtoPacket = Cursor::new(input)
-            .read_be()
-            .map_err(|_| ParseError::UnrecognizedPacket)?;
+        self.encode_into(&mut buf);
+        buf
+    }
 
-        let packet = match proto_packet {
-            ProtoPacket::Rrq {
+    /// Copy all borrowed data, so the packet can outlive the buffer
+    /// it was parsed from.
+    pub fn into_owned(self) -> Packet<'static> {
+        fn options_into_owned(options: Vec<RequestOption>) -> Vec<RequestOption<'static>> {
+            options.into_iter().map(RequestOption::into_owned).collect()
+        }
+
+        match self {
+            Packet::Rrq {
                 filename,
                 mode,
                 options,
-            } => {
-                Packet::Rrq {
-                    // We avoid going through String to accept filenames
-                    // with invalid UTF-8. While the TFTP spec only allows
-                    // plain ASCII filenames, this is not the reality on a
-                    // modern Linux system.
-                    filename: PathBuf::from(OsString::from_vec(filename.to_vec())),
-                    mode: mode_from_u8(&mode)?,
-                    options: options_from_proto(&options)?,
-                }
-            }
-            ProtoPacket::Wrq {
+            } => Packet::Rrq {
+                filename: Cow::Owned(filename.into_owned()),
+                mode,
+                options: options_into_owned(options),
+            },
+            Packet::Wrq {
                 filename,
                 mode,
                 options,
             } => Packet::Wrq {
-                // See the comment in Rrq above.
-                filename: PathBuf::from(OsString::from_vec(filename.to_vec())),
-                mode: mode_from_u8(&mode)?,
-                options: options_from_proto(&options)?,
+                filename: Cow::Owned(filename.into_owned()),
+                mode,
+                options: options_into_owned(options),
             },
-            ProtoPacket::Data { block, data } => Packet::Data { block, data },
-            ProtoPacket::Ack { block } => Packet::Ack { block },
-            ProtoPacket::Error {
+            Packet::Data { block, data } => Packet::Data {
+                block,
+                data: Cow::Owned(data.into_owned()),
+            },
+            Packet::Ack { block } => Packet::Ack { block },
+            Packet::Error {
                 error_code,
                 error_msg,
             } => Packet::Error {
                 error_code,
-                error_msg: String::from_utf8(error_msg.to_vec())
-                    .map_err(|_| ParseError::InvalidString)?,
+                error_msg: Cow::Owned(error_msg.into_owned()),
+            },
+            Packet::OAck { options } => Packet::OAck {
+                options: options_into_owned(options),
+            },
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Packet<'a> {
+    type Error = ParseError;
+
+    fn try_from(input: &'a [u8]) -> Result<Self, ParseError> {
+        let (opcode, rest) = take_u16(input)?;
+
+        let packet = match opcode {
+            opcode::RRQ => {
+                let (filename, mode, options) = parse_request(rest)?;
+
+                Packet::Rrq {
+                    filename,
+                    mode,
+                    options,
+                }
+            }
+            opcode::WRQ => {
+                let (filename, mode, options) = parse_request(rest)?;
+
+                Packet::Wrq {
+                    filename,
+                    mode,
+                    options,
+                }
+            }
+            opcode::DATA => {
+                let (block, data) = take_u16(rest)?;
+
+                Packet::Data {
+                    block,
+                    data: Cow::Borrowed(data),
+                }
+            }
+            opcode::ACK => Packet::Ack {
+                block: take_u16(rest)?.0,
             },
-            ProtoPacket::OAck { options } => Packet::OAck {
-                options: options_from_proto(&options)?,
+            opcode::ERROR => {
+                let (error_code, rest) = take_u16(rest)?;
+                let (error_msg, _) = take_string(rest)?;
+
+                Packet::Error {
+                    error_code,
+                    error_msg: Cow::Borrowed(string_from_u8(error_msg)?),
+                }
+            }
+            opcode::OACK => Packet::OAck {
+                options: parse_options(rest)?,
             },
+            _ => return Err(ParseError::UnrecognizedPacket),
         };
 
         Ok(packet)
@@ -291,14 +444,34 @@ impl TryFrom<&[u8]> for Packet {
 mod tests {
     use super::*;
 
-    use std::str::FromStr;
+    #[test]
+    fn rollover_block_numbers() {
+        for (rollover, numbers) in [
+            (
+                Rollover::ToZero,
+                [Some(65535), Some(0), Some(1), Some(65535)],
+            ),
+            (Rollover::ToOne, [Some(65535), Some(1), Some(2), Some(1)]),
+            (Rollover::Refuse, [Some(65535), None, None, None]),
+        ] {
+            assert_eq!(
+                [65535, 65536, 65537, 131071].map(|block| rollover.block_number(block)),
+                numbers,
+                "{rollover:?}"
+            );
+        }
+
+        assert_eq!(Rollover::from_option("1"), Some(Rollover::ToOne));
+        assert_eq!(Rollover::from_option("2"), None);
+        assert_eq!("refuse".parse(), Ok(Rollover::Refuse));
+    }
 
     #[test]
     fn parse_rrq_without_options() {
         assert_eq!(
             Packet::try_from(b"\x00\x01\0octet\0".as_ref()),
             Ok(Packet::Rrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -307,7 +480,7 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x01foo\0NeTAscIi\0".as_ref()),
             Ok(Packet::Rrq {
-                filename: PathBuf::from_str("foo").unwrap(),
+                filename: Path::new("foo").into(),
                 mode: RequestMode::Netascii,
                 options: vec![]
             })
@@ -316,7 +489,7 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x01zOo\0oCtet\0".as_ref()),
             Ok(Packet::Rrq {
-                filename: PathBuf::from_str("zOo").unwrap(),
+                filename: Path::new("zOo").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -327,7 +500,7 @@ mod tests {
     fn serialize_rrq_without_options() {
         assert_eq!(
             (Packet::Rrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -337,7 +510,7 @@ mod tests {
 
         assert_eq!(
             (Packet::Rrq {
-                filename: PathBuf::from_str("zOo").unwrap(),
+                filename: Path::new("zOo").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -351,36 +524,52 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x01\0octet\0key1\0value1\0key2\0value2\0".as_ref()),
             Ok(Packet::Rrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
         );
     }
 
+    #[test]
+    fn parse_rrq_with_invalid_options() {
+        assert_eq!(
+            Packet::try_from(b"\x00\x01kernel\0octet\0\0\0\0".as_ref()),
+            Err(ParseError::InvalidOptions)
+        );
+        assert_eq!(
+            Packet::try_from(b"\x00\x01kernel\0octet\0blksize\0\xff\0".as_ref()),
+            Err(ParseError::InvalidOptions)
+        );
+        assert_eq!(
+            Packet::try_from(b"\x00\x01kernel\0mail\0\0\0\0".as_ref()),
+            Err(ParseError::InvalidMode)
+        );
+    }
+
     #[test]
     fn serialize_rrq_with_options() {
         assert_eq!(
             (Packet::Rrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
@@ -394,7 +583,7 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x02\0octet\0".as_ref()),
             Ok(Packet::Wrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -403,7 +592,7 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x02foo\0NeTAscIi\0".as_ref()),
             Ok(Packet::Wrq {
-                filename: PathBuf::from_str("foo").unwrap(),
+                filename: Path::new("foo").into(),
                 mode: RequestMode::Netascii,
                 options: vec![]
             })
@@ -412,7 +601,7 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x02zOo\0oCtet\0".as_ref()),
             Ok(Packet::Wrq {
-                filename: PathBuf::from_str("zOo").unwrap(),
+                filename: Path::new("zOo").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -423,7 +612,7 @@ mod tests {
     fn serialize_wrq_without_options() {
         assert_eq!(
             (Packet::Wrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![]
             })
@@ -433,7 +622,7 @@ mod tests {
 
         assert_eq!(
             (Packet::Wrq {
-                filename: PathBuf::from_str("foo").unwrap(),
+                filename: Path::new("foo").into(),
                 mode: RequestMode::Netascii,
                 options: vec![]
             })
@@ -447,16 +636,16 @@ mod tests {
         assert_eq!(
             Packet::try_from(b"\x00\x02\0octet\0key1\0value1\0key2\0value2\0".as_ref()),
             Ok(Packet::Wrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
@@ -467,16 +656,16 @@ mod tests {
     fn serialize_wrq_with_options() {
         assert_eq!(
             (Packet::Wrq {
-                filename: PathBuf::from_str("").unwrap(),
+                filename: Path::new("").into(),
                 mode: RequestMode::Octet,
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
@@ -491,7 +680,7 @@ mod tests {
             Packet::try_from(b"\x00\x03\x12\x34hello world".as_ref()),
             Ok(Packet::Data {
                 block: 0x1234,
-                data: b"hello world".to_vec(),
+                data: b"hello world".as_ref().into(),
             })
         )
     }
@@ -501,7 +690,7 @@ mod tests {
         assert_eq!(
             (Packet::Data {
                 block: 0x1234,
-                data: b"hello world".to_vec(),
+                data: b"hello world".as_ref().into(),
             })
             .to_vec(),
             b"\x00\x03\x12\x34hello world",
@@ -530,7 +719,7 @@ mod tests {
             Packet::try_from(b"\x00\x05\x01\x02Some error!\0".as_ref()),
             Ok(Packet::Error {
                 error_code: 0x0102,
-                error_msg: "Some error!".to_owned()
+                error_msg: "Some error!".into()
             })
         )
     }
@@ -540,7 +729,7 @@ mod tests {
         assert_eq!(
             (Packet::Error {
                 error_code: 0x0102,
-                error_msg: "Some error!".to_owned()
+                error_msg: "Some error!".into()
             })
             .to_vec(),
             b"\x00\x05\x01\x02Some error!\0",
@@ -559,12 +748,12 @@ mod tests {
             Ok(Packet::OAck {
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
@@ -579,12 +768,12 @@ mod tests {
             (Packet::OAck {
                 options: vec![
                     RequestOption {
-                        name: "key1".to_string(),
-                        value: "value1".to_string()
+                        name: "key1".into(),
+                        value: "value1".into()
                     },
                     RequestOption {
-                        name: "key2".to_string(),
-                        value: "value2".to_string()
+                        name: "key2".into(),
+                        value: "value2".into()
                     }
                 ]
             })
@@ -592,4 +781,52 @@ mod tests {
             b"\x00\x06key1\0value1\0key2\0value2\0"
         );
     }
+
+    #[test]
+    fn parse_borrows_from_input() {
+        let input = b"\x00\x03\x00\x01hello";
+
+        match Packet::try_from(input.as_ref()) {
+            Ok(Packet::Data {
+                data: Cow::Borrowed(data),
+                ..
+            }) => assert_eq!(data.as_ptr(), input[4..].as_ptr()),
+            p => panic!("Unexpected parse result: {p:?}"),
+        }
+    }
+
+    #[test]
+    fn reject_truncated_packets() {
+        for input in [
+            b"".as_ref(),
+            b"\x00",
+            b"\x00\x04\x00",
+            b"\x00\x01foo",
+            b"\x00\x01foo\0octet",
+            b"\x00\x06key\0",
+            b"\x00\x07",
+        ] {
+            assert_eq!(
+                Packet::try_from(input),
+                Err(ParseError::UnrecognizedPacket),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_into_reuses_buffer() {
+        let mut buf = Vec::with_capacity(64);
+        let ptr = buf.as_ptr();
+
+        (Packet::Data {
+            block: 1,
+            data: b"first".as_ref().into(),
+        })
+        .encode_into(&mut buf);
+        (Packet::Ack { block: 2 }).encode_into(&mut buf);
+
+        assert_eq!(buf, b"\x00\x04\x00\x02");
+        assert_eq!(buf.as_ptr(), ptr);
+    }
 }
@@ -0,0 +1,671 @@
+//! This module implements the client side of TFTP downloads in terms
+//! of [`simple_proto`].
+//!
+//! [`Client`] is the state machine. [`get`] drives it over a UDP
+//! socket.
+
+use std::{
+    borrow::Cow,
+    io::Write,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use log::{debug, trace, warn};
+
+use crate::{
+    simple_proto::{self, ConnectionStatus, Event, Response},
+    tftp::{
+        self, RequestOption, DEFAULT_TFTP_BLKSIZE, DEFAULT_TFTP_TIMEOUT, DEFAULT_TFTP_WINDOWSIZE,
+        MAX_RETRANSMISSIONS,
+    },
+};
+
+type Packet = tftp::Packet<'static>;
+
+/// What the client asks the server for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// The block size to request. Without it, blocks have 512 bytes.
+    pub block_size: Option<u16>,
+
+    /// The window size to request. Without it, every block is
+    /// acknowledged individually.
+    pub window_size: Option<u16>,
+
+    /// How long we wait for the server before we resend our last packet.
+    pub timeout: Duration,
+
+    /// How many times we resend a packet before we give up.
+    pub max_retransmissions: u32,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            block_size: None,
+            window_size: None,
+            timeout: DEFAULT_TFTP_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+        }
+    }
+}
+
+/// The outcome of a successful download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// How many bytes we received.
+    pub bytes: u64,
+
+    /// The file size announced by the server, if it supports the
+    /// `tsize` option.
+    pub transfer_size: Option<u64>,
+
+    /// The negotiated block size.
+    pub block_size: u16,
+
+    /// The negotiated window size.
+    pub window_size: u16,
+}
+
+#[derive(Debug)]
+enum State {
+    /// We have sent the request and wait for OACK or the first block.
+    Requesting,
+
+    /// We are receiving data.
+    Receiving {
+        /// The block we expect next. Like on the server, this doesn't
+        /// wrap around.
+        next_block: u64,
+
+        /// How many blocks we received since we last sent an ACK.
+        in_window: u16,
+
+        /// Whether we already acknowledged the last good block after
+        /// noticing a gap. This avoids sending one ACK per packet
+        /// that arrives after a lost one.
+        gap_acked: bool,
+    },
+
+    /// The whole file was received.
+    Done,
+
+    /// The transfer failed.
+    Failed(String),
+}
+
+/// A client that downloads a single file and writes it to `W`.
+#[derive(Debug)]
+pub struct Client<W: Write> {
+    sink: W,
+    options: ClientOptions,
+    state: State,
+
+    /// The packet we resend on timeouts.
+    last_sent: Packet,
+    timeouts: u32,
+
+    block_size: u16,
+    window_size: u16,
+    transfer_size: Option<u64>,
+    bytes: u64,
+}
+
+impl<W: Write> Client<W> {
+    /// Create a client that requests `filename` and writes its
+    /// contents to `sink`. Nothing is sent before [`Client::start`].
+    pub fn new(filename: impl AsRef<Path>, options: ClientOptions, sink: W) -> Self {
+        let mut request_options = vec![RequestOption {
+            name: "tsize".into(),
+            value: "0".into(),
+        }];
+
+        if let Some(block_size) = options.block_size {
+            request_options.push(RequestOption {
+                name: "blksize".into(),
+                value: block_size.to_string().into(),
+            });
+        }
+
+        if let Some(window_size) = options.window_size {
+            request_options.push(RequestOption {
+                name: "windowsize".into(),
+                value: window_size.to_string().into(),
+            });
+        }
+
+        Self {
+            sink,
+            options,
+            state: State::Requesting,
+            last_sent: tftp::Packet::Rrq {
+                filename: Cow::Owned(filename.as_ref().to_owned()),
+                mode: tftp::RequestMode::Octet,
+                options: request_options,
+            },
+            timeouts: 0,
+            block_size: DEFAULT_TFTP_BLKSIZE,
+            window_size: DEFAULT_TFTP_WINDOWSIZE,
+            transfer_size: None,
+            bytes: 0,
+        }
+    }
+
+    /// Return the read request that starts the transfer.
+    pub fn start(&self) -> Response<Packet> {
+        self.wait_with(vec![self.last_sent.clone()])
+    }
+
+    /// Return the result of the transfer, once it has terminated.
+    pub fn finish(self) -> Result<Summary> {
+        match self.state {
+            State::Done => Ok(Summary {
+                bytes: self.bytes,
+                transfer_size: self.transfer_size,
+                block_size: self.block_size,
+                window_size: self.window_size,
+            }),
+            State::Failed(error) => Err(anyhow!(error)),
+            State::Requesting | State::Receiving { .. } => bail!("The transfer did not finish"),
+        }
+    }
+
+    fn wait_with(&self, packets: Vec<Packet>) -> Response<Packet> {
+        Response {
+            packets,
+            next_status: ConnectionStatus::WaitingForPacket(self.options.timeout),
+        }
+    }
+
+    fn send(&mut self, packet: Packet) -> Response<Packet> {
+        self.last_sent = packet.clone();
+        self.wait_with(vec![packet])
+    }
+
+    fn ack(&mut self, block: u64) -> Response<Packet> {
+        self.send(tftp::Packet::Ack {
+            block: u16::try_from(block & 0xffff).unwrap(),
+        })
+    }
+
+    /// Give up without telling the server.
+    fn fail(&mut self, error: String) -> Response<Packet> {
+        debug!("{error}");
+        self.state = State::Failed(error);
+
+        Response {
+            packets: vec![],
+            next_status: ConnectionStatus::Terminated,
+        }
+    }
+
+    /// Give up and tell the server why.
+    fn fail_with_error(&mut self, error_code: u16, error: String) -> Response<Packet> {
+        let mut response = self.fail(error.clone());
+
+        response.packets.push(tftp::Packet::Error {
+            error_code,
+            error_msg: error.into(),
+        });
+        response
+    }
+
+    /// Check the options the server acknowledged. Returns an error
+    /// message, if the server acknowledged something we didn't ask for.
+    fn accept_options(&mut self, options: &[RequestOption]) -> Result<(), String> {
+        for option in options {
+            if option.name.eq_ignore_ascii_case("blksize") {
+                match (option.value.parse::<u16>(), self.options.block_size) {
+                    (Ok(block_size), Some(requested)) if (8..=requested).contains(&block_size) => {
+                        self.block_size = block_size
+                    }
+                    _ => return Err(format!("Invalid block size from server: {}", option.value)),
+                }
+            } else if option.name.eq_ignore_ascii_case("windowsize") {
+                match (option.value.parse::<u16>(), self.options.window_size) {
+                    (Ok(window_size), Some(requested))
+                        if (1..=requested).contains(&window_size) =>
+                    {
+                        self.window_size = window_size
+                    }
+                    _ => return Err(format!("Invalid window size from server: {}", option.value)),
+                }
+            } else if option.name.eq_ignore_ascii_case("tsize") {
+                match option.value.parse::<u64>() {
+                    Ok(transfer_size) => self.transfer_size = Some(transfer_size),
+                    Err(_) => {
+                        return Err(format!(
+                            "Invalid transfer size from server: {}",
+                            option.value
+                        ))
+                    }
+                }
+            } else {
+                return Err(format!(
+                    "Server acknowledged unknown option {}",
+                    option.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive_data(
+        &mut self,
+        mut next_block: u64,
+        mut in_window: u16,
+        gap_acked: bool,
+        block: u16,
+        data: &[u8],
+    ) -> Result<Response<Packet>> {
+        if u64::from(block) != next_block & 0xffff {
+            debug!("Received block {block:#x}, but expected {next_block:#x}.");
+
+            // Acknowledge what we have, so the server resends from there.
+            self.state = State::Receiving {
+                next_block,
+                in_window: 0,
+                gap_acked: true,
+            };
+
+            return Ok(if gap_acked {
+                self.wait_with(vec![])
+            } else {
+                self.ack(next_block - 1)
+            });
+        }
+
+        if data.len() > usize::from(self.block_size) {
+            return Ok(self.fail_with_error(
+                tftp::error::ILLEGAL_OPERATION,
+                format!("Block {block:#x} is larger than the block size"),
+            ));
+        }
+
+        self.sink
+            .write_all(data)
+            .context("Failed to write received data")?;
+        self.bytes += data.len() as u64;
+        self.timeouts = 0;
+
+        if data.len() < usize::from(self.block_size) {
+            debug!("Received final block {next_block:#x}.");
+
+            self.sink.flush().context("Failed to write received data")?;
+
+            let mut response = self.ack(next_block);
+            response.next_status = ConnectionStatus::Terminated;
+            self.state = State::Done;
+
+            return Ok(response);
+        }
+
+        in_window += 1;
+        next_block += 1;
+
+        let response = if in_window == self.window_size {
+            in_window = 0;
+            self.ack(next_block - 1)
+        } else {
+            self.wait_with(vec![])
+        };
+
+        self.state = State::Receiving {
+            next_block,
+            in_window,
+            gap_acked: false,
+        };
+
+        Ok(response)
+    }
+
+    fn handle_timeout(&mut self) -> Response<Packet> {
+        self.timeouts += 1;
+
+        if self.timeouts > self.options.max_retransmissions {
+            return self.fail("Server timed out".to_owned());
+        }
+
+        debug!("Timeout, resending {:?}.", self.last_sent);
+
+        if let State::Receiving { in_window, .. } = &mut self.state {
+            *in_window = 0;
+        }
+
+        self.wait_with(vec![self.last_sent.clone()])
+    }
+}
+
+#[async_trait]
+impl<W: Write + Send> simple_proto::SimpleUdpProtocol for Client<W> {
+    type Packet = Packet;
+    type Error = anyhow::Error;
+
+    async fn handle_event(&mut self, event: Event<Self::Packet>) -> Result<Response<Packet>> {
+        let packet = match event {
+            Event::Timeout => return Ok(self.handle_timeout()),
+            Event::PacketReceived(packet) => packet,
+        };
+
+        match (&self.state, packet) {
+            (State::Done | State::Failed(_), packet) => {
+                panic!("Should not receive packets after the transfer is over: {packet:?}")
+            }
+            (
+                _,
+                tftp::Packet::Error {
+                    error_code,
+                    error_msg,
+                },
+            ) => Ok(self.fail(format!("Server sent error {error_code}: {error_msg}"))),
+            (State::Requesting, tftp::Packet::OAck { options }) => {
+                match self.accept_options(&options) {
+                    Ok(()) => {
+                        self.state = State::Receiving {
+                            next_block: 1,
+                            in_window: 0,
+                            gap_acked: false,
+                        };
+                        self.timeouts = 0;
+
+                        Ok(self.ack(0))
+                    }
+                    Err(error) => Ok(self.fail_with_error(tftp::error::INVALID_OPTION, error)),
+                }
+            }
+            (State::Requesting, tftp::Packet::Data { block, data }) => {
+                // The server ignored our options.
+                self.receive_data(1, 0, false, block, &data)
+            }
+            (
+                State::Receiving {
+                    next_block,
+                    in_window,
+                    gap_acked,
+                },
+                tftp::Packet::Data { block, data },
+            ) => self.receive_data(*next_block, *in_window, *gap_acked, block, &data),
+            (State::Receiving { .. }, tftp::Packet::OAck { .. }) => {
+                // The server didn't see our ACK and resends its OACK.
+                Ok(self.wait_with(vec![self.last_sent.clone()]))
+            }
+            (_, packet) => Ok(self.fail_with_error(
+                tftp::error::ILLEGAL_OPERATION,
+                format!("Unexpected packet from server: {packet:?}"),
+            )),
+        }
+    }
+}
+
+/// Wait for a packet from the server. Until we know the server's
+/// transfer port, we accept packets from any port of `server`.
+async fn recv_from_server(
+    socket: &tokio::net::UdpSocket,
+    server: SocketAddr,
+    peer: &mut Option<SocketAddr>,
+    buf: &mut [u8],
+) -> Result<Packet> {
+    loop {
+        let (len, from) = socket.recv_from(buf).await?;
+
+        let expected = match peer {
+            Some(peer) => from == *peer,
+            None => from.ip() == server.ip(),
+        };
+
+        if !expected {
+            debug!("Ignoring packet from unexpected address {from}.");
+            continue;
+        }
+
+        match tftp::Packet::try_from(&buf[..len]) {
+            Ok(packet) => {
+                *peer = Some(from);
+                return Ok(packet.into_owned());
+            }
+            Err(e) => warn!("Ignoring packet from {from}: {e}"),
+        }
+    }
+}
+
+/// Download `filename` from `server` into `sink`.
+pub async fn get<W: Write + Send>(
+    server: SocketAddr,
+    filename: impl Into<PathBuf>,
+    options: ClientOptions,
+    sink: W,
+) -> Result<Summary> {
+    use simple_proto::SimpleUdpProtocol;
+
+    let local_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = tokio::net::UdpSocket::bind(local_addr).await?;
+
+    let mut client = Client::new(filename.into(), options, sink);
+    let mut response = client.start();
+    let mut peer = None;
+    let mut recv_buf = vec![0u8; 1 << 16];
+    let mut send_buf = vec![];
+
+    loop {
+        for packet in &response.packets {
+            trace!("{packet:?}");
+            packet.encode_into(&mut send_buf);
+            socket.send_to(&send_buf, peer.unwrap_or(server)).await?;
+        }
+
+        let timeout = match response.next_status {
+            ConnectionStatus::Terminated => break,
+            ConnectionStatus::WaitingForPacket(timeout) => timeout,
+        };
+
+        let event = match tokio::time::timeout(
+            timeout,
+            recv_from_server(&socket, server, &mut peer, &mut recv_buf),
+        )
+        .await
+        {
+            Ok(packet) => Event::PacketReceived(packet?),
+            Err(_) => Event::Timeout,
+        };
+
+        response = client.handle_event(event).await?;
+    }
+
+    client.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_proto::SimpleUdpProtocol;
+
+    use super::*;
+
+    fn data(block: u16, data: &[u8]) -> Event<Packet> {
+        Event::PacketReceived(tftp::Packet::Data {
+            block,
+            data: data.to_vec().into(),
+        })
+    }
+
+    fn ack(block: u16) -> Packet {
+        tftp::Packet::Ack { block }
+    }
+
+    #[tokio::test]
+    async fn windowed_download() {
+        let mut client = Client::new(
+            "kernel",
+            ClientOptions {
+                block_size: Some(8),
+                window_size: Some(2),
+                ..Default::default()
+            },
+            vec![],
+        );
+
+        assert!(matches!(
+            &client.start().packets[..],
+            [tftp::Packet::Rrq { options, .. }] if options.len() == 3
+        ));
+
+        let response = client
+            .handle_event(Event::PacketReceived(tftp::Packet::OAck {
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "8".into(),
+                    },
+                    RequestOption {
+                        name: "windowsize".into(),
+                        value: "2".into(),
+                    },
+                    RequestOption {
+                        name: "tsize".into(),
+                        value: "18".into(),
+                    },
+                ],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.packets, [ack(0)]);
+
+        assert!(client
+            .handle_event(data(1, b"abcdefgh"))
+            .await
+            .unwrap()
+            .packets
+            .is_empty());
+        assert_eq!(
+            client
+                .handle_event(data(2, b"ijklmnop"))
+                .await
+                .unwrap()
+                .packets,
+            [ack(2)]
+        );
+
+        // Block 3 got lost. We acknowledge block 2 only once.
+        assert_eq!(
+            client
+                .handle_event(data(4, b"stuvwxyz"))
+                .await
+                .unwrap()
+                .packets,
+            [ack(2)]
+        );
+        assert!(client
+            .handle_event(data(5, b"01234567"))
+            .await
+            .unwrap()
+            .packets
+            .is_empty());
+
+        assert_eq!(
+            client.handle_event(data(3, b"qr")).await.unwrap(),
+            Response {
+                packets: vec![ack(3)],
+                next_status: ConnectionStatus::Terminated,
+            }
+        );
+
+        assert_eq!(client.sink, b"abcdefghijklmnopqr");
+        assert_eq!(
+            client.finish().unwrap(),
+            Summary {
+                bytes: 18,
+                transfer_size: Some(18),
+                block_size: 8,
+                window_size: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unrequested_options() {
+        let mut client = Client::new("kernel", ClientOptions::default(), vec![]);
+
+        let response = client
+            .handle_event(Event::PacketReceived(tftp::Packet::OAck {
+                options: vec![RequestOption {
+                    name: "blksize".into(),
+                    value: "1024".into(),
+                }],
+            }))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            &response.packets[..],
+            [tftp::Packet::Error {
+                error_code: tftp::error::INVALID_OPTION,
+                ..
+            }]
+        ));
+        assert!(client.finish().is_err());
+    }
+
+    #[tokio::test]
+    async fn retransmits_and_gives_up() {
+        let mut client = Client::new("kernel", ClientOptions::default(), vec![]);
+        let request = client.start().packets;
+
+        for _ in 0..MAX_RETRANSMISSIONS {
+            assert_eq!(
+                client.handle_event(Event::Timeout).await.unwrap().packets,
+                request
+            );
+        }
+
+        assert_eq!(
+            client
+                .handle_event(Event::Timeout)
+                .await
+                .unwrap()
+                .next_status,
+            ConnectionStatus::Terminated
+        );
+        assert!(client.finish().is_err());
+    }
+
+    #[tokio::test]
+    async fn downloads_from_server() {
+        let contents: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+
+        tokio::spawn(
+            crate::Server::new(crate::simple_fs::MapFilesystem::from([(
+                PathBuf::from("/kernel"),
+                contents.clone(),
+            )]))
+            .socket(socket)
+            .serve(),
+        );
+
+        let mut received = vec![];
+        let summary = get(
+            server_addr,
+            "kernel",
+            ClientOptions {
+                block_size: Some(1000),
+                window_size: Some(4),
+                ..Default::default()
+            },
+            &mut received,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(received, contents);
+        assert_eq!(summary.transfer_size, Some(3000));
+        assert_eq!(summary.window_size, 4);
+    }
+}
@@ -1,49 +1,266 @@
 //! This module implements the TFTP protocol in terms of [`simple_proto`].
 
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    net::{SocketAddr, SocketAddrV4},
     path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
 use crate::{
+    arp::{ArpTable, Peer},
+    client_arch::{ArchTable, Architecture},
+    inventory::{Host, Inventory},
+    multicast::{Membership, MulticastGroups, SessionKey},
     path::normalize,
+    pxe_lookup::PxeLookup,
+    quirks::Quirks,
+    rtt::RttEstimator,
     simple_fs::{self, File},
     simple_proto::{self, ConnectionStatus, Event, Response},
-    tftp::{self, RequestOption},
+    tftp::{
+        self, RequestOption, Rollover, DEFAULT_TFTP_BLKSIZE, DEFAULT_TFTP_TIMEOUT,
+        DEFAULT_TFTP_WINDOWSIZE, MAX_RETRANSMISSIONS,
+    },
 };
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use log::{debug, error, info, warn};
+use tokio::time::Instant;
+
+/// The packets the state machine works with. Received packets are
+/// usually parsed without allocating, so this doesn't cost anything
+/// for ACKs.
+type Packet = tftp::Packet<'static>;
+
+const MIN_TFTP_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_TFTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest block size that RFC 2348 allows.
+const MAX_TFTP_BLKSIZE: u16 = 65464;
+
+/// The largest window we agree to. Larger windows make packet loss
+/// expensive, because we resend the whole window.
+const MAX_TFTP_WINDOWSIZE: u16 = 64;
+
+/// RFC 2347 limits requests with options to this many bytes.
+const MAX_TFTP_REQUEST_SIZE: u16 = 512;
+
+/// The longest filename in bytes that we accept by default. Boot
+/// loaders ask for much shorter paths.
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// The most options that we accept in a request by default. There
+/// are only a handful of options that we understand.
+const MAX_REQUEST_OPTIONS: usize = 16;
+
+/// Settings that control how we treat clients.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How long we wait for the first response before we resend
+    /// packets. Later timeouts are estimated from round-trip times,
+    /// unless the client asks for a fixed timeout.
+    pub timeout: Duration,
+
+    /// The shortest timeout the estimate may come up with.
+    pub min_timeout: Duration,
+
+    /// The longest timeout the estimate may come up with.
+    pub max_timeout: Duration,
+
+    /// How many times do we resend packets, if we don't get a response.
+    pub max_retransmissions: u32,
+
+    /// The largest window that we agree to, if a client asks for one.
+    pub max_window_size: u16,
+
+    /// The largest block size that we agree to, if a client asks for
+    /// a larger one.
+    pub max_block_size: u16,
+
+    /// Send the file without options, if a client doesn't accept our
+    /// OACK. Some old clients can't handle OACKs at all.
+    pub restart_without_options: bool,
+
+    /// Also limit the block size, so data packets fit into the path MTU
+    /// towards the client and aren't fragmented.
+    pub limit_block_size_to_mtu: bool,
 
-const DEFAULT_TFTP_TIMEOUT: Duration = Duration::from_secs(1);
-const DEFAULT_TFTP_BLKSIZE: u16 = 512;
+    /// Requests larger than this are refused. RFC 2347 allows 512
+    /// bytes, but some clients send more options than fit.
+    pub max_request_size: u16,
 
-/// How many times do we resend packets, if we don't get a response.
-const MAX_RETRANSMISSIONS: u32 = 5;
+    /// Requests for longer filenames are refused.
+    pub max_filename_length: usize,
+
+    /// Requests with more options than this are refused.
+    pub max_request_options: usize,
+
+    /// Resolve PXELINUX-style lookups on the server.
+    pub pxe_lookup: Option<Arc<PxeLookup>>,
+
+    /// Tells us the MAC addresses of clients for the inventory,
+    /// lookups and `arch_table`.
+    pub arp_table: Option<ArpTable>,
+
+    /// The hosts we know.
+    pub inventory: Option<Arc<Inventory>>,
+
+    /// Serve hosts of these inventory groups from a subdirectory of
+    /// the root directory.
+    pub group_roots: BTreeMap<String, PathBuf>,
+
+    /// Tells us the architecture of clients for `arch_rules`.
+    pub arch_table: Option<Arc<ArchTable>>,
+
+    /// Serve a different file depending on the architecture of the
+    /// client, e.g. `bootfile` becomes `undionly.kpxe` for BIOS
+    /// clients. Paths are relative to the root directory. Clients of
+    /// unknown architecture get the file they asked for.
+    pub arch_rules: BTreeMap<PathBuf, BTreeMap<Architecture, PathBuf>>,
+
+    /// Offer multicast sessions to clients that ask for them.
+    pub multicast: Option<Arc<MulticastGroups>>,
+
+    /// What follows block 65535, unless the client asks for something
+    /// else with the `rollover` option.
+    pub rollover: Rollover,
+
+    /// Work around bugs of these clients.
+    pub quirks: Option<Arc<Quirks>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TFTP_TIMEOUT,
+            min_timeout: MIN_TFTP_TIMEOUT,
+            max_timeout: MAX_TFTP_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+            max_window_size: MAX_TFTP_WINDOWSIZE,
+            max_block_size: MAX_TFTP_BLKSIZE,
+            restart_without_options: false,
+            limit_block_size_to_mtu: false,
+            max_request_size: MAX_TFTP_REQUEST_SIZE,
+            max_filename_length: MAX_FILENAME_LENGTH,
+            max_request_options: MAX_REQUEST_OPTIONS,
+            pxe_lookup: None,
+            arp_table: None,
+            inventory: None,
+            group_roots: BTreeMap::new(),
+            arch_table: None,
+            arch_rules: BTreeMap::new(),
+            multicast: None,
+            rollover: Rollover::default(),
+            quirks: None,
+        }
+    }
+}
 
 /// The options sent by the client that we acknowledged.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 struct AcceptedOptions {
     block_size: Option<u16>,
     transfer_size: Option<u64>,
+    window_size: Option<u16>,
+
+    /// The timeout in seconds the client asked for, see RFC 2349.
+    timeout: Option<u8>,
+
+    /// The client asked to join a multicast session.
+    multicast: bool,
+
+    rollover: Option<Rollover>,
+}
+
+/// The `multicast` option of an OACK, see RFC 2090.
+fn multicast_option(group: SocketAddrV4, master: bool) -> RequestOption<'static> {
+    RequestOption {
+        name: "multicast".into(),
+        value: format!("{},{},{}", group.ip(), group.port(), u8::from(master)).into(),
+    }
+}
+
+/// Format options for log messages, e.g. `blksize=1468 tsize=1234`.
+fn format_options(options: &[RequestOption]) -> String {
+    options
+        .iter()
+        .map(|option| format!("{}={}", option.name, option.value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Check the options of a request for problems that make us refuse
+/// the whole request. Returns the reason to refuse it. Some clients pad
+/// requests with NUL bytes, so empty options at the end are ignored.
+pub(crate) fn check_request_options(options: &[RequestOption]) -> Result<(), &'static str> {
+    let padding = options
+        .iter()
+        .rev()
+        .take_while(|option| option.name.is_empty() && option.value.is_empty())
+        .count();
+    let options = &options[..options.len() - padding];
+
+    for (i, option) in options.iter().enumerate() {
+        if option.name.is_empty() {
+            return Err("Empty option name");
+        }
+
+        // RFC 2347 doesn't say which one counts.
+        if options[..i]
+            .iter()
+            .any(|earlier| earlier.name.eq_ignore_ascii_case(&option.name))
+        {
+            return Err("Duplicate option");
+        }
+    }
+
+    Ok(())
 }
 
 impl AcceptedOptions {
-    fn to_option_vec(self) -> Vec<RequestOption> {
+    fn to_option_vec(self) -> Vec<RequestOption<'static>> {
         let mut res = vec![];
 
         if let Some(block_size) = self.block_size {
             res.push(RequestOption {
-                name: "blksize".to_string(),
-                value: block_size.to_string(),
+                name: "blksize".into(),
+                value: block_size.to_string().into(),
+            })
+        }
+
+        if let Some(timeout) = self.timeout {
+            res.push(RequestOption {
+                name: "timeout".into(),
+                value: timeout.to_string().into(),
             })
         }
 
         if let Some(transfer_size) = self.transfer_size {
             res.push(RequestOption {
-                name: "tsize".to_string(),
-                value: transfer_size.to_string(),
+                name: "tsize".into(),
+                value: transfer_size.to_string().into(),
+            })
+        }
+
+        if let Some(window_size) = self.window_size {
+            res.push(RequestOption {
+                name: "windowsize".into(),
+                value: window_size.to_string().into(),
+            })
+        }
+
+        if let Some(rollover) = self.rollover {
+            res.push(RequestOption {
+                name: "rollover".into(),
+                value: match rollover {
+                    Rollover::ToOne => "1",
+                    Rollover::ToZero | Rollover::Refuse => "0",
+                }
+                .into(),
             })
         }
 
@@ -51,32 +268,182 @@ impl AcceptedOptions {
     }
 }
 
+/// The state of a file transfer that survives from one window to the
+/// next.
+#[derive(Debug)]
+pub struct Transfer<F: File> {
+    file: F,
+    options: Options,
+
+    /// The filename from the client's request. Clients that don't
+    /// understand our OACK may ask for it again.
+    filename: PathBuf,
+
+    /// The block size for data packets.
+    block_size: u16,
+
+    /// How many data packets we send before we wait for an ACK. See
+    /// [RFC 7440](https://datatracker.ietf.org/doc/html/rfc7440).
+    window_size: u16,
+
+    /// What follows block 65535.
+    rollover: Rollover,
+
+    /// Estimates how long we wait for ACKs.
+    rtt: RttEstimator,
+
+    /// The timeout the client asked for. This replaces the estimate.
+    fixed_timeout: Option<Duration>,
+
+    /// When we sent the packets that we wait an ACK for. This is
+    /// `None` for retransmissions, which we don't measure.
+    sent_at: Option<Instant>,
+
+    /// When we last sent packets. Ignored packets don't extend the
+    /// timeout.
+    waiting_since: Instant,
+
+    /// How many ACKs we have ignored, because they didn't acknowledge
+    /// anything new.
+    duplicate_acks: u64,
+
+    /// Buffers of data packets that were already sent. We reuse them
+    /// for the next window.
+    spare_blocks: Vec<Vec<u8>>,
+
+    /// The packet list of the last response, so we can reuse it.
+    spare_packets: Vec<Packet>,
+
+    /// Our place in a multicast session. Data packets go to the
+    /// multicast group instead of the client.
+    multicast: Option<Membership>,
+}
+
+impl<F: File> Transfer<F> {
+    fn new(file: F, filename: &Path, options: Options, block_size: u16, window_size: u16) -> Self {
+        Self {
+            file,
+            filename: filename.to_owned(),
+            rollover: options.rollover,
+            rtt: RttEstimator::new(options.timeout, options.min_timeout, options.max_timeout),
+            fixed_timeout: None,
+            sent_at: None,
+            waiting_since: Instant::now(),
+            duplicate_acks: 0,
+            options,
+            block_size,
+            window_size,
+            spare_blocks: vec![],
+            spare_packets: vec![],
+            multicast: None,
+        }
+    }
+
+    /// Whether the client answered our OACK with `packet`, because it
+    /// doesn't understand it.
+    fn ignored_options(&self, packet: &Packet) -> bool {
+        match packet {
+            tftp::Packet::Ack { .. } => true,
+            tftp::Packet::Rrq {
+                filename, options, ..
+            } => options.is_empty() && filename.as_ref() == self.filename,
+            _ => false,
+        }
+    }
+
+    /// How long we wait for a response.
+    fn timeout(&self) -> Duration {
+        self.fixed_timeout.unwrap_or_else(|| self.rtt.rto())
+    }
+
+    /// How much of the timeout is left since we last sent packets.
+    fn remaining_timeout(&self) -> Duration {
+        self.timeout().saturating_sub(self.waiting_since.elapsed())
+    }
+
+    /// We are about to send packets and wait for the response.
+    fn start_round_trip(&mut self, retransmission: bool) {
+        let now = Instant::now();
+
+        self.sent_at = (!retransmission).then_some(now);
+        self.waiting_since = now;
+    }
+
+    /// The response to the packets we sent last has arrived.
+    fn finish_round_trip(&mut self) {
+        if let Some(sent_at) = self.sent_at.take() {
+            self.rtt.sample(sent_at.elapsed());
+        }
+    }
+
+    /// No response arrived in time.
+    fn timed_out(&mut self) {
+        self.sent_at = None;
+        self.rtt.back_off();
+    }
+
+    /// The number of the last block of the file. It is always shorter
+    /// than the block size and may be empty.
+    async fn final_block(&self) -> Result<u64, F::Error> {
+        Ok(self.file.size().await? / u64::from(self.block_size) + 1)
+    }
+
+    /// The size of the file, if it needs more blocks than there are
+    /// block numbers and we refuse to roll over.
+    async fn refused_rollover(&self) -> Option<u64> {
+        if self.rollover != Rollover::Refuse {
+            return None;
+        }
+
+        let size = self.file.size().await.ok()?;
+
+        (size / u64::from(self.block_size) + 1 > tftp::MAX_BLOCK_NUMBER).then_some(size)
+    }
+
+    /// Take back the buffers of packets that were sent.
+    fn recycle(&mut self, mut packets: Vec<Packet>) {
+        for packet in packets.drain(..) {
+            if let tftp::Packet::Data {
+                data: Cow::Owned(data),
+                ..
+            } = packet
+            {
+                self.spare_blocks.push(data);
+            }
+        }
+
+        self.spare_packets = packets;
+    }
+}
+
 /// The current state of the TFTP connection.
 #[derive(Debug)]
 pub enum Connection<FS: simple_fs::Filesystem> {
     /// The connection is terminated. No further packets are expected.
     Dead,
     /// We haven't seen an initial packet yet.
-    WaitingForInitialPacket { filesystem: FS, root: PathBuf },
+    WaitingForInitialPacket {
+        filesystem: FS,
+        root: PathBuf,
+        peer: Peer,
+        options: Options,
+    },
 
     /// We have sent an OACK packet and wait for the corresponding ACK with block 0.
     AcknowledgingOptions {
-        file: FS::File,
+        transfer: Transfer<FS::File>,
 
         /// How many timeout events have we received for this packet.
         timeout_events: u32,
 
         /// The list of options that we want to acknowledge.
-        acknowledged_options: Vec<RequestOption>,
-
-        /// The block size for data packets.
-        block_size: u16,
+        acknowledged_options: Vec<RequestOption<'static>>,
     },
 
     /// The client successfully requested a file and we have managed
     /// to open it. Now we are reading the contents.
     ReadingFile {
-        file: FS::File,
+        transfer: Transfer<FS::File>,
 
         /// The last block we acked. Note that this is not `u16` as
         /// the block number in TFTP packets, because otherwise we
@@ -86,149 +453,263 @@ pub enum Connection<FS: simple_fs::Filesystem> {
         /// How many timeout events have we received for the current block.
         timeout_events: u32,
 
-        /// We are waiting for the last ACK.
-        last_was_final: bool,
+        /// The number of the last block of the file, if we have
+        /// already sent it. Once this block is acknowledged, we are done.
+        final_block: Option<u64>,
+    },
 
-        /// The block size for data packets. This is negotiated via options when the connection is established.
-        block_size: u16,
+    /// The client receives data from a multicast session that another
+    /// client drives. We wait until it becomes the master client.
+    FollowingMulticast {
+        transfer: Transfer<FS::File>,
+
+        /// The options we acknowledged, so we can repeat the OACK.
+        acknowledged_options: Vec<RequestOption<'static>>,
+
+        /// How many timeouts passed since we last heard from the
+        /// client.
+        timeout_events: u32,
     },
 }
 
 impl<FS: simple_fs::Filesystem> Connection<FS> {
+    #[cfg(test)]
     pub fn new_with_filesystem(filesystem: FS, root: impl AsRef<Path>) -> Self {
+        Self::new(
+            filesystem,
+            root,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            // Fixed timeouts keep the responses predictable.
+            Options {
+                min_timeout: DEFAULT_TFTP_TIMEOUT,
+                max_timeout: DEFAULT_TFTP_TIMEOUT,
+                ..Options::default()
+            },
+        )
+    }
+
+    /// Create a connection for requests from `peer`.
+    pub fn new(
+        filesystem: FS,
+        root: impl AsRef<Path>,
+        peer: impl Into<Peer>,
+        options: Options,
+    ) -> Self {
         Self::WaitingForInitialPacket {
             filesystem,
             root: root.as_ref().to_path_buf(),
+            peer: peer.into(),
+            options,
         }
     }
 
-    async fn read_block(file: &mut FS::File, block: u64, block_size: u16) -> Result<Vec<u8>> {
+    /// Read a block into `buf`, replacing its contents.
+    async fn read_block(
+        file: &FS::File,
+        block: u64,
+        block_size: u16,
+        mut buf: Vec<u8>,
+    ) -> Result<Vec<u8>> {
         assert!(block >= 1);
 
-        let mut buf = vec![0; usize::from(block_size)];
+        buf.resize(usize::from(block_size), 0);
 
-        let size = file
-            .read((block - 1) * u64::try_from(block_size)?, &mut buf)
-            .await?;
-
-        Ok(buf[0..size].to_vec())
+        Ok(file
+            .read_owned((block - 1) * u64::from(block_size), buf)
+            .await?)
     }
 
+    /// Keep waiting for the ACK of the current window without sending
+    /// anything. Answering duplicate ACKs with data would double the
+    /// traffic with each retransmission (the Sorcerer's Apprentice
+    /// Syndrome, see RFC 1123 section 4.2.3.1), so only timeouts lead
+    /// to retransmissions.
     async fn ignore_packet(
-        file: FS::File,
+        transfer: Transfer<FS::File>,
         block: u64,
         timeouts: u32,
-        last_was_final: bool,
-        block_size: u16,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
+        final_block: Option<u64>,
+    ) -> Result<(Self, Response<Packet>)> {
+        let timeout = transfer.remaining_timeout();
+
         Ok((
             Self::ReadingFile {
-                file,
+                transfer,
                 last_acked_block: block,
                 timeout_events: timeouts,
-                last_was_final,
-                block_size,
+                final_block,
             },
             Response {
-                packet: None,
-                next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT),
+                packets: vec![],
+                next_status: ConnectionStatus::WaitingForPacket(timeout),
             },
         ))
     }
 
     /// Drop the connection without sending an error.
-    fn drop_connection() -> Result<(Self, Response<tftp::Packet>)> {
+    fn drop_connection() -> Result<(Self, Response<Packet>)> {
         Ok((
             Self::Dead,
             Response {
-                packet: None,
+                packets: vec![],
                 next_status: ConnectionStatus::Terminated,
             },
         ))
     }
 
-    fn drop_connection_with_error<S: Into<String>>(
+    fn drop_connection_with_error<S: Into<Cow<'static, str>>>(
         error_code: u16,
         error_msg: S,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
-        let error_msg: String = error_msg.into();
+    ) -> Result<(Self, Response<Packet>)> {
+        let error_msg = error_msg.into();
 
         warn!("Sending error to client: {error_code} {error_msg}");
 
         Ok((
             Self::Dead,
             Response {
-                packet: Some(tftp::Packet::Error {
+                packets: vec![tftp::Packet::Error {
                     error_code,
                     error_msg,
-                }),
+                }],
                 next_status: ConnectionStatus::Terminated,
             },
         ))
     }
 
-    async fn send_block(
-        mut file: FS::File,
-        block: u64,
+    /// Send a window of blocks starting with `first_block`. The
+    /// window ends early at the end of the file.
+    async fn send_window(
+        mut transfer: Transfer<FS::File>,
+        first_block: u64,
         timeouts: u32,
-        block_size: u16,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
-        assert!(block > 0);
-        assert!(block_size > 0);
+    ) -> Result<(Self, Response<Packet>)> {
+        assert!(first_block > 0);
+        assert!(transfer.block_size > 0);
+        assert!(transfer.window_size > 0);
+
+        let mut packets = std::mem::take(&mut transfer.spare_packets);
+        let mut final_block = None;
+
+        for block in first_block..first_block + u64::from(transfer.window_size) {
+            let buf = transfer.spare_blocks.pop().unwrap_or_default();
+            let data = Self::read_block(&transfer.file, block, transfer.block_size, buf).await?;
+            assert!(data.len() <= usize::from(transfer.block_size));
+
+            let is_final = data.len() < usize::from(transfer.block_size);
+            let Some(block_number) = transfer.rollover.block_number(block) else {
+                // The file is at least this large.
+                return Self::refuse_rollover((block - 1) * u64::from(transfer.block_size));
+            };
+
+            packets.push(tftp::Packet::Data {
+                block: block_number,
+                data: Cow::Owned(data),
+            });
+
+            if is_final {
+                final_block = Some(block);
+                break;
+            }
+        }
 
-        let data = Self::read_block(&mut file, block, block_size).await?;
-        assert!(data.len() <= usize::from(block_size));
+        transfer.start_round_trip(timeouts > 0);
+        let timeout = transfer.timeout();
 
         Ok((
             Self::ReadingFile {
-                file,
-                last_acked_block: block - 1,
+                transfer,
+                last_acked_block: first_block - 1,
                 timeout_events: timeouts,
-                last_was_final: data.len() < usize::from(block_size),
-                block_size,
+                final_block,
             },
             Response {
-                packet: Some(tftp::Packet::Data {
-                    block: u16::try_from(block & 0xffff).unwrap(),
-                    data,
-                }),
-                next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT),
+                packets,
+                next_status: ConnectionStatus::WaitingForPacket(timeout),
             },
         ))
     }
 
+    /// Fail the transfer of a file of `size` bytes that needs a
+    /// rollover we refuse to do.
+    fn refuse_rollover(size: u64) -> Result<(Self, Response<Packet>)> {
+        // The last block is always shorter than the block size, so
+        // this is the smallest block size with at most 65535 blocks.
+        let min_block_size = size / tftp::MAX_BLOCK_NUMBER + 1;
+
+        Self::drop_connection_with_error(
+            tftp::error::UNDEFINED,
+            format!(
+                "File needs more than {} blocks, use a blksize of at least {min_block_size}",
+                tftp::MAX_BLOCK_NUMBER
+            ),
+        )
+    }
+
+    /// Send the file as if the client hadn't asked for any options.
+    async fn restart_without_options(
+        mut transfer: Transfer<FS::File>,
+    ) -> Result<(Self, Response<Packet>)> {
+        info!("Restarting transfer without options.");
+
+        transfer.block_size = DEFAULT_TFTP_BLKSIZE;
+        transfer.window_size = DEFAULT_TFTP_WINDOWSIZE;
+        transfer.rollover = transfer.options.rollover;
+        transfer.fixed_timeout = None;
+        transfer.multicast = None;
+
+        if let Some(size) = transfer.refused_rollover().await {
+            return Self::refuse_rollover(size);
+        }
+
+        Self::send_window(transfer, 1, 0).await
+    }
+
     async fn acknowledge_options(
-        file: FS::File,
-        acknowledged_options: Vec<RequestOption>,
+        mut transfer: Transfer<FS::File>,
+        acknowledged_options: Vec<RequestOption<'static>>,
         timeout_events: u32,
-        block_size: u16,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
+    ) -> Result<(Self, Response<Packet>)> {
+        transfer.start_round_trip(timeout_events > 0);
+        let timeout = transfer.timeout();
+
         Ok((
             Self::AcknowledgingOptions {
-                file,
+                transfer,
                 acknowledged_options: acknowledged_options.clone(),
-                block_size,
                 timeout_events,
             },
             Response {
-                packet: Some(tftp::Packet::OAck {
+                packets: vec![tftp::Packet::OAck {
                     options: acknowledged_options,
-                }),
-                next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT),
+                }],
+                next_status: ConnectionStatus::WaitingForPacket(timeout),
             },
         ))
     }
 
     /// Take the client's proposed options and see what is useful for us.
-    async fn accept_options(file: &FS::File, options: &[RequestOption]) -> AcceptedOptions {
+    async fn accept_options(
+        file: &FS::File,
+        max_block_size: u16,
+        max_window_size: u16,
+        options: &[RequestOption<'_>],
+    ) -> AcceptedOptions {
         let mut block_size: Option<u16> = None;
         let mut transfer_size: Option<u64> = None;
+        let mut window_size: Option<u16> = None;
+        let mut timeout: Option<u8> = None;
+        let mut multicast = false;
+        let mut rollover: Option<Rollover> = None;
 
         for option in options {
             if option.name.eq_ignore_ascii_case("blksize") {
                 match option.value.parse::<u16>() {
-                    Ok(parsed_block_size) if (8..=65464).contains(&parsed_block_size) => {
-                        block_size = Some(parsed_block_size);
+                    Ok(parsed_block_size)
+                        if (8..=MAX_TFTP_BLKSIZE).contains(&parsed_block_size) =>
+                    {
+                        // RFC 2348 lets us answer with a smaller size.
+                        block_size = Some(parsed_block_size.min(max_block_size));
                     }
                     _ => {
                         warn!("Ignoring invalid block size: {}", option.value);
@@ -241,6 +722,33 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
                         error!("Failed to query size of file, ignoring TSIZE option: {e}");
                     }
                 }
+            } else if option.name.eq_ignore_ascii_case("windowsize") {
+                match option.value.parse::<u16>() {
+                    Ok(parsed_window_size) if parsed_window_size >= 1 => {
+                        window_size = Some(parsed_window_size.min(max_window_size));
+                    }
+                    _ => {
+                        warn!("Ignoring invalid window size: {}", option.value);
+                    }
+                }
+            } else if option.name.eq_ignore_ascii_case("timeout") {
+                match option.value.parse::<u8>() {
+                    Ok(parsed_timeout) if parsed_timeout >= 1 => timeout = Some(parsed_timeout),
+                    _ => {
+                        warn!("Ignoring invalid timeout: {}", option.value);
+                    }
+                }
+            } else if option.name.eq_ignore_ascii_case("rollover") {
+                match Rollover::from_option(&option.value) {
+                    Some(parsed_rollover) => rollover = Some(parsed_rollover),
+                    None => warn!("Ignoring invalid rollover: {}", option.value),
+                }
+            } else if option.name.eq_ignore_ascii_case("multicast") {
+                if option.value.is_empty() {
+                    multicast = true;
+                } else {
+                    warn!("Ignoring invalid multicast option: {}", option.value);
+                }
             } else {
                 debug!("Ignoring unknown option {}={}", option.name, option.value);
             }
@@ -249,35 +757,254 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
         AcceptedOptions {
             block_size,
             transfer_size,
+            window_size,
+            timeout,
+            multicast,
+            rollover,
+        }
+    }
+
+    /// Open the file at `path`, which must be normalized. If `path` is
+    /// part of a PXELINUX-style lookup chain, the first file of the
+    /// chain that exists is opened instead.
+    async fn open_file(
+        filesystem: &FS,
+        root: &Path,
+        server_options: &Options,
+        peer: &Peer,
+        host: Option<&Host>,
+        path: &Path,
+    ) -> Result<FS::File, FS::Error> {
+        let candidates = match &server_options.pxe_lookup {
+            Some(lookup) => lookup.candidates(path, peer, host).await,
+            None => None,
+        };
+        let Some(candidates) = candidates else {
+            return filesystem.open_for(&root.join(path), peer).await;
+        };
+
+        let mut last_error = None;
+
+        for candidate in candidates {
+            match filesystem.open_for(&root.join(&candidate), peer).await {
+                Ok(file) => {
+                    info!(
+                        "{peer}: Lookup of {} resolved to {}",
+                        path.display(),
+                        candidate.display()
+                    );
+                    return Ok(file);
+                }
+                Err(e) => {
+                    debug!("{peer}: Lookup candidate {}: {e}", candidate.display());
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.expect("Lookup chains are never empty"))
+    }
+
+    /// Join the multicast session for the file of `transfer`, if we
+    /// offer multicast.
+    async fn join_multicast(
+        transfer: &Transfer<FS::File>,
+        local_path: &Path,
+        peer: SocketAddr,
+    ) -> Option<Membership> {
+        let groups = transfer.options.multicast.as_ref()?;
+
+        // Sessions are keyed by path, which doesn't identify the
+        // contents of such files.
+        if transfer.file.is_per_client() {
+            debug!(
+                "{peer}: Not offering multicast for {}, which was made for this client",
+                local_path.display()
+            );
+            return None;
+        }
+
+        let size = match transfer.file.size().await {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Failed to query size of file, ignoring multicast option: {e}");
+                return None;
+            }
+        };
+
+        groups.join(
+            SessionKey {
+                path: local_path.to_owned(),
+                size,
+                block_size: transfer.block_size,
+            },
+            peer,
+        )
+    }
+
+    /// Apply the architecture rules to a normalized path.
+    async fn select_for_architecture<'a>(
+        server_options: &'a Options,
+        peer: &Peer,
+        host: Option<&Host>,
+        path: &'a Path,
+    ) -> &'a Path {
+        let (Some(rules), Some(arch_table)) = (
+            server_options.arch_rules.get(path),
+            &server_options.arch_table,
+        ) else {
+            return path;
+        };
+
+        let Some(arch) = arch_table.lookup(peer, host).await else {
+            debug!(
+                "{peer}: Architecture unknown, serving {} as requested",
+                path.display()
+            );
+            return path;
+        };
+
+        match rules.get(&arch) {
+            Some(selected) => {
+                info!(
+                    "{peer}: Selected {} for {arch} client instead of {}",
+                    selected.display(),
+                    path.display()
+                );
+                selected
+            }
+            None => path,
+        }
+    }
+
+    /// The directory that `host` is served from.
+    fn host_root(root: &Path, server_options: &Options, host: Option<&Host>) -> PathBuf {
+        let Some((group, group_root)) = host
+            .and_then(|host| host.group.as_ref())
+            .and_then(|group| Some((group, server_options.group_roots.get(group)?)))
+        else {
+            return root.to_owned();
+        };
+
+        match normalize(group_root) {
+            Some(group_root) => root.join(group_root),
+            None => {
+                warn!(
+                    "Ignoring root directory of group {group}: {}",
+                    group_root.display()
+                );
+                root.to_owned()
+            }
         }
     }
 
     async fn handle_initial_read(
         filesystem: FS,
         root: &Path,
+        peer: &Peer,
+        mut server_options: Options,
         path: &Path,
-        options: &[RequestOption],
-    ) -> Result<(Self, Response<tftp::Packet>)> {
-        let local_path = root.join(
-            normalize(path)
-                .ok_or_else(|| anyhow!("Failed to normalize path: {}", path.display()))?,
-        );
+        options: &[RequestOption<'_>],
+    ) -> Result<(Self, Response<Packet>)> {
+        let quirk_options;
+        let options = match server_options
+            .quirks
+            .clone()
+            .and_then(|quirks| quirks.fixes(peer.ip(), options))
+        {
+            Some(fixes) => {
+                quirk_options = fixes.apply(&mut server_options, options);
+                &quirk_options
+            }
+            None => options,
+        };
 
-        info!("TFTP READ {} -> {}", path.display(), local_path.display());
+        let normalized_path = normalize(path)
+            .ok_or_else(|| anyhow!("Failed to normalize path: {}", path.display()))?;
+        let host = match &server_options.inventory {
+            Some(inventory) => inventory.lookup(peer).await,
+            None => None,
+        };
+        let root = Self::host_root(root, &server_options, host.as_deref());
+        let normalized_path =
+            Self::select_for_architecture(&server_options, peer, host.as_deref(), &normalized_path)
+                .await
+                .to_owned();
+        let local_path = root.join(&normalized_path);
+
+        match host.as_ref().and_then(|host| host.hostname.as_ref()) {
+            Some(hostname) => info!(
+                "TFTP READ {} -> {} for {hostname}",
+                path.display(),
+                local_path.display()
+            ),
+            None => info!("TFTP READ {} -> {}", path.display(), local_path.display()),
+        }
 
-        match filesystem.open(&local_path).await {
+        match Self::open_file(
+            &filesystem,
+            &root,
+            &server_options,
+            peer,
+            host.as_deref(),
+            &normalized_path,
+        )
+        .await
+        {
             Ok(file) => {
-                let accepted_options = Self::accept_options(&file, options).await;
+                let accepted_options = Self::accept_options(
+                    &file,
+                    server_options.max_block_size,
+                    server_options.max_window_size,
+                    options,
+                )
+                .await;
+
+                let mut transfer = Transfer::new(
+                    file,
+                    path,
+                    server_options,
+                    accepted_options.block_size.unwrap_or(DEFAULT_TFTP_BLKSIZE),
+                    accepted_options
+                        .window_size
+                        .unwrap_or(DEFAULT_TFTP_WINDOWSIZE),
+                );
+                let mut option_vec = accepted_options.to_option_vec();
+
+                if let Some(timeout) = accepted_options.timeout {
+                    transfer.fixed_timeout = Some(Duration::from_secs(timeout.into()));
+                }
+
+                if let Some(rollover) = accepted_options.rollover {
+                    transfer.rollover = rollover;
+                }
+
+                if let Some(size) = transfer.refused_rollover().await {
+                    return Self::refuse_rollover(size);
+                }
+
+                if accepted_options.multicast {
+                    transfer.multicast =
+                        Self::join_multicast(&transfer, &local_path, peer.addr()).await;
+                }
+
+                if let Some(membership) = &transfer.multicast {
+                    let master = membership.is_master();
 
-                let block_size = accepted_options.block_size.unwrap_or(DEFAULT_TFTP_BLKSIZE);
-                let option_vec = accepted_options.to_option_vec();
+                    option_vec.push(multicast_option(membership.group(), master));
+
+                    if !master {
+                        debug!("Accepted these options: {option_vec:?}");
+                        return Self::follow_multicast(transfer, option_vec, 0);
+                    }
+                }
 
                 debug!("Accepted these options: {option_vec:?}");
 
                 if option_vec.is_empty() {
-                    Self::send_block(file, 1, 0, block_size).await
+                    Self::send_window(transfer, 1, 0).await
                 } else {
-                    Self::acknowledge_options(file, option_vec, 0, block_size).await
+                    Self::acknowledge_options(transfer, option_vec, 0).await
                 }
             }
             Err(err) => Self::drop_connection_with_error(
@@ -290,15 +1017,27 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
     async fn handle_initial_event(
         filesystem: FS,
         root: &Path,
-        event: Event<tftp::Packet>,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
+        peer: &Peer,
+        server_options: Options,
+        event: Event<Packet>,
+    ) -> Result<(Self, Response<Packet>)> {
         match event {
             Event::PacketReceived(p) => match p {
                 tftp::Packet::Rrq {
                     filename,
                     mode: _,
                     options,
-                } => Self::handle_initial_read(filesystem, root, &filename, &options).await,
+                } => {
+                    Self::handle_initial_read(
+                        filesystem,
+                        root,
+                        peer,
+                        server_options,
+                        &filename,
+                        &options,
+                    )
+                    .await
+                }
                 tftp::Packet::Wrq { .. } => Self::drop_connection_with_error(
                     tftp::error::ACCESS_VIOLATION,
                     "This server only supports reading files",
@@ -312,80 +1051,290 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
         }
     }
 
-    async fn handle_option_acknowledgement(
-        file: FS::File,
+    /// Send the OACK to a client that is not the master client of its
+    /// multicast session. It only listens until it becomes master.
+    fn follow_multicast(
+        transfer: Transfer<FS::File>,
+        acknowledged_options: Vec<RequestOption<'static>>,
         timeout_events: u32,
-        acknowledged_options: Vec<RequestOption>,
-        block_size: u16,
-        event: Event<tftp::Packet>,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
-        match event {
-            Event::PacketReceived(p) => match p {
-                tftp::Packet::Ack { block: 0 } => Self::send_block(file, 1, 0, block_size).await,
-                tftp::Packet::Error {
-                    error_code,
-                    error_msg,
-                } => {
-                    warn!("Client declined options: {error_code} {error_msg}");
-                    Self::drop_connection()
-                }
-                _ => Self::drop_connection_with_error(
-                    tftp::error::ILLEGAL_OPERATION,
-                    "Expected ACK 0 as OACK response",
-                ),
+    ) -> Result<(Self, Response<Packet>)> {
+        let timeout = transfer.timeout();
+
+        Ok((
+            Self::FollowingMulticast {
+                transfer,
+                acknowledged_options: acknowledged_options.clone(),
+                timeout_events,
+            },
+            Response {
+                packets: vec![tftp::Packet::OAck {
+                    options: acknowledged_options,
+                }],
+                next_status: ConnectionStatus::WaitingForPacket(timeout),
             },
+        ))
+    }
+
+    /// Clients that don't drive the session are mostly silent. We
+    /// repeat the OACK when they don't become master in time and give
+    /// up on them like on any other client, so clients that vanish
+    /// don't keep their place in the session.
+    async fn handle_following_multicast_event(
+        mut transfer: Transfer<FS::File>,
+        acknowledged_options: Vec<RequestOption<'static>>,
+        timeout_events: u32,
+        event: Event<Packet>,
+    ) -> Result<(Self, Response<Packet>)> {
+        let timeout = transfer.timeout();
+        let membership = transfer
+            .multicast
+            .as_ref()
+            .expect("Only multicast transfers follow a session");
+
+        match event {
+            Event::Timeout if membership.is_master() => {
+                debug!("Making client the master of {}.", membership.group());
+
+                // The new master answers with an ACK for the last block
+                // it has received in order.
+                let options = vec![multicast_option(membership.group(), true)];
+                Self::acknowledge_options(transfer, options, 0).await
+            }
             Event::Timeout => {
-                if timeout_events >= MAX_RETRANSMISSIONS {
-                    warn!("Client timed out sending first ACK.");
+                let timeout_events = timeout_events + 1;
+
+                if timeout_events > transfer.options.max_retransmissions {
+                    warn!("Client timed out waiting for multicast session.");
                     Self::drop_connection()
                 } else {
-                    debug!("Timeout waiting for ACK for options, resending...",);
-
-                    Self::acknowledge_options(
-                        file,
-                        acknowledged_options,
-                        timeout_events,
-                        block_size,
-                    )
-                    .await
+                    transfer.timed_out();
+                    Self::follow_multicast(transfer, acknowledged_options, timeout_events)
                 }
             }
+            // Only the master client is supposed to ACK, but at least
+            // the client is still there.
+            Event::PacketReceived(tftp::Packet::Ack { .. }) => Ok((
+                Self::FollowingMulticast {
+                    transfer,
+                    acknowledged_options,
+                    timeout_events: 0,
+                },
+                Response {
+                    packets: vec![],
+                    next_status: ConnectionStatus::WaitingForPacket(timeout),
+                },
+            )),
+            Event::PacketReceived(tftp::Packet::Error {
+                error_code,
+                error_msg,
+            }) => {
+                warn!("Client sent error: {error_code} {error_msg}");
+                Self::drop_connection()
+            }
+            Event::PacketReceived(_) => Self::drop_connection_with_error(
+                tftp::error::ILLEGAL_OPERATION,
+                "Received unexpected packet. Closing connection.",
+            ),
         }
     }
 
-    async fn handle_reading_file_event(
-        file: FS::File,
-        mut last_acked_block: u64,
-        mut timeouts: u32,
-        last_was_final: bool,
-        block_size: u16,
-        event: Event<tftp::Packet>,
-    ) -> Result<(Self, Response<tftp::Packet>)> {
-        match event {
-            Event::PacketReceived(packet) => match packet {
-                tftp::Packet::Ack { block } => {
-                    let expected_block = last_acked_block + 1;
+    /// Find out which block a multicast master client means with an
+    /// ACK for `block`. Block numbers wrap around, so we have to guess:
+    /// A new master usually has everything after the blocks it asked
+    /// for, so the number of the final block means just that. Other
+    /// ACKs are taken as progress if they are at most half the number
+    /// space ahead of `last_acked_block`, like serial numbers in RFC
+    /// 1982. Returns `None` for stale ACKs.
+    fn multicast_acked_block(
+        last_acked_block: Option<u64>,
+        final_block: u64,
+        rollover: Rollover,
+        block: u16,
+    ) -> Option<u64> {
+        let matches = |b: &u64| rollover.block_number(*b) == Some(block);
+
+        // Clients that have nothing yet ACK block 0.
+        if last_acked_block.is_none() && matches(&0) {
+            return Some(0);
+        }
 
-                    debug!("Client acknowledged block {block:#x}, we expect {expected_block:#x}.");
+        if matches(&final_block) {
+            return Some(final_block);
+        }
 
-                    if u64::from(block) == expected_block & 0xffff {
-                        timeouts = 0;
-                        last_acked_block += 1;
+        match last_acked_block {
+            None => (1..final_block).find(matches),
+            Some(last_acked_block) => {
+                (last_acked_block + 1..final_block.min(last_acked_block + (1 << 15))).find(matches)
+            }
+        }
+    }
 
-                        if last_was_final {
-                            debug!("Successfully sent {last_acked_block} blocks.");
-                            return Self::drop_connection();
-                        }
+    /// Continue a multicast session after `block`, which the master
+    /// client has received.
+    async fn resume_multicast(
+        transfer: Transfer<FS::File>,
+        block: u64,
+        final_block: u64,
+    ) -> Result<(Self, Response<Packet>)> {
+        if block >= final_block {
+            debug!("Client already has all {final_block} blocks.");
+            Self::drop_connection()
+        } else {
+            Self::send_window(transfer, block + 1, 0).await
+        }
+    }
+
+    async fn handle_option_acknowledgement(
+        mut transfer: Transfer<FS::File>,
+        timeout_events: u32,
+        acknowledged_options: Vec<RequestOption<'static>>,
+        event: Event<Packet>,
+    ) -> Result<(Self, Response<Packet>)> {
+        if let Event::PacketReceived(tftp::Packet::Ack { .. }) = event {
+            transfer.finish_round_trip();
+        }
+
+        match event {
+            Event::PacketReceived(p) => match p {
+                tftp::Packet::Ack { block } if transfer.multicast.is_some() => {
+                    let final_block = transfer.final_block().await?;
+
+                    match Self::multicast_acked_block(None, final_block, transfer.rollover, block) {
+                        Some(block) => Self::resume_multicast(transfer, block, final_block).await,
+                        None => Self::drop_connection_with_error(
+                            tftp::error::ILLEGAL_OPERATION,
+                            "Acknowledged a block beyond the end of the file",
+                        ),
+                    }
+                }
+                tftp::Packet::Ack { block: 0 } => Self::send_window(transfer, 1, 0).await,
+                tftp::Packet::Error {
+                    error_code,
+                    error_msg,
+                } => {
+                    warn!(
+                        "Client declined options {}: {error_code} {error_msg}",
+                        format_options(&acknowledged_options)
+                    );
+
+                    if transfer.options.restart_without_options {
+                        Self::restart_without_options(transfer).await
                     } else {
-                        debug!("Unexpected ACK. Ignoring.");
-                        return Self::ignore_packet(
-                            file,
-                            last_acked_block,
-                            timeouts,
-                            last_was_final,
-                            block_size,
-                        )
-                        .await;
+                        Self::drop_connection()
+                    }
+                }
+                // Some old clients don't understand the OACK and answer
+                // with a stray ACK or the same request without options
+                // instead of an error.
+                p if transfer.options.restart_without_options && transfer.ignored_options(&p) => {
+                    warn!(
+                        "Client ignored options {}: {p:?}",
+                        format_options(&acknowledged_options)
+                    );
+                    Self::restart_without_options(transfer).await
+                }
+                _ => Self::drop_connection_with_error(
+                    tftp::error::ILLEGAL_OPERATION,
+                    "Expected ACK 0 as OACK response",
+                ),
+            },
+            Event::Timeout => {
+                let timeout_events = timeout_events + 1;
+
+                if timeout_events > transfer.options.max_retransmissions {
+                    warn!("Client timed out sending first ACK.");
+                    Self::drop_connection()
+                } else {
+                    debug!("Timeout waiting for ACK for options, resending...",);
+
+                    transfer.timed_out();
+                    Self::acknowledge_options(transfer, acknowledged_options, timeout_events).await
+                }
+            }
+        }
+    }
+
+    /// Figure out how many blocks of the current window an ACK for
+    /// `block` acknowledges. Returns `None`, if the ACK doesn't refer
+    /// to a block in the current window.
+    fn acknowledged_blocks(
+        last_acked_block: u64,
+        window_size: u16,
+        final_block: Option<u64>,
+        rollover: Rollover,
+        block: u16,
+    ) -> Option<u64> {
+        let window_end = last_acked_block + u64::from(window_size);
+        let window_end = final_block.map_or(window_end, |f| window_end.min(f));
+
+        (last_acked_block + 1..=window_end)
+            .find(|b| rollover.block_number(*b) == Some(block))
+            .map(|b| b - last_acked_block)
+    }
+
+    async fn handle_reading_file_event(
+        mut transfer: Transfer<FS::File>,
+        mut last_acked_block: u64,
+        mut timeouts: u32,
+        final_block: Option<u64>,
+        event: Event<Packet>,
+    ) -> Result<(Self, Response<Packet>)> {
+        match event {
+            Event::PacketReceived(packet) => match packet {
+                tftp::Packet::Ack { block } => {
+                    debug!(
+                        "Client acknowledged block {block:#x}, we expect {:#x}.",
+                        last_acked_block + 1
+                    );
+
+                    match Self::acknowledged_blocks(
+                        last_acked_block,
+                        transfer.window_size,
+                        final_block,
+                        transfer.rollover,
+                        block,
+                    ) {
+                        Some(blocks) => {
+                            transfer.finish_round_trip();
+                            timeouts = 0;
+                            last_acked_block += blocks;
+
+                            if Some(last_acked_block) == final_block {
+                                debug!(
+                                    "Successfully sent {last_acked_block} blocks, ignored {} duplicate ACKs.",
+                                    transfer.duplicate_acks
+                                );
+                                return Self::drop_connection();
+                            }
+                        }
+                        None => {
+                            // A new multicast master may already have
+                            // later blocks from the group.
+                            if transfer.multicast.is_some() {
+                                let final_block = transfer.final_block().await?;
+
+                                if let Some(block) = Self::multicast_acked_block(
+                                    Some(last_acked_block),
+                                    final_block,
+                                    transfer.rollover,
+                                    block,
+                                ) {
+                                    return Self::resume_multicast(transfer, block, final_block)
+                                        .await;
+                                }
+                            }
+
+                            debug!("Unexpected ACK. Ignoring.");
+                            transfer.duplicate_acks += 1;
+                            return Self::ignore_packet(
+                                transfer,
+                                last_acked_block,
+                                timeouts,
+                                final_block,
+                            )
+                            .await;
+                        }
                     }
                 }
                 tftp::Packet::Error {
@@ -405,10 +1354,11 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
             Event::Timeout => {
                 timeouts += 1;
 
-                if timeouts > MAX_RETRANSMISSIONS {
+                if timeouts > transfer.options.max_retransmissions {
                     warn!("Client timed out sending ACKs.");
                     return Self::drop_connection();
                 } else {
+                    transfer.timed_out();
                     debug!(
                         "Timeout waiting for ACK for block {:x}, resending...",
                         last_acked_block + 1
@@ -418,61 +1368,69 @@ impl<FS: simple_fs::Filesystem> Connection<FS> {
         }
 
         debug!("Sending block {:x}.", last_acked_block + 1);
-        Self::send_block(file, last_acked_block + 1, timeouts, block_size).await
-    }
-}
-
-impl Connection<simple_fs::AsyncFilesystem> {
-    pub fn new(root: impl AsRef<Path>) -> Self {
-        Self::new_with_filesystem(simple_fs::AsyncFilesystem::default(), root)
+        Self::send_window(transfer, last_acked_block + 1, timeouts).await
     }
 }
 
 #[async_trait]
 impl<FS: simple_fs::Filesystem> simple_proto::SimpleUdpProtocol for Connection<FS> {
-    type Packet = tftp::Packet;
+    type Packet = Packet;
     type Error = anyhow::Error;
 
     async fn handle_event(
         &mut self,
         event: Event<Self::Packet>,
     ) -> Result<simple_proto::Response<Self::Packet>, Self::Error> {
-        let (new_self, response) = match self {
+        // Take the state out of the connection. If we fail, the
+        // connection stays dead.
+        let (new_self, response) = match std::mem::replace(self, Self::Dead) {
             Self::Dead => panic!(
                 "Should not receive events on a dead connection: {:?}",
                 event
             ),
-            Self::WaitingForInitialPacket { filesystem, root } => {
-                Self::handle_initial_event(filesystem.clone(), root, event).await?
-            }
+            Self::WaitingForInitialPacket {
+                filesystem,
+                root,
+                peer,
+                options,
+            } => Self::handle_initial_event(filesystem, &root, &peer, options, event).await?,
             Self::AcknowledgingOptions {
-                file,
+                transfer,
                 timeout_events,
                 acknowledged_options,
-                block_size,
             } => {
                 Self::handle_option_acknowledgement(
-                    file.clone(),
-                    *timeout_events,
-                    acknowledged_options.clone(),
-                    *block_size,
+                    transfer,
+                    timeout_events,
+                    acknowledged_options,
                     event,
                 )
                 .await?
             }
             Self::ReadingFile {
-                file,
+                transfer,
                 last_acked_block,
                 timeout_events,
-                last_was_final,
-                block_size,
+                final_block,
             } => {
                 Self::handle_reading_file_event(
-                    file.clone(),
-                    *last_acked_block,
-                    *timeout_events,
-                    *last_was_final,
-                    *block_size,
+                    transfer,
+                    last_acked_block,
+                    timeout_events,
+                    final_block,
+                    event,
+                )
+                .await?
+            }
+            Self::FollowingMulticast {
+                transfer,
+                acknowledged_options,
+                timeout_events,
+            } => {
+                Self::handle_following_multicast_event(
+                    transfer,
+                    acknowledged_options,
+                    timeout_events,
                     event,
                 )
                 .await?
@@ -482,6 +1440,25 @@ impl<FS: simple_fs::Filesystem> simple_proto::SimpleUdpProtocol for Connection<F
         *self = new_self;
         Ok(response)
     }
+
+    fn recycle(&mut self, response: Response<Self::Packet>) {
+        match self {
+            Self::AcknowledgingOptions { transfer, .. }
+            | Self::ReadingFile { transfer, .. }
+            | Self::FollowingMulticast { transfer, .. } => transfer.recycle(response.packets),
+            Self::Dead | Self::WaitingForInitialPacket { .. } => (),
+        }
+    }
+
+    fn destination(&self) -> Option<SocketAddr> {
+        match self {
+            Self::ReadingFile { transfer, .. } => transfer
+                .multicast
+                .as_ref()
+                .map(|membership| membership.group().into()),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -492,6 +1469,41 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn checks_request_options() {
+        let options = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|&(name, value)| RequestOption {
+                    name: name.into(),
+                    value: value.into(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(check_request_options(&options(&[])), Ok(()));
+        assert_eq!(
+            check_request_options(&options(&[("blksize", "1468"), ("tsize", "0")])),
+            Ok(())
+        );
+        assert_eq!(
+            check_request_options(&options(&[("blksize", "1468"), ("", ""), ("", "")])),
+            Ok(())
+        );
+        assert_eq!(
+            check_request_options(&options(&[("", ""), ("blksize", "1468")])),
+            Err("Empty option name")
+        );
+        assert_eq!(
+            check_request_options(&options(&[("blksize", "1468"), ("", "1")])),
+            Err("Empty option name")
+        );
+        assert_eq!(
+            check_request_options(&options(&[("blksize", "1468"), ("BLKSIZE", "512")])),
+            Err("Duplicate option")
+        );
+    }
+
     #[tokio::test]
     async fn simple_read() {
         let mut file_contents = [0xab_u8; 513].to_vec();
@@ -508,17 +1520,17 @@ mod tests {
 
         assert_eq!(
             con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
-                filename: PathBuf::from("/foo"),
+                filename: Path::new("/foo").into(),
                 mode: tftp::RequestMode::Octet,
                 options: vec![]
             }))
             .await
             .unwrap(),
             Response {
-                packet: Some(tftp::Packet::Data {
+                packets: vec![tftp::Packet::Data {
                     block: 1,
-                    data: file_contents[0..512].to_vec()
-                }),
+                    data: file_contents[0..512].to_vec().into()
+                }],
                 next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT)
             }
         );
@@ -528,10 +1540,10 @@ mod tests {
                 .await
                 .unwrap(),
             Response {
-                packet: Some(tftp::Packet::Data {
+                packets: vec![tftp::Packet::Data {
                     block: 2,
-                    data: file_contents[512..].to_vec()
-                }),
+                    data: file_contents[512..].to_vec().into()
+                }],
                 next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT)
             }
         );
@@ -553,26 +1565,26 @@ mod tests {
 
         assert_eq!(
             con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
-                filename: PathBuf::from("/foo"),
+                filename: Path::new("/foo").into(),
                 mode: tftp::RequestMode::Octet,
                 options: vec![
                     (RequestOption {
-                        name: "blksize".to_string(),
-                        value: "10".to_string(),
+                        name: "blksize".into(),
+                        value: "10".into(),
                     })
                 ]
             }))
             .await
             .unwrap(),
             Response {
-                packet: Some(tftp::Packet::OAck {
+                packets: vec![tftp::Packet::OAck {
                     options: vec![
                         (RequestOption {
-                            name: "blksize".to_string(),
-                            value: "10".to_string(),
+                            name: "blksize".into(),
+                            value: "10".into(),
                         })
                     ]
-                }),
+                }],
                 next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT)
             }
         );
@@ -582,15 +1594,60 @@ mod tests {
                 .await
                 .unwrap(),
             Response {
-                packet: Some(tftp::Packet::Data {
+                packets: vec![tftp::Packet::Data {
                     block: 1,
-                    data: file_contents[0..10].to_vec()
-                }),
+                    data: file_contents[0..10].to_vec().into()
+                }],
                 next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT)
             }
         );
     }
 
+    #[tokio::test]
+    async fn limits_block_size() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0xab; 2000])]);
+        let mut con = Connection::new(
+            fs,
+            "/",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Options {
+                max_block_size: 1024,
+                ..Options::default()
+            },
+        );
+
+        assert_eq!(
+            con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![RequestOption {
+                    name: "blksize".into(),
+                    value: "1428".into(),
+                }]
+            }))
+            .await
+            .unwrap()
+            .packets,
+            [tftp::Packet::OAck {
+                options: vec![RequestOption {
+                    name: "blksize".into(),
+                    value: "1024".into(),
+                }]
+            }]
+        );
+
+        assert_eq!(
+            con.handle_event(Event::PacketReceived(tftp::Packet::Ack { block: 0 }))
+                .await
+                .unwrap()
+                .packets,
+            [tftp::Packet::Data {
+                block: 1,
+                data: vec![0xab; 1024].into()
+            }]
+        );
+    }
+
     #[tokio::test]
     async fn query_file_size() {
         let file_contents = [0xab_u8; 513].to_vec();
@@ -603,28 +1660,1119 @@ mod tests {
 
         assert_eq!(
             con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
-                filename: PathBuf::from("/foo"),
+                filename: Path::new("/foo").into(),
                 mode: tftp::RequestMode::Octet,
                 options: vec![
                     (RequestOption {
-                        name: "tsize".to_string(),
-                        value: "0".to_string(),
+                        name: "tsize".into(),
+                        value: "0".into(),
                     })
                 ]
             }))
             .await
             .unwrap(),
             Response {
-                packet: Some(tftp::Packet::OAck {
+                packets: vec![tftp::Packet::OAck {
                     options: vec![
                         (RequestOption {
-                            name: "tsize".to_string(),
-                            value: "513".to_string(),
+                            name: "tsize".into(),
+                            value: "513".into(),
                         })
                     ]
-                }),
+                }],
                 next_status: ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT)
             }
         );
     }
+
+    #[tokio::test]
+    async fn windowed_read() {
+        let file_contents: Vec<u8> = (0..35).collect();
+
+        let fs = simple_fs::MapFilesystem::from([(
+            PathBuf::from_str("/foo").unwrap(),
+            file_contents.clone(),
+        )]);
+        let mut con = Connection::new_with_filesystem(fs, "/");
+
+        let data = |block: u16| tftp::Packet::Data {
+            block,
+            data: file_contents[usize::from(block - 1) * 10..]
+                .iter()
+                .copied()
+                .take(10)
+                .collect::<Vec<u8>>()
+                .into(),
+        };
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+        let waiting = ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT);
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "10".into(),
+                    },
+                    RequestOption {
+                        name: "windowsize".into(),
+                        value: "3".into(),
+                    },
+                ],
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(&response.packets[..], [tftp::Packet::OAck { .. }]));
+
+        assert_eq!(
+            con.handle_event(ack(0)).await.unwrap(),
+            Response {
+                packets: vec![data(1), data(2), data(3)],
+                next_status: waiting,
+            }
+        );
+
+        // A partial ACK moves the window.
+        assert_eq!(
+            con.handle_event(ack(1)).await.unwrap(),
+            Response {
+                packets: vec![data(2), data(3), data(4)],
+                next_status: waiting,
+            }
+        );
+
+        // Stale ACKs are ignored and don't extend the timeout.
+        let response = con.handle_event(ack(1)).await.unwrap();
+        assert_eq!(response.packets, []);
+        assert!(matches!(
+            response.next_status,
+            ConnectionStatus::WaitingForPacket(timeout) if timeout <= DEFAULT_TFTP_TIMEOUT
+        ));
+
+        // A timeout resends the whole window.
+        assert_eq!(
+            con.handle_event(Event::Timeout).await.unwrap(),
+            Response {
+                packets: vec![data(2), data(3), data(4)],
+                next_status: waiting,
+            }
+        );
+
+        assert_eq!(
+            con.handle_event(ack(4)).await.unwrap(),
+            Response {
+                packets: vec![],
+                next_status: ConnectionStatus::Terminated,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_window_to_file() {
+        let file_contents: Vec<u8> = (0..55).collect();
+
+        let fs = simple_fs::MapFilesystem::from([(
+            PathBuf::from_str("/foo").unwrap(),
+            file_contents.clone(),
+        )]);
+        let mut con = Connection::new_with_filesystem(fs, "/");
+
+        let data = |block: u16| tftp::Packet::Data {
+            block,
+            data: file_contents[usize::from(block - 1) * 10..]
+                .iter()
+                .copied()
+                .take(10)
+                .collect::<Vec<u8>>()
+                .into(),
+        };
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+        let waiting = ConnectionStatus::WaitingForPacket(DEFAULT_TFTP_TIMEOUT);
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "10".into(),
+                    },
+                    RequestOption {
+                        name: "windowsize".into(),
+                        value: "1000".into(),
+                    },
+                ],
+            }))
+            .await
+            .unwrap();
+
+        // Window sizes are capped.
+        assert_eq!(
+            response.packets,
+            [tftp::Packet::OAck {
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "10".into(),
+                    },
+                    RequestOption {
+                        name: "windowsize".into(),
+                        value: MAX_TFTP_WINDOWSIZE.to_string().into(),
+                    },
+                ]
+            }]
+        );
+
+        // The window ends with the final block.
+        assert_eq!(
+            con.handle_event(ack(0)).await.unwrap(),
+            Response {
+                packets: (1..=6).map(data).collect(),
+                next_status: waiting,
+            }
+        );
+
+        // An ACK can cover multiple blocks.
+        assert_eq!(
+            con.handle_event(ack(4)).await.unwrap(),
+            Response {
+                packets: vec![data(5), data(6)],
+                next_status: waiting,
+            }
+        );
+
+        assert_eq!(
+            con.handle_event(ack(6)).await.unwrap(),
+            Response {
+                packets: vec![],
+                next_status: ConnectionStatus::Terminated,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adapts_timeouts() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 2048])]);
+        let options = Options {
+            max_retransmissions: 2,
+            ..Options::default()
+        };
+        let mut con = Connection::new(
+            fs.clone(),
+            "/",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            options.clone(),
+        );
+        let rrq = |options| {
+            Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options,
+            })
+        };
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+        let waiting = |timeout| ConnectionStatus::WaitingForPacket(timeout);
+
+        // Without a measurement, we start with the configured timeout.
+        assert_eq!(
+            con.handle_event(rrq(vec![])).await.unwrap().next_status,
+            waiting(DEFAULT_TFTP_TIMEOUT)
+        );
+
+        // Quick round trips drop the timeout to its minimum.
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(
+            con.handle_event(ack(1)).await.unwrap().next_status,
+            waiting(MIN_TFTP_TIMEOUT)
+        );
+
+        // Each timeout doubles the timeout.
+        assert_eq!(
+            con.handle_event(Event::Timeout).await.unwrap().next_status,
+            waiting(MIN_TFTP_TIMEOUT * 2)
+        );
+        assert_eq!(
+            con.handle_event(Event::Timeout).await.unwrap().next_status,
+            waiting(MIN_TFTP_TIMEOUT * 4)
+        );
+        assert_eq!(
+            con.handle_event(Event::Timeout).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+
+        // A timeout requested by the client is used as is.
+        let mut con = Connection::new(fs, "/", SocketAddr::from(([127, 0, 0, 1], 0)), options);
+        let response = con
+            .handle_event(rrq(vec![RequestOption {
+                name: "timeout".into(),
+                value: "3".into(),
+            }]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.packets,
+            [tftp::Packet::OAck {
+                options: vec![RequestOption {
+                    name: "timeout".into(),
+                    value: "3".into(),
+                }]
+            }]
+        );
+        for event in [ack(0), Event::Timeout, ack(1)] {
+            assert_eq!(
+                con.handle_event(event).await.unwrap().next_status,
+                waiting(Duration::from_secs(3))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_unacknowledged_options() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 100])]);
+        let mut con = Connection::new_with_filesystem(fs, "/");
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![RequestOption {
+                    name: "tsize".into(),
+                    value: "0".into(),
+                }],
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(&response.packets[..], [tftp::Packet::OAck { .. }]));
+
+        for _ in 0..MAX_RETRANSMISSIONS {
+            let response = con.handle_event(Event::Timeout).await.unwrap();
+            assert!(matches!(&response.packets[..], [tftp::Packet::OAck { .. }]));
+        }
+
+        assert_eq!(
+            con.handle_event(Event::Timeout).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_quirks() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 2000])]);
+        let options = Options {
+            quirks: Some(Arc::new(Quirks::new([
+                "capped options=blksize,tsize ignore=tsize max-blksize=1024"
+                    .parse()
+                    .unwrap(),
+                "silent options=tsize no-oack".parse().unwrap(),
+            ]))),
+            ..Options::default()
+        };
+        let read = |names: &[&str]| tftp::Packet::Rrq {
+            filename: Path::new("/foo").into(),
+            mode: tftp::RequestMode::Octet,
+            options: names
+                .iter()
+                .map(|name| RequestOption {
+                    name: name.to_string().into(),
+                    value: "1468".into(),
+                })
+                .collect(),
+        };
+
+        let mut con = Connection::new(
+            fs.clone(),
+            "/",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            options.clone(),
+        );
+        let response = con
+            .handle_event(Event::PacketReceived(read(&["blksize", "tsize"])))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.packets,
+            [tftp::Packet::OAck {
+                options: vec![RequestOption {
+                    name: "blksize".into(),
+                    value: "1024".into(),
+                }]
+            }]
+        );
+
+        let mut con = Connection::new(fs, "/", SocketAddr::from(([127, 0, 0, 1], 0)), options);
+        let response = con
+            .handle_event(Event::PacketReceived(read(&["tsize"])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            &response.packets[..],
+            [tftp::Packet::Data { block: 1, data }] if data.len() == 512
+        ));
+    }
+
+    /// Answer an OACK for `blksize=100` with `reaction` and return the
+    /// response.
+    async fn refuse_options(restart: bool, reaction: Packet) -> Response<Packet> {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 600])]);
+        let mut con = Connection::new(
+            fs,
+            "/",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Options {
+                restart_without_options: restart,
+                ..Options::default()
+            },
+        );
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![RequestOption {
+                    name: "blksize".into(),
+                    value: "100".into(),
+                }],
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(&response.packets[..], [tftp::Packet::OAck { .. }]));
+
+        con.handle_event(Event::PacketReceived(reaction))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn restarts_without_options() {
+        let reactions = [
+            tftp::Packet::Error {
+                error_code: tftp::error::INVALID_OPTION,
+                error_msg: "No options, please".into(),
+            },
+            tftp::Packet::Error {
+                error_code: tftp::error::UNDEFINED,
+                error_msg: "Unknown option".into(),
+            },
+            tftp::Packet::Ack { block: 1 },
+            tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![],
+            },
+        ];
+
+        for reaction in reactions {
+            let response = refuse_options(true, reaction).await;
+
+            assert!(matches!(
+                &response.packets[..],
+                [tftp::Packet::Data { block: 1, data }] if data.len() == 512
+            ));
+        }
+
+        // A request for another file is not a refused OACK.
+        let response = refuse_options(
+            true,
+            tftp::Packet::Rrq {
+                filename: Path::new("/bar").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![],
+            },
+        )
+        .await;
+        assert!(matches!(
+            &response.packets[..],
+            [tftp::Packet::Error {
+                error_code: tftp::error::ILLEGAL_OPERATION,
+                ..
+            }]
+        ));
+
+        let response = refuse_options(
+            false,
+            tftp::Packet::Error {
+                error_code: tftp::error::INVALID_OPTION,
+                error_msg: "No options, please".into(),
+            },
+        )
+        .await;
+        assert!(response.packets.is_empty());
+        assert_eq!(response.next_status, ConnectionStatus::Terminated);
+
+        let response = refuse_options(false, tftp::Packet::Ack { block: 1 }).await;
+        assert!(matches!(
+            &response.packets[..],
+            [tftp::Packet::Error {
+                error_code: tftp::error::ILLEGAL_OPERATION,
+                ..
+            }]
+        ));
+    }
+
+    /// Replay `acks` against a transfer of a 45-byte file in blocks of
+    /// 10 bytes. Returns the block numbers sent after each event and
+    /// the number of ignored ACKs.
+    async fn replay_acks(window_size: u16, events: &[Event<Packet>]) -> (Vec<Vec<u16>>, u64) {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 45])]);
+        let mut con = Connection::new_with_filesystem(fs, "/");
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "10".into(),
+                    },
+                    RequestOption {
+                        name: "windowsize".into(),
+                        value: window_size.to_string().into(),
+                    },
+                ],
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(&response.packets[..], [tftp::Packet::OAck { .. }]));
+
+        let mut sent = vec![];
+        let mut duplicate_acks = 0;
+
+        for event in events {
+            let response = con.handle_event(event.clone()).await.unwrap();
+
+            sent.push(
+                response
+                    .packets
+                    .iter()
+                    .map(|packet| match packet {
+                        tftp::Packet::Data { block, .. } => *block,
+                        packet => panic!("Unexpected packet: {packet:?}"),
+                    })
+                    .collect(),
+            );
+
+            if let Connection::ReadingFile { transfer, .. } = &con {
+                duplicate_acks = transfer.duplicate_acks;
+            }
+        }
+
+        assert!(matches!(con, Connection::Dead));
+        (sent, duplicate_acks)
+    }
+
+    #[tokio::test]
+    async fn ignores_duplicate_acks() {
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+
+        // The classic Sorcerer's Apprentice: block 1 is retransmitted and
+        // both copies are acknowledged. Only the first ACK is answered.
+        let (sent, duplicate_acks) = replay_acks(
+            1,
+            &[
+                ack(0),
+                Event::Timeout,
+                ack(1),
+                ack(1),
+                ack(2),
+                // Reordered ACKs from earlier.
+                ack(0),
+                ack(1),
+                ack(3),
+                ack(4),
+                ack(4),
+                ack(5),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            [
+                vec![1],
+                vec![1],
+                vec![2],
+                vec![],
+                vec![3],
+                vec![],
+                vec![],
+                vec![4],
+                vec![5],
+                vec![],
+                vec![],
+            ]
+        );
+        assert_eq!(duplicate_acks, 4);
+    }
+
+    #[tokio::test]
+    async fn ignores_reordered_window_acks() {
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+
+        let (sent, duplicate_acks) = replay_acks(
+            2,
+            &[
+                ack(0),
+                ack(2),
+                // The ACK of the first block of a window arrives late.
+                ack(1),
+                ack(3),
+                ack(2),
+                ack(5),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            [vec![1, 2], vec![3, 4], vec![], vec![4, 5], vec![], vec![],]
+        );
+        assert_eq!(duplicate_acks, 2);
+    }
+
+    #[tokio::test]
+    async fn resolves_pxelinux_lookups() {
+        let fs = simple_fs::MapFilesystem::from([
+            (PathBuf::from("/tftp/pxelinux.cfg/C0A8"), b"subnet".to_vec()),
+            (
+                PathBuf::from("/tftp/pxelinux.cfg/default"),
+                b"default".to_vec(),
+            ),
+        ]);
+        let options = Options {
+            pxe_lookup: Some(Arc::new(PxeLookup::new(["pxelinux.cfg/"]))),
+            ..Options::default()
+        };
+        let rrq = |filename: &str| {
+            Event::PacketReceived(tftp::Packet::Rrq {
+                filename: PathBuf::from(filename).into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![],
+            })
+        };
+        let data = |data: &[u8]| tftp::Packet::Data {
+            block: 1,
+            data: data.to_vec().into(),
+        };
+
+        for (peer, filename, expected) in [
+            (
+                [192, 168, 1, 2],
+                "pxelinux.cfg/01-aa-bb-cc-dd-ee-ff",
+                data(b"subnet"),
+            ),
+            (
+                [10, 0, 0, 1],
+                "/pxelinux.cfg/01-aa-bb-cc-dd-ee-ff",
+                data(b"default"),
+            ),
+            ([192, 168, 1, 2], "pxelinux.cfg/C", data(b"default")),
+        ] {
+            let mut con = Connection::new(
+                fs.clone(),
+                "/tftp",
+                SocketAddr::from((peer, 1234)),
+                options.clone(),
+            );
+
+            assert_eq!(
+                con.handle_event(rrq(filename)).await.unwrap().packets,
+                [expected]
+            );
+        }
+
+        // Without a lookup chain, missing files are still errors.
+        let mut con = Connection::new(
+            fs,
+            "/tftp",
+            SocketAddr::from(([192, 168, 1, 2], 1234)),
+            options,
+        );
+        assert!(matches!(
+            &con.handle_event(rrq("pxelinux.cfg/menu"))
+                .await
+                .unwrap()
+                .packets[..],
+            [tftp::Packet::Error { .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_group_roots() {
+        let fs = simple_fs::MapFilesystem::from([
+            (PathBuf::from("/tftp/boot.ipxe"), b"default".to_vec()),
+            (PathBuf::from("/tftp/gpu/boot.ipxe"), b"gpu".to_vec()),
+        ]);
+        let options = Options {
+            inventory: Some(Arc::new(
+                Inventory::parse("ip,group\n10.0.0.1,gpu\n10.0.0.2,cpu").unwrap(),
+            )),
+            group_roots: BTreeMap::from([("gpu".to_owned(), PathBuf::from("/gpu"))]),
+            ..Options::default()
+        };
+
+        for (peer, expected) in [
+            ([10, 0, 0, 1], b"gpu".as_slice()),
+            ([10, 0, 0, 2], b"default"),
+            ([10, 0, 0, 3], b"default"),
+        ] {
+            let mut con = Connection::new(
+                fs.clone(),
+                "/tftp",
+                SocketAddr::from((peer, 1234)),
+                options.clone(),
+            );
+
+            assert_eq!(
+                con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                    filename: Path::new("boot.ipxe").into(),
+                    mode: tftp::RequestMode::Octet,
+                    options: vec![],
+                }))
+                .await
+                .unwrap()
+                .packets,
+                [tftp::Packet::Data {
+                    block: 1,
+                    data: expected.into()
+                }]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn selects_files_by_architecture() {
+        let fs = simple_fs::MapFilesystem::from([
+            (PathBuf::from("/bootfile"), b"bootfile".to_vec()),
+            (PathBuf::from("/undionly.kpxe"), b"bios".to_vec()),
+            (PathBuf::from("/ipxe.efi"), b"uefi".to_vec()),
+        ]);
+        let arch_table = Arc::new(ArchTable::default());
+        let options = Options {
+            arch_table: Some(arch_table.clone()),
+            arch_rules: BTreeMap::from([(
+                PathBuf::from("bootfile"),
+                BTreeMap::from([
+                    (Architecture::Bios, PathBuf::from("undionly.kpxe")),
+                    (Architecture::UefiX64, PathBuf::from("ipxe.efi")),
+                ]),
+            )]),
+            ..Options::default()
+        };
+
+        arch_table.observe(
+            "aa:bb:cc:dd:ee:01",
+            Some([10, 0, 0, 1].into()),
+            Architecture::Bios,
+        );
+        arch_table.observe(
+            "aa:bb:cc:dd:ee:02",
+            Some([10, 0, 0, 2].into()),
+            Architecture::UefiX64,
+        );
+        arch_table.observe(
+            "aa:bb:cc:dd:ee:03",
+            Some([10, 0, 0, 3].into()),
+            Architecture::UefiArm64,
+        );
+
+        for (peer, filename, expected) in [
+            ([10, 0, 0, 1], "/bootfile", b"bios".as_slice()),
+            ([10, 0, 0, 2], "bootfile", b"uefi"),
+            // No rule for this architecture.
+            ([10, 0, 0, 3], "bootfile", b"bootfile"),
+            // Unknown architecture.
+            ([10, 0, 0, 4], "bootfile", b"bootfile"),
+            // No rule for this file.
+            ([10, 0, 0, 1], "ipxe.efi", b"uefi"),
+        ] {
+            let mut con = Connection::new(
+                fs.clone(),
+                "/",
+                SocketAddr::from((peer, 1234)),
+                options.clone(),
+            );
+
+            assert_eq!(
+                con.handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                    filename: Path::new(filename).into(),
+                    mode: tftp::RequestMode::Octet,
+                    options: vec![],
+                }))
+                .await
+                .unwrap()
+                .packets,
+                [tftp::Packet::Data {
+                    block: 1,
+                    data: expected.into()
+                }]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_over_multicast_sessions() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0xab; 1000])]);
+        let group = SocketAddrV4::from_str("239.255.0.1:1758").unwrap();
+        let options = Options {
+            multicast: Some(Arc::new(MulticastGroups::new([group]))),
+            ..Options::default()
+        };
+        let connect = |peer: [u8; 4]| {
+            Connection::new(
+                fs.clone(),
+                "/",
+                SocketAddr::from((peer, 1234)),
+                options.clone(),
+            )
+        };
+        let rrq = || {
+            Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![RequestOption {
+                    name: "multicast".into(),
+                    value: "".into(),
+                }],
+            })
+        };
+        let oack = |master: bool| {
+            vec![tftp::Packet::OAck {
+                options: vec![multicast_option(group, master)],
+            }]
+        };
+        let data = |block: u16| {
+            vec![tftp::Packet::Data {
+                block,
+                data: vec![0xab; if block == 1 { 512 } else { 488 }].into(),
+            }]
+        };
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+
+        let mut first = connect([10, 0, 0, 1]);
+        let mut second = connect([10, 0, 0, 2]);
+
+        assert_eq!(first.handle_event(rrq()).await.unwrap().packets, oack(true));
+        assert_eq!(first.destination(), None);
+        assert_eq!(first.handle_event(ack(0)).await.unwrap().packets, data(1));
+        assert_eq!(first.destination(), Some(group.into()));
+
+        // The second client joins late and gets block 2 via multicast.
+        // Until it becomes master, we only repeat the OACK.
+        assert_eq!(
+            second.handle_event(rrq()).await.unwrap().packets,
+            oack(false)
+        );
+        assert_eq!(
+            second.handle_event(Event::Timeout).await.unwrap().packets,
+            oack(false)
+        );
+
+        assert_eq!(first.handle_event(ack(1)).await.unwrap().packets, data(2));
+        assert_eq!(
+            first.handle_event(ack(2)).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+
+        // Now the second client is master and asks for block 1.
+        assert_eq!(
+            second.handle_event(Event::Timeout).await.unwrap().packets,
+            oack(true)
+        );
+        assert_eq!(second.handle_event(ack(0)).await.unwrap().packets, data(1));
+        assert_eq!(second.destination(), Some(group.into()));
+        assert_eq!(
+            second.handle_event(ack(2)).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+
+        // The group is free again for unicast-only clients as well.
+        let mut third = connect([10, 0, 0, 3]);
+        assert_eq!(third.handle_event(rrq()).await.unwrap().packets, oack(true));
+    }
+
+    #[tokio::test]
+    async fn drops_vanished_multicast_followers() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0xab; 1000])]);
+        let group = SocketAddrV4::from_str("239.255.0.1:1758").unwrap();
+        let groups = Arc::new(MulticastGroups::new([group]));
+        let options = Options {
+            multicast: Some(groups.clone()),
+            ..Options::default()
+        };
+        let rrq = || {
+            Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![RequestOption {
+                    name: "multicast".into(),
+                    value: "".into(),
+                }],
+            })
+        };
+
+        let mut master = Connection::new(
+            fs.clone(),
+            "/",
+            SocketAddr::from(([10, 0, 0, 1], 1234)),
+            options.clone(),
+        );
+        let mut follower = Connection::new(
+            fs,
+            "/",
+            SocketAddr::from(([10, 0, 0, 2], 1234)),
+            options.clone(),
+        );
+
+        master.handle_event(rrq()).await.unwrap();
+        follower.handle_event(rrq()).await.unwrap();
+
+        // ACKs from followers count as a sign of life.
+        for _ in 0..options.max_retransmissions {
+            follower.handle_event(Event::Timeout).await.unwrap();
+        }
+        follower
+            .handle_event(Event::PacketReceived(tftp::Packet::Ack { block: 0 }))
+            .await
+            .unwrap();
+
+        for _ in 0..options.max_retransmissions {
+            assert!(matches!(
+                &follower.handle_event(Event::Timeout).await.unwrap().packets[..],
+                [tftp::Packet::OAck { .. }]
+            ));
+        }
+
+        let response = follower.handle_event(Event::Timeout).await.unwrap();
+        assert!(response.packets.is_empty());
+        assert_eq!(response.next_status, ConnectionStatus::Terminated);
+
+        // Without the follower, the session ends with the master.
+        drop(master);
+        assert!(groups
+            .join(
+                SessionKey {
+                    path: PathBuf::from("/bar"),
+                    size: 0,
+                    block_size: 512,
+                },
+                SocketAddr::from(([10, 0, 0, 3], 1234)),
+            )
+            .is_some_and(|membership| membership.is_master()));
+    }
+
+    #[tokio::test]
+    async fn hands_over_multicast_sessions_after_rollover() {
+        // 70000 full blocks of 8 bytes and an empty final block.
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/big"), vec![0; 8 * 70000])]);
+        let group = SocketAddrV4::from_str("239.255.0.1:1758").unwrap();
+        let options = Options {
+            multicast: Some(Arc::new(MulticastGroups::new([group]))),
+            ..Options::default()
+        };
+        let connect = |peer: [u8; 4]| {
+            Connection::new(
+                fs.clone(),
+                "/",
+                SocketAddr::from((peer, 1234)),
+                options.clone(),
+            )
+        };
+        let rrq = || {
+            Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("big").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![
+                    RequestOption {
+                        name: "blksize".into(),
+                        value: "8".into(),
+                    },
+                    RequestOption {
+                        name: "multicast".into(),
+                        value: "".into(),
+                    },
+                ],
+            })
+        };
+        let data = |block: u16| {
+            vec![tftp::Packet::Data {
+                block,
+                data: vec![0; 8].into(),
+            }]
+        };
+        let ack = |block| Event::PacketReceived(tftp::Packet::Ack { block });
+
+        let mut first = connect([10, 0, 0, 1]);
+        let mut second = connect([10, 0, 0, 2]);
+
+        first.handle_event(rrq()).await.unwrap();
+        second.handle_event(rrq()).await.unwrap();
+        assert_eq!(first.handle_event(ack(0)).await.unwrap().packets, data(1));
+
+        // The master client skips ahead to blocks it got from the group,
+        // past the first rollover.
+        assert_eq!(
+            first.handle_event(ack(30000)).await.unwrap().packets,
+            data(30001)
+        );
+        assert_eq!(
+            first.handle_event(ack(60000)).await.unwrap().packets,
+            data(60001)
+        );
+        assert_eq!(first.handle_event(ack(64)).await.unwrap().packets, data(65));
+
+        // Stale ACKs don't move the transfer.
+        assert!(first
+            .handle_event(ack(60000))
+            .await
+            .unwrap()
+            .packets
+            .is_empty());
+
+        // The number of the final block, 70001, after rollover.
+        assert_eq!(
+            first.handle_event(ack(4465)).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+
+        // The new master already has everything.
+        second.handle_event(Event::Timeout).await.unwrap();
+        assert_eq!(
+            second.handle_event(ack(4465)).await.unwrap().next_status,
+            ConnectionStatus::Terminated
+        );
+    }
+
+    /// Download a file of 65536 full blocks of 8 bytes and return the
+    /// block numbers of all data packets, or the error we got.
+    async fn rollover_block_numbers(
+        rollover: Rollover,
+        client_rollover: Option<&str>,
+    ) -> Result<Vec<u16>, Packet> {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/big"), vec![0; 8 << 16])]);
+        let mut con = Connection::new(
+            fs,
+            "/",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Options {
+                rollover,
+                ..Options::default()
+            },
+        );
+        let mut options = vec![
+            RequestOption {
+                name: "blksize".into(),
+                value: "8".into(),
+            },
+            RequestOption {
+                name: "windowsize".into(),
+                value: "64".into(),
+            },
+        ];
+
+        if let Some(value) = client_rollover {
+            options.push(RequestOption {
+                name: "rollover".into(),
+                value: value.to_owned().into(),
+            });
+        }
+
+        let mut response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("big").into(),
+                mode: tftp::RequestMode::Octet,
+                options,
+            }))
+            .await
+            .unwrap();
+        let mut block_numbers = vec![];
+
+        if let [packet @ tftp::Packet::Error { .. }] = &response.packets[..] {
+            return Err(packet.clone());
+        }
+
+        let mut ack = 0;
+
+        while response.next_status != ConnectionStatus::Terminated {
+            response = con
+                .handle_event(Event::PacketReceived(tftp::Packet::Ack { block: ack }))
+                .await
+                .unwrap();
+
+            for packet in &response.packets {
+                match packet {
+                    tftp::Packet::Data { block, .. } => {
+                        block_numbers.push(*block);
+                        ack = *block;
+                    }
+                    packet => return Err(packet.clone()),
+                }
+            }
+        }
+
+        Ok(block_numbers)
+    }
+
+    #[tokio::test]
+    async fn rolls_over_block_numbers() {
+        for (rollover, client_rollover, expected) in [
+            (Rollover::ToZero, None, [65535, 0, 1]),
+            (Rollover::ToOne, None, [65535, 1, 2]),
+            (Rollover::ToZero, Some("1"), [65535, 1, 2]),
+            (Rollover::ToOne, Some("0"), [65535, 0, 1]),
+            (Rollover::Refuse, Some("0"), [65535, 0, 1]),
+        ] {
+            let block_numbers = rollover_block_numbers(rollover, client_rollover)
+                .await
+                .unwrap();
+
+            assert_eq!(block_numbers.len(), 65537);
+            assert_eq!(block_numbers[..2], [1, 2]);
+            assert_eq!(
+                block_numbers[65534..],
+                expected,
+                "{rollover:?} {client_rollover:?}"
+            );
+        }
+
+        assert_eq!(
+            rollover_block_numbers(Rollover::Refuse, None).await,
+            Err(tftp::Packet::Error {
+                error_code: tftp::error::UNDEFINED,
+                error_msg: "File needs more than 65535 blocks, use a blksize of at least 9".into(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_block_buffers() {
+        let fs = simple_fs::MapFilesystem::from([(PathBuf::from("/foo"), vec![0; 2048])]);
+        let mut con = Connection::new_with_filesystem(fs, "/");
+
+        let data_ptr = |response: &Response<Packet>| match &response.packets[..] {
+            [tftp::Packet::Data { data, .. }] => data.as_ptr(),
+            p => panic!("Unexpected packets: {p:?}"),
+        };
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Rrq {
+                filename: Path::new("/foo").into(),
+                mode: tftp::RequestMode::Octet,
+                options: vec![],
+            }))
+            .await
+            .unwrap();
+        let first = data_ptr(&response);
+        con.recycle(response);
+
+        let response = con
+            .handle_event(Event::PacketReceived(tftp::Packet::Ack { block: 1 }))
+            .await
+            .unwrap();
+        assert_eq!(data_ptr(&response), first);
+    }
 }
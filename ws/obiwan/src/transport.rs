@@ -0,0 +1,214 @@
+//! This module abstracts how transfers send and receive packets.
+//!
+//! By default, this uses Tokio's UDP sockets. With the `io-uring`
+//! feature, packets are sent and received via [`crate::uring`]
+//! instead.
+//!
+//! Transfer sockets are connected to the client, so the kernel only
+//! hands us packets from the client's port. RFC 1350 asks us to
+//! answer packets from other ports with an error. The kernel answers
+//! them with an ICMP port unreachable message instead, which tells
+//! the sender just as much and doesn't cost us a socket per transfer.
+
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "io-uring")]
+use std::sync::Arc;
+
+#[cfg(feature = "io-uring")]
+use crate::uring::Ring;
+
+/// The context that is needed to create sockets for transfers.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    #[cfg(feature = "io-uring")]
+    ring: Ring,
+}
+
+impl Transport {
+    /// Create a new transport. With io_uring, this starts the I/O
+    /// thread, so this should only be called after dropping
+    /// privileges.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            #[cfg(feature = "io-uring")]
+            ring: Ring::new()?,
+        })
+    }
+
+    /// The ring that is used for I/O.
+    #[cfg(feature = "io-uring")]
+    pub fn ring(&self) -> Ring {
+        self.ring.clone()
+    }
+
+    /// Create a socket on `local_addr` that only talks to `remote_addr`.
+    pub async fn connect(
+        &self,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) -> std::io::Result<TransferSocket> {
+        let socket = Socket::new(
+            Domain::for_address(local_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+
+        socket.bind(&local_addr.into())?;
+        socket.connect(&remote_addr.into())?;
+
+        #[cfg(not(feature = "io-uring"))]
+        {
+            socket.set_nonblocking(true)?;
+
+            Ok(TransferSocket {
+                socket: tokio::net::UdpSocket::from_std(socket.into())?,
+            })
+        }
+
+        #[cfg(feature = "io-uring")]
+        {
+            Ok(TransferSocket {
+                socket: Arc::new(socket.into()),
+                ring: self.ring.clone(),
+            })
+        }
+    }
+}
+
+/// A socket that is used for a single transfer.
+#[derive(Debug)]
+pub struct TransferSocket {
+    #[cfg(not(feature = "io-uring"))]
+    socket: tokio::net::UdpSocket,
+
+    #[cfg(feature = "io-uring")]
+    socket: Arc<std::net::UdpSocket>,
+    #[cfg(feature = "io-uring")]
+    ring: Ring,
+}
+
+impl TransferSocket {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The kernel's current estimate of the path MTU towards the peer.
+    pub fn path_mtu(&self) -> std::io::Result<u32> {
+        use nix::libc;
+        use std::os::fd::AsRawFd;
+
+        let (level, name) = match self.local_addr()? {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&mtu) as libc::socklen_t;
+
+        // Safety: `mtu` and `len` outlive the system call and `len`
+        // is the size of `mtu`.
+        let result = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                level,
+                name,
+                (&mut mtu as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        u32::try_from(mtu).map_err(std::io::Error::other)
+    }
+
+    /// Send a single packet. Returns the buffer, so it can be reused.
+    /// With io_uring, the buffer is handed to the kernel while the
+    /// packet is in flight.
+    pub async fn send(&self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        #[cfg(not(feature = "io-uring"))]
+        {
+            self.socket.send(&data).await?;
+            Ok(data)
+        }
+
+        #[cfg(feature = "io-uring")]
+        self.ring.send(self.socket.clone(), data).await
+    }
+
+    /// Send a single packet to `destination` instead of the peer, such
+    /// as a multicast group. Returns the buffer like [`Self::send`].
+    pub async fn send_to(
+        &self,
+        data: Vec<u8>,
+        destination: SocketAddr,
+    ) -> std::io::Result<Vec<u8>> {
+        #[cfg(not(feature = "io-uring"))]
+        {
+            self.socket.send_to(&data, destination).await?;
+            Ok(data)
+        }
+
+        #[cfg(feature = "io-uring")]
+        self.ring
+            .send_to(self.socket.clone(), data, destination)
+            .await
+    }
+
+    /// Send multiple packets in order. Where possible, this uses a
+    /// single system call for many packets.
+    pub async fn send_batch(&self, packets: &mut [Vec<u8>]) -> std::io::Result<()> {
+        #[cfg(not(feature = "io-uring"))]
+        {
+            use std::os::fd::AsRawFd;
+
+            let mut sent = 0;
+
+            while sent < packets.len() {
+                let count = self
+                    .socket
+                    .async_io(tokio::io::Interest::WRITABLE, || {
+                        crate::batch_io::send(self.socket.as_raw_fd(), &packets[sent..])
+                    })
+                    .await?;
+
+                sent += count;
+            }
+        }
+
+        // Operations on the ring may complete out of order, so we send
+        // packets one after another to keep them in sequence.
+        #[cfg(feature = "io-uring")]
+        for packet in packets {
+            *packet = self.send(std::mem::take(packet)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive a single packet into `buf`. Returns the size of the
+    /// packet or `None`, if no packet arrived within `timeout`, and
+    /// the buffer like [`Self::send`].
+    pub async fn recv(
+        &self,
+        buf: Vec<u8>,
+        timeout: Duration,
+    ) -> std::io::Result<(Option<usize>, Vec<u8>)> {
+        #[cfg(not(feature = "io-uring"))]
+        {
+            let mut buf = buf;
+
+            match tokio::time::timeout(timeout, self.socket.recv(&mut buf)).await {
+                Ok(res) => Ok((Some(res?), buf)),
+                Err(_) => Ok((None, buf)),
+            }
+        }
+
+        #[cfg(feature = "io-uring")]
+        self.ring.recv(self.socket.clone(), buf, timeout).await
+    }
+}
@@ -0,0 +1,720 @@
+//! This module implements file and network I/O on top of io_uring.
+//!
+//! All operations are funneled to a dedicated thread that owns the
+//! ring. Submitters queue their requests and wake up the ring thread
+//! via an eventfd that the ring itself is polling. Because requests
+//! pile up while the ring thread is busy, they are naturally
+//! submitted in batches. Submitters only write to the eventfd, if
+//! the ring thread hasn't been woken up already.
+//!
+//! Operations take ownership of the caller's buffer and hand it back
+//! when they complete, so data is never copied between buffers.
+
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use io_uring::{opcode, squeue, types, IoUring};
+use log::{debug, error};
+use nix::libc;
+use socket2::SockAddr;
+use tokio::sync::oneshot;
+
+use crate::simple_fs;
+
+/// The number of submission queue entries.
+const RING_ENTRIES: u32 = 256;
+
+/// The user data for the eventfd read that wakes up the ring thread.
+const WAKEUP: u64 = u64::MAX;
+
+/// The user data for timeouts that are linked to receive operations.
+const LINKED_TIMEOUT: u64 = u64::MAX - 1;
+
+/// Anything that owns a file descriptor. Requests keep their owner
+/// alive until the operation has completed.
+pub type FdOwner = Arc<dyn AsRawFd + Send + Sync>;
+
+enum Request {
+    /// Read into `buf[pos..]`.
+    Read {
+        fd: FdOwner,
+        offset: u64,
+        buf: Vec<u8>,
+        pos: usize,
+    },
+    Send {
+        fd: FdOwner,
+        data: Vec<u8>,
+    },
+    SendTo {
+        fd: FdOwner,
+        data: Vec<u8>,
+        destination: SocketAddr,
+    },
+    Recv {
+        fd: FdOwner,
+        buf: Vec<u8>,
+        timeout: Duration,
+    },
+}
+
+/// The result of an operation: the number of bytes transferred and
+/// the buffer that was used. The buffer comes back even if the
+/// operation failed.
+type Outcome = (std::io::Result<usize>, Vec<u8>);
+
+struct Submission {
+    request: Request,
+    done: oneshot::Sender<Outcome>,
+}
+
+/// The message header of a send to an explicit destination. The
+/// kernel reads this while the operation is in flight, so it is
+/// boxed to give it a stable address.
+struct Message {
+    header: libc::msghdr,
+    iov: libc::iovec,
+    destination: SockAddr,
+}
+
+/// An operation that was handed to the kernel.
+struct InFlight {
+    /// Keeps the file descriptor open until the operation completes.
+    _fd: FdOwner,
+    buffer: Vec<u8>,
+
+    /// The timeout of a receive operation. The kernel reads this
+    /// while the operation is in flight, so it needs a stable address.
+    _timeout: Option<Box<types::Timespec>>,
+    _message: Option<Box<Message>>,
+    done: oneshot::Sender<Outcome>,
+}
+
+struct Inner {
+    /// Only `None` while dropping.
+    submissions: Option<mpsc::Sender<Submission>>,
+    wakeup: Arc<OwnedFd>,
+
+    /// Whether the ring thread was woken up, but hasn't looked at the
+    /// submissions yet. Further submissions don't need to wake it.
+    wakeup_pending: Arc<AtomicBool>,
+}
+
+impl Inner {
+    fn wake(&self) {
+        if self.wakeup_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let one = 1u64.to_ne_bytes();
+
+        if let Err(e) = nix::unistd::write(self.wakeup.as_ref(), &one) {
+            error!("Failed to wake up io_uring thread: {e}");
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Close the channel before waking the ring thread, so it sees
+        // that there will be no more submissions.
+        drop(self.submissions.take());
+        self.wakeup_pending.store(false, Ordering::Release);
+        self.wake();
+    }
+}
+
+/// A handle to the io_uring thread. Cloning the handle is cheap.
+#[derive(Clone)]
+pub struct Ring {
+    inner: Arc<Inner>,
+}
+
+impl Debug for Ring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ring").finish_non_exhaustive()
+    }
+}
+
+impl Ring {
+    /// Create a new ring and the thread that drives it.
+    pub fn new() -> std::io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        // Safety: eventfd has no memory safety requirements. We
+        // take ownership of the returned descriptor right away.
+        let wakeup = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } {
+            -1 => return Err(std::io::Error::last_os_error()),
+            fd => Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let wakeup_pending = Arc::new(AtomicBool::new(false));
+        let mut driver = Driver {
+            ring,
+            submissions: receiver,
+            wakeup: wakeup.clone(),
+            wakeup_pending: wakeup_pending.clone(),
+            wakeup_buf: Box::new([0; 8]),
+            in_flight: vec![],
+            free_slots: vec![],
+        };
+
+        std::thread::Builder::new()
+            .name("io_uring".to_owned())
+            .spawn(move || driver.run())?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                submissions: Some(sender),
+                wakeup,
+                wakeup_pending,
+            }),
+        })
+    }
+
+    async fn submit(&self, request: Request) -> Outcome {
+        let (done, result) = oneshot::channel();
+
+        if let Err(mpsc::SendError(submission)) = self
+            .inner
+            .submissions
+            .as_ref()
+            .expect("submissions are only closed on drop")
+            .send(Submission { request, done })
+        {
+            let buf = match submission.request {
+                Request::Read { buf, .. }
+                | Request::Send { data: buf, .. }
+                | Request::SendTo { data: buf, .. }
+                | Request::Recv { buf, .. } => buf,
+            };
+
+            return (Err(std::io::Error::other("io_uring thread is gone")), buf);
+        }
+        self.inner.wake();
+
+        result.await.unwrap_or_else(|_| {
+            (
+                Err(std::io::Error::other("io_uring thread dropped request")),
+                vec![],
+            )
+        })
+    }
+
+    /// Read from `fd` at `offset` into `buf[pos..]`. Returns the
+    /// number of bytes read and the buffer.
+    pub async fn read_at(
+        &self,
+        fd: FdOwner,
+        offset: u64,
+        buf: Vec<u8>,
+        pos: usize,
+    ) -> std::io::Result<(usize, Vec<u8>)> {
+        assert!(pos <= buf.len());
+
+        let (result, buf) = self
+            .submit(Request::Read {
+                fd,
+                offset,
+                buf,
+                pos,
+            })
+            .await;
+
+        Ok((result?, buf))
+    }
+
+    /// Send `data` on a connected socket. Returns the buffer, so it
+    /// can be reused.
+    pub async fn send(&self, fd: FdOwner, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let len = data.len();
+        let (result, data) = self.submit(Request::Send { fd, data }).await;
+
+        if result? != len {
+            return Err(std::io::Error::other("Short send on UDP socket"));
+        }
+
+        Ok(data)
+    }
+
+    /// Send `data` to `destination`. Returns the buffer, so it can be
+    /// reused.
+    pub async fn send_to(
+        &self,
+        fd: FdOwner,
+        data: Vec<u8>,
+        destination: SocketAddr,
+    ) -> std::io::Result<Vec<u8>> {
+        let len = data.len();
+        let (result, data) = self
+            .submit(Request::SendTo {
+                fd,
+                data,
+                destination,
+            })
+            .await;
+
+        if result? != len {
+            return Err(std::io::Error::other("Short send on UDP socket"));
+        }
+
+        Ok(data)
+    }
+
+    /// Receive a packet into `buf` from a connected socket. Returns
+    /// the size of the packet or `None`, if no packet arrived before
+    /// the timeout, and the buffer.
+    pub async fn recv(
+        &self,
+        fd: FdOwner,
+        buf: Vec<u8>,
+        timeout: Duration,
+    ) -> std::io::Result<(Option<usize>, Vec<u8>)> {
+        match self.submit(Request::Recv { fd, buf, timeout }).await {
+            (Ok(received), buf) => Ok((Some(received), buf)),
+            (Err(e), buf) if e.raw_os_error() == Some(libc::ECANCELED) => Ok((None, buf)),
+            (Err(e), _) => Err(e),
+        }
+    }
+}
+
+/// The state of the thread that owns the ring.
+struct Driver {
+    ring: IoUring,
+    submissions: mpsc::Receiver<Submission>,
+    wakeup: Arc<OwnedFd>,
+    wakeup_pending: Arc<AtomicBool>,
+
+    /// The eventfd read target. Boxed to give it a stable address.
+    wakeup_buf: Box<[u8; 8]>,
+
+    in_flight: Vec<Option<InFlight>>,
+    free_slots: Vec<usize>,
+}
+
+// Safety: The raw pointers in the message headers of in-flight
+// operations are only used by the kernel and the driver thread.
+unsafe impl Send for Driver {}
+
+impl Driver {
+    /// Queue an entry for submission. Submits queued entries, if the
+    /// submission queue is full.
+    ///
+    /// # Safety
+    ///
+    /// All memory referenced by the entry must stay valid until it
+    /// completes.
+    unsafe fn push(&mut self, entries: &[squeue::Entry]) -> std::io::Result<()> {
+        loop {
+            let mut sq = self.ring.submission();
+
+            if sq.capacity() - sq.len() >= entries.len() {
+                sq.push_multiple(entries)
+                    .expect("Submission queue has enough space");
+                return Ok(());
+            }
+
+            drop(sq);
+            self.ring.submit()?;
+        }
+    }
+
+    fn arm_wakeup(&mut self) -> std::io::Result<()> {
+        let entry = opcode::Read::new(
+            types::Fd(self.wakeup.as_raw_fd()),
+            self.wakeup_buf.as_mut_ptr(),
+            8,
+        )
+        .build()
+        .user_data(WAKEUP);
+
+        // Safety: The wakeup buffer lives as long as the driver.
+        unsafe { self.push(&[entry]) }
+    }
+
+    fn alloc_slot(&mut self, in_flight: InFlight) -> u64 {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.in_flight[slot] = Some(in_flight);
+                slot
+            }
+            None => {
+                self.in_flight.push(Some(in_flight));
+                self.in_flight.len() - 1
+            }
+        };
+
+        slot as u64
+    }
+
+    fn start(&mut self, submission: Submission) -> std::io::Result<()> {
+        let Submission { request, done } = submission;
+
+        match request {
+            Request::Read {
+                fd,
+                offset,
+                mut buf,
+                pos,
+            } => {
+                let len = u32::try_from(buf.len() - pos).unwrap_or(u32::MAX);
+                let entry =
+                    opcode::Read::new(types::Fd(fd.as_raw_fd()), buf[pos..].as_mut_ptr(), len)
+                        .offset(offset)
+                        .build();
+
+                let slot = self.alloc_slot(InFlight {
+                    _fd: fd,
+                    buffer: buf,
+                    _timeout: None,
+                    _message: None,
+                    done,
+                });
+
+                // Safety: The buffer is owned by the in-flight
+                // operation.
+                unsafe { self.push(&[entry.user_data(slot)]) }
+            }
+            Request::Send { fd, data } => {
+                let entry =
+                    opcode::Send::new(types::Fd(fd.as_raw_fd()), data.as_ptr(), data.len() as u32)
+                        .build();
+                let slot = self.alloc_slot(InFlight {
+                    _fd: fd,
+                    buffer: data,
+                    _timeout: None,
+                    _message: None,
+                    done,
+                });
+
+                // Safety: The data is owned by the in-flight operation.
+                unsafe { self.push(&[entry.user_data(slot)]) }
+            }
+            Request::SendTo {
+                fd,
+                mut data,
+                destination,
+            } => {
+                // Safety: All-zero is a valid message header.
+                let mut message = Box::new(Message {
+                    header: unsafe { std::mem::zeroed() },
+                    iov: libc::iovec {
+                        iov_base: data.as_mut_ptr().cast(),
+                        iov_len: data.len(),
+                    },
+                    destination: destination.into(),
+                });
+
+                message.header.msg_name = message.destination.as_ptr() as *mut libc::c_void;
+                message.header.msg_namelen = message.destination.len();
+                message.header.msg_iov = &mut message.iov;
+                message.header.msg_iovlen = 1;
+
+                let entry =
+                    opcode::SendMsg::new(types::Fd(fd.as_raw_fd()), &message.header).build();
+                let slot = self.alloc_slot(InFlight {
+                    _fd: fd,
+                    buffer: data,
+                    _timeout: None,
+                    _message: Some(message),
+                    done,
+                });
+
+                // Safety: The data and the message header are owned by
+                // the in-flight operation. Moving the boxed header
+                // doesn't move the memory it points to.
+                unsafe { self.push(&[entry.user_data(slot)]) }
+            }
+            Request::Recv {
+                fd,
+                mut buf,
+                timeout,
+            } => {
+                let timeout = Box::new(types::Timespec::from(timeout));
+                let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+
+                let recv = opcode::Recv::new(types::Fd(fd.as_raw_fd()), buf.as_mut_ptr(), len)
+                    .build()
+                    .flags(squeue::Flags::IO_LINK);
+                let link_timeout = opcode::LinkTimeout::new(timeout.as_ref())
+                    .build()
+                    .user_data(LINKED_TIMEOUT);
+
+                let slot = self.alloc_slot(InFlight {
+                    _fd: fd,
+                    buffer: buf,
+                    _timeout: Some(timeout),
+                    _message: None,
+                    done,
+                });
+
+                // Safety: The buffer and the timeout are owned by the
+                // in-flight operation.
+                unsafe { self.push(&[recv.user_data(slot), link_timeout]) }
+            }
+        }
+    }
+
+    fn complete(&mut self, slot: u64, result: i32) {
+        let slot = slot as usize;
+        let in_flight = self.in_flight[slot]
+            .take()
+            .expect("Completion for unknown operation");
+        self.free_slots.push(slot);
+
+        let result =
+            usize::try_from(result).map_err(|_| std::io::Error::from_raw_os_error(-result));
+
+        // The submitter may have given up waiting. That's fine.
+        let _ = in_flight.done.send((result, in_flight.buffer));
+    }
+
+    /// Start all queued submissions. Returns false, if there will be
+    /// no more submissions.
+    fn accept_submissions(&mut self) -> std::io::Result<bool> {
+        loop {
+            match self.submissions.try_recv() {
+                Ok(submission) => self.start(submission)?,
+                Err(mpsc::TryRecvError::Empty) => return Ok(true),
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        if let Err(e) = self.run_loop() {
+            error!("io_uring thread died: {e}");
+        }
+
+        debug!("io_uring thread exits.");
+    }
+
+    fn run_loop(&mut self) -> std::io::Result<()> {
+        let mut accepting = true;
+
+        self.arm_wakeup()?;
+
+        while accepting || self.in_flight.iter().any(Option::is_some) {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) => return Err(e),
+            }
+
+            let completions: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+
+            for (user_data, result) in completions {
+                match user_data {
+                    WAKEUP => {
+                        // Submissions from now on need another wakeup.
+                        // This synchronizes with the submitters, so we
+                        // see their queued submissions below.
+                        self.wakeup_pending.swap(false, Ordering::AcqRel);
+                        accepting = self.accept_submissions()?;
+
+                        if accepting {
+                            self.arm_wakeup()?;
+                        }
+                    }
+                    LINKED_TIMEOUT => {}
+                    slot => self.complete(slot, result),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A file that is read via io_uring.
+#[derive(Debug, Clone)]
+pub struct UringFile {
+    ring: Ring,
+    file: Arc<std::fs::File>,
+
+    /// The metadata of the file when it was opened.
+    metadata: std::fs::Metadata,
+}
+
+#[async_trait]
+impl simple_fs::File for UringFile {
+    type Error = std::io::Error;
+
+    async fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let data = self.read_owned(offset, vec![0; buf.len()]).await?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn read_owned(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>, Self::Error> {
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let (read, returned) = self
+                .ring
+                .read_at(self.file.clone(), offset + pos as u64, buf, pos)
+                .await?;
+
+            buf = returned;
+
+            if read == 0 {
+                break;
+            }
+
+            pos += read;
+        }
+
+        buf.truncate(pos);
+        Ok(buf)
+    }
+
+    async fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.metadata.len())
+    }
+
+    fn metadata(&self) -> Option<&std::fs::Metadata> {
+        Some(&self.metadata)
+    }
+}
+
+/// A filesystem whose files are read via io_uring.
+#[derive(Debug, Clone)]
+pub struct UringFilesystem {
+    ring: Ring,
+}
+
+impl UringFilesystem {
+    pub fn new(ring: Ring) -> Self {
+        Self { ring }
+    }
+}
+
+#[async_trait]
+impl simple_fs::Filesystem for UringFilesystem {
+    type File = UringFile;
+    type Error = std::io::Error;
+
+    async fn open(&self, path: &Path) -> Result<Self::File, Self::Error> {
+        let file = tokio::fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+
+        Ok(UringFile {
+            ring: self.ring.clone(),
+            file: Arc::new(file.into_std().await),
+            metadata,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_fs::{File, Filesystem};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn can_read_files() {
+        let contents: Vec<u8> = (0..=255).cycle().take(3 << 16).collect();
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+
+        std::io::Write::write_all(&mut tmp, &contents).unwrap();
+
+        let fs = UringFilesystem::new(Ring::new().unwrap());
+        let file = fs.open(tmp.path()).await.unwrap();
+
+        assert_eq!(file.size().await.unwrap(), contents.len() as u64);
+
+        let mut buf = [0; 1000];
+        assert_eq!(file.read(10, &mut buf).await.unwrap(), 1000);
+        assert_eq!(&buf[..], &contents[10..1010]);
+
+        // Reads at the end of the file are short.
+        let mut buf = [0; 1000];
+        assert_eq!(
+            file.read(contents.len() as u64 - 10, &mut buf)
+                .await
+                .unwrap(),
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_into_owned_buffers_in_place() {
+        let contents: Vec<u8> = (0..=255).cycle().take(3 << 16).collect();
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+
+        std::io::Write::write_all(&mut tmp, &contents).unwrap();
+
+        let fs = UringFilesystem::new(Ring::new().unwrap());
+        let file = fs.open(tmp.path()).await.unwrap();
+
+        let buf = vec![0; 2 << 16];
+        let ptr = buf.as_ptr();
+
+        let mut buf = file.read_owned(5, buf).await.unwrap();
+        assert_eq!(&buf[..], &contents[5..5 + (2 << 16)]);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        buf.resize(2 << 16, 0);
+        let buf = file.read_owned(2 << 16, buf).await.unwrap();
+        assert_eq!(&buf[..], &contents[2 << 16..]);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+
+    #[tokio::test]
+    async fn can_send_and_receive() {
+        let ring = Ring::new().unwrap();
+
+        let a = Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+        let b = Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+        let c = Arc::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+
+        let buf = vec![0; 1024];
+        let ptr = buf.as_ptr();
+
+        let (received, buf) = ring
+            .recv(b.clone(), buf, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(received, None);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        ring.send(a.clone(), b"hello".to_vec()).await.unwrap();
+
+        let (received, buf) = ring
+            .recv(b.clone(), buf, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(received, Some(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // Sockets can also send to other destinations.
+        ring.send_to(a.clone(), b"psst".to_vec(), c.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(c.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"psst");
+    }
+}
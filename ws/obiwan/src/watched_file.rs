@@ -0,0 +1,112 @@
+//! Configuration files that are read again when they change.
+//!
+//! We keep the directory of the file open, so this also works after
+//! `chroot` and when editors replace the file instead of writing it.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, Read},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::warn;
+use nix::{fcntl::OFlag, sys::stat::Mode};
+
+/// How often [`reload_periodically`] checks for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct WatchedFile {
+    /// The directory that contains the file.
+    dir: File,
+    name: OsString,
+    path: PathBuf,
+
+    /// The modification time of the file when we last read it.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl WatchedFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        Ok(Self {
+            dir: File::open(dir)?,
+            name: name.to_owned(),
+            path: path.to_owned(),
+            modified: Mutex::new(None),
+        })
+    }
+
+    /// The path of the file for log messages.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the contents of the file, if it has changed since the
+    /// last call. The first call always returns the contents.
+    pub fn read_if_changed(&self) -> io::Result<Option<String>> {
+        let fd = nix::fcntl::openat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_os_str(),
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+
+        // openat returns a new file descriptor that nobody else owns.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let modified = file.metadata()?.modified()?;
+
+        {
+            let mut last_modified = self.modified.lock().unwrap();
+
+            if *last_modified == Some(modified) {
+                return Ok(None);
+            }
+
+            // Remember broken versions as well, so callers complain
+            // only once.
+            *last_modified = Some(modified);
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        Ok(Some(contents))
+    }
+}
+
+/// Call `reload` on `target` every few seconds, forever. Reloading
+/// happens on the blocking thread pool, so readers of `target` never
+/// wait for the disk. Errors are logged and the next attempt is made
+/// as usual.
+pub async fn reload_periodically<T: Send + Sync + 'static>(
+    target: Arc<T>,
+    reload: fn(&T) -> io::Result<()>,
+) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let target = target.clone();
+
+        match tokio::task::spawn_blocking(move || reload(&target)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to reload configuration: {e}"),
+            Err(e) => warn!("Failed to reload configuration: {e}"),
+        }
+    }
+}